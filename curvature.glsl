@@ -0,0 +1,15 @@
+/* Bends the world around a planet, for any shader drawing terrain */
+
+/* Radius in blocks of the planet the world bends around, or 0 for flat */
+uniform float planet_radius;
+
+/* Lowers a world position with horizontal distance from the camera. Must
+ * match Curvature::sink */
+vec3 curve(vec3 position, vec3 camera_position) {
+    vec3 curved_position = position;
+    if (planet_radius > 0.0) {
+        float horiz_dist = min(length(camera_position.xz - position.xz), planet_radius);
+        curved_position.y -= planet_radius - sqrt(pow(planet_radius, 2.0) - pow(horiz_dist, 2.0));
+    }
+    return curved_position;
+}
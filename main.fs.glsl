@@ -1,19 +1,102 @@
 #version 120
 
-const vec4 fog_color = vec4(0.0, 0.75, 1.0, 1.0);
+const float tex_size = 128.0;
+const float chunk_size = 32.0;
 
 uniform sampler2D texture;
 
-varying vec4 frag_diffuse_factor;
-varying vec2 frag_texcoord1;
-varying vec2 frag_texcoord2;
-varying float frag_tex_factor;
+/* The sky colour for the time of day, also used as the clear colour */
+uniform vec3 fog_color;
+
+/* Block ids of the chunk being drawn, for quads spanning several types */
+uniform sampler3D chunk_blocks;
+uniform vec3 chunk_origin;
+
+/* Per block type, filled from the block registry */
+const int MAX_BLOCKS = 64;
+uniform vec4 block_color[MAX_BLOCKS];
+/* Scale of the two texture lookups, and how much the texture darkens the color */
+uniform vec3 block_texture[MAX_BLOCKS];
+
+/* Sun depth maps, nearest cascade first */
+const int NUM_CASCADES = 3;
+uniform bool shadows_enabled;
+uniform sampler2D shadow_maps[NUM_CASCADES];
+/* World to shadow map coordinates for each cascade */
+uniform mat4 shadow_transform[NUM_CASCADES];
+/* Distance from the camera where each cascade ends */
+uniform vec3 shadow_splits;
+/* Moves the lookup off the surface so faces don't shadow themselves */
+const float shadow_normal_offset = 0.1;
+const float shadow_bias = 0.0005;
+
+varying vec3 frag_position;
+varying vec3 frag_normal;
+varying float frag_blocktype;
+varying vec3 frag_sun;
+varying vec3 frag_sky;
+varying vec3 frag_block_light;
+varying float frag_occlusion_factor;
+varying float frag_depth;
 varying float frag_fog_factor;
 
+int block_at_fragment() {
+    if (frag_blocktype >= 0.0) {
+        return int(frag_blocktype + 0.5);
+    }
+
+    /* Step back from the face into the block it belongs to */
+    vec3 block = floor(frag_position - chunk_origin - frag_normal * 0.5);
+    block = clamp(block, 0.0, chunk_size - 1.0);
+
+    /* The texture is laid out like Map::blocks, z varying fastest */
+    vec3 coord = (block.zyx + 0.5) / chunk_size;
+    return int(texture3D(chunk_blocks, coord).r * 255.0 + 0.5);
+}
+
+/* 1 where the sun reaches the fragment, 0 in shadow */
+float lookup_shadow(sampler2D map, mat4 transform) {
+    vec4 coord = transform * vec4(frag_position + frag_normal * shadow_normal_offset, 1.0);
+    if (any(lessThan(coord.xyz, vec3(0.0))) || any(greaterThan(coord.xyz, vec3(1.0)))) {
+        return 1.0;
+    }
+    return coord.z - shadow_bias > texture2D(map, coord.xy).r ? 0.0 : 1.0;
+}
+
+float sunlight() {
+    if (!shadows_enabled) {
+        return 1.0;
+    }
+
+    /* Samplers can only be indexed by constants in GLSL 1.20 */
+    if (frag_depth < shadow_splits.x) {
+        return lookup_shadow(shadow_maps[0], shadow_transform[0]);
+    } else if (frag_depth < shadow_splits.y) {
+        return lookup_shadow(shadow_maps[1], shadow_transform[1]);
+    } else if (frag_depth < shadow_splits.z) {
+        return lookup_shadow(shadow_maps[2], shadow_transform[2]);
+    }
+    return 1.0;
+}
+
 void main() {
-    vec4 noise = mix(texture2D(texture, frag_texcoord1),
-                     texture2D(texture, frag_texcoord2),
-                     frag_tex_factor);
-    gl_FragColor = noise * frag_diffuse_factor;
-    gl_FragColor = mix(fog_color, gl_FragColor, frag_fog_factor);
+    int block = block_at_fragment();
+
+    vec2 texcoord;
+    if (frag_normal.x != 0.0) {
+        texcoord = frag_position.yz;
+    } else if (frag_normal.y != 0.0) {
+        texcoord = frag_position.xz;
+    } else {
+        texcoord = frag_position.xy;
+    }
+    texcoord /= tex_size;
+
+    vec4 noise = mix(texture2D(texture, texcoord * block_texture[block].x),
+                     texture2D(texture, texcoord * block_texture[block].y),
+                     block_texture[block].z);
+    vec3 sky = frag_sky + frag_sun * sunlight();
+    vec4 light = vec4(min(max(sky, frag_block_light), 1.0) * frag_occlusion_factor, 1.0);
+    gl_FragColor = noise * light * block_color[block];
+    gl_FragColor = mix(vec4(fog_color, 1.0), gl_FragColor, frag_fog_factor);
 }
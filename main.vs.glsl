@@ -3,83 +3,67 @@
 uniform mat4 view;
 uniform mat4 projection;
 uniform vec3 camera_position;
+/* Direction the sunlight travels */
+uniform vec3 light_direction;
+/* Set by the world clock for the time of day */
+uniform vec3 sun_color;
+uniform vec3 ambient_color;
+/* Thinner for longer view distances */
+uniform float fog_density;
 
 attribute vec3 position;
 attribute vec3 normal;
 attribute float blocktype;
+/* 0 in the open, 1 in a corner enclosed by blocks */
+attribute float occlusion;
+/* Skylight and block light in front of the face, from 0 to 1 */
+attribute vec2 light;
 
-varying vec4 frag_diffuse_factor;
-varying vec2 frag_texcoord1;
-varying vec2 frag_texcoord2;
-varying float frag_tex_factor;
+varying vec3 frag_position;
+varying vec3 frag_normal;
+varying float frag_blocktype;
+/* Direct sunlight, added by the fragment shader where it isn't shadowed */
+varying vec3 frag_sun;
+/* Ambient skylight */
+varying vec3 frag_sky;
+varying vec3 frag_block_light;
+varying float frag_occlusion_factor;
+/* Distance in front of the camera, for picking a shadow cascade */
+varying float frag_depth;
 varying float frag_fog_factor;
 
-const vec3 light_direction = vec3(0.408248, -0.816497, 0.408248);
-const vec4 light_diffuse = vec4(0.8, 0.8, 0.8, 0.0);
-const vec4 light_ambient = vec4(0.2, 0.2, 0.2, 1.0);
+/* Light left in a fully occluded corner */
+const float min_occlusion_light = 0.4;
+/* Fraction of brightness kept per light level */
+const float light_falloff = 0.8;
+const vec3 block_light_color = vec3(1.0, 0.85, 0.6);
 
-const float planet_radius = 6371000.0 / 5000.0;
-const float fog_density = 0.003;
-const float tex_size = 128.0;
+#include "curvature.glsl"
 
-const float BLOCK_GRASS = 1.0;
-const float BLOCK_STONE = 2.0;
-const float BLOCK_DIRT = 3.0;
-const float BLOCK_WATER = 4.0;
+/* Maps a light level from 0 to 1 to a brightness */
+float brightness(float level) {
+    return pow(light_falloff, (1.0 - level) * 15.0);
+}
 
 void main() {
-    float horiz_dist = length(camera_position - position);
-
-    /* Curvature of the planet */
-    vec3 curved_position = position;
-    //curved_position.y -= planet_radius - sqrt(pow(planet_radius, 2.0) - pow(horiz_dist, 2.0));
-
-    vec4 eye_position = view * vec4(curved_position, 1.0);
+    vec4 eye_position = view * vec4(curve(position, camera_position), 1.0);
 
     gl_Position = projection * eye_position;
 
-    if (normal.x != 0.0) {
-        frag_texcoord1 = position.yz;
-    } else if (normal.y != 0.0) {
-        frag_texcoord1 = position.xz;
-    } else {
-        frag_texcoord1 = position.xy;
-    }
-
-    frag_texcoord1 /= tex_size;
-    frag_texcoord2 = frag_texcoord1;
+    frag_position = position;
+    frag_normal = normal;
+    /* Negative for quads spanning several block types */
+    frag_blocktype = blocktype;
 
-    vec4 base_color;
-    if (blocktype == BLOCK_GRASS) {
-        base_color = vec4(0.0, 0.8, 0.2, 1.0);
-        frag_texcoord1 *= 0.5;
-        frag_texcoord2 *= 16.0;
-        frag_tex_factor = 0.8;
-    } else if (blocktype == BLOCK_STONE) {
-        base_color = vec4(0.8, 0.8, 0.8, 1.0);
-        frag_texcoord1 *= 1.0;
-        frag_texcoord2 *= 8.0;
-        frag_tex_factor = 0.3;
-    } else if (blocktype == BLOCK_DIRT) {
-        base_color = vec4(0.63, 0.35, 0.03, 1.0);
-        frag_texcoord1 *= 0.5;
-        frag_texcoord2 *= 16.0;
-        frag_tex_factor = 0.8;
-    } else if (blocktype == BLOCK_WATER) {
-        base_color = vec4(0.1, 0.1, 0.9, 1.0);
-        frag_texcoord1 *= 2.0;
-        frag_texcoord2 *= 0.1;
-        frag_tex_factor = 0.8;
-    } else {
-        base_color = vec4(1.0, 0.0, 0.0, 1.0);
-        frag_texcoord1 *= 16.0;
-        frag_texcoord2 *= 16.0;
-        frag_tex_factor = 0.5;
-    }
+    frag_depth = -eye_position.z;
 
-    vec4 diffuse_factor
-        = max(-dot(normal, light_direction), 0.0) * light_diffuse;
-    frag_diffuse_factor = (diffuse_factor + light_ambient) * base_color;
+    float sky_brightness = brightness(light.x);
+    vec3 diffuse_factor
+        = max(-dot(normal, light_direction), 0.0) * sun_color;
+    frag_sun = diffuse_factor * sky_brightness;
+    frag_sky = ambient_color * sky_brightness;
+    frag_block_light = block_light_color * brightness(light.y);
+    frag_occlusion_factor = mix(1.0, min_occlusion_light, occlusion);
 
     frag_fog_factor = clamp(exp2(-pow(length(eye_position), 2.0) * pow(fog_density, 2.0) * 1.44), 0.0, 1.0);
 }
@@ -0,0 +1,10 @@
+#version 120
+
+/* World to the sun's clip coordinates */
+uniform mat4 transform;
+
+attribute vec3 position;
+
+void main() {
+    gl_Position = transform * vec4(position, 1.0);
+}
@@ -0,0 +1,55 @@
+#version 120
+
+/* Direction the sunlight travels */
+uniform vec3 light_direction;
+/* The fog colour, so distant chunks fade into the sky */
+uniform vec3 horizon_color;
+uniform vec3 zenith_color;
+
+varying vec3 frag_direction;
+
+/* Cosines of the angular radii of the discs */
+const float sun_size = 0.9995;
+const float moon_size = 0.9997;
+const vec3 sun_disc_color = vec3(1.0, 0.95, 0.8);
+const vec3 moon_disc_color = vec3(0.8, 0.82, 0.9);
+/* Cells of the star grid across the sky */
+const float star_density = 400.0;
+/* Fraction of grid cells holding a star */
+const float star_fraction = 0.003;
+
+/* Pseudo-random number from 0 to 1 for a grid cell */
+float hash(vec3 cell) {
+    return fract(sin(dot(cell, vec3(12.9898, 78.233, 45.164))) * 43758.5453);
+}
+
+void main() {
+    vec3 direction = normalize(frag_direction);
+    vec3 sun = -light_direction;
+
+    /* Below the horizon is hidden by fogged terrain, so keep to its colour */
+    float height = max(direction.y, 0.0);
+    vec3 color = mix(horizon_color, zenith_color, sqrt(height));
+
+    /* Fades in as the sun sets */
+    float night = clamp(-sun.y * 5.0, 0.0, 1.0);
+
+    float star = hash(floor(direction * star_density));
+    if (star < star_fraction) {
+        color += vec3(star / star_fraction) * night * height;
+    }
+
+    /* The moon is opposite the sun */
+    if (dot(direction, -sun) > moon_size) {
+        color = mix(color, moon_disc_color, night);
+    }
+
+    /* Glow around the sun, then the disc itself */
+    float sun_angle = max(dot(direction, sun), 0.0);
+    color += sun_disc_color * pow(sun_angle, 200.0) * 0.5;
+    if (sun_angle > sun_size) {
+        color = sun_disc_color;
+    }
+
+    gl_FragColor = vec4(color, 1.0);
+}
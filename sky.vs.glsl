@@ -0,0 +1,17 @@
+#version 120
+
+/* Rotates view space directions into the world */
+uniform mat3 view_to_world;
+/* Half the width and height of the view one unit in front of the camera */
+uniform vec2 view_scale;
+
+/* Corner of the screen in normalized device coordinates */
+attribute vec2 position;
+
+varying vec3 frag_direction;
+
+void main() {
+    /* At the far plane, though depth testing is off anyway */
+    gl_Position = vec4(position, 1.0, 1.0);
+    frag_direction = view_to_world * vec3(position * view_scale, -1.0);
+}
@@ -0,0 +1,430 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Maps keys and mouse buttons to actions, loaded from a JSON config file.
+//!
+//! The file is an object from action name to a list of bindings:
+//!
+//!   {
+//!       "move_forward": ["W", "Up"],
+//!       "place_block": ["MouseRight", "Ctrl+MouseLeft"]
+//!   }
+//!
+//! A binding is an optional list of modifiers (Shift, Ctrl, Alt, Super)
+//! followed by a key name as in GLFW without the "Key" prefix, or one of
+//! MouseLeft, MouseRight and MouseMiddle. Actions missing from the file keep
+//! their default bindings; an empty list unbinds an action.
+
+extern mod extra;
+extern mod glfw;
+
+use std::ascii::StrAsciiExt;
+use std::path::Path;
+use std::str;
+
+use extra::json;
+
+use read_file;
+
+#[deriving(Eq, Clone)]
+pub enum Action {
+    MoveForward,
+    MoveBack,
+    MoveLeft,
+    MoveRight,
+    /// Jumps while walking, rises while flying
+    Jump,
+    /// Descends while flying
+    Descend,
+    ToggleFlying,
+    BreakBlock,
+    PlaceBlock,
+    ToggleGrab,
+    ToggleWireframe,
+    ToggleShadows,
+    ToggleCurvature,
+    /// Makes time pass faster while held
+    FastForwardTime,
+    FreezeTime,
+    ReloadShaders,
+    Quit,
+}
+
+static ACTIONS : &'static [(&'static str, Action)] = &[
+    ("move_forward", MoveForward),
+    ("move_back", MoveBack),
+    ("move_left", MoveLeft),
+    ("move_right", MoveRight),
+    ("jump", Jump),
+    ("descend", Descend),
+    ("toggle_flying", ToggleFlying),
+    ("break_block", BreakBlock),
+    ("place_block", PlaceBlock),
+    ("toggle_grab", ToggleGrab),
+    ("toggle_wireframe", ToggleWireframe),
+    ("toggle_shadows", ToggleShadows),
+    ("toggle_curvature", ToggleCurvature),
+    ("fast_forward_time", FastForwardTime),
+    ("freeze_time", FreezeTime),
+    ("reload_shaders", ReloadShaders),
+    ("quit", Quit),
+];
+
+/// Used for actions the config file doesn't mention. Keep in sync with
+/// bindings.json.
+static DEFAULT_BINDINGS : &'static str = "{
+    \"move_forward\": [\"W\"],
+    \"move_back\": [\"S\"],
+    \"move_left\": [\"A\"],
+    \"move_right\": [\"D\"],
+    \"jump\": [\"Space\"],
+    \"descend\": [\"LeftControl\"],
+    \"toggle_flying\": [\"F\"],
+    \"break_block\": [\"MouseLeft\"],
+    \"place_block\": [\"MouseRight\"],
+    \"toggle_grab\": [\"G\"],
+    \"toggle_wireframe\": [\"L\"],
+    \"toggle_shadows\": [\"K\"],
+    \"toggle_curvature\": [\"C\"],
+    \"fast_forward_time\": [\"T\"],
+    \"freeze_time\": [\"P\"],
+    \"reload_shaders\": [\"R\"],
+    \"quit\": [\"Escape\"]
+}";
+
+/// Modifier bits
+pub type Modifiers = u8;
+pub static SHIFT : Modifiers = 1;
+pub static CONTROL : Modifiers = 2;
+pub static ALT : Modifiers = 4;
+pub static SUPER : Modifiers = 8;
+
+static MODIFIER_NAMES : &'static [(&'static str, Modifiers)] = &[
+    ("Shift", SHIFT),
+    ("Ctrl", CONTROL),
+    ("Alt", ALT),
+    ("Super", SUPER),
+];
+
+/// The keys that set each modifier bit.
+static MODIFIER_KEYS : &'static [(glfw::Key, Modifiers)] = &[
+    (glfw::KeyLeftShift, SHIFT),
+    (glfw::KeyRightShift, SHIFT),
+    (glfw::KeyLeftControl, CONTROL),
+    (glfw::KeyRightControl, CONTROL),
+    (glfw::KeyLeftAlt, ALT),
+    (glfw::KeyRightAlt, ALT),
+    (glfw::KeyLeftSuper, SUPER),
+    (glfw::KeyRightSuper, SUPER),
+];
+
+static MOUSE_BUTTON_NAMES : &'static [(&'static str, glfw::MouseButton)] = &[
+    ("MouseLeft", glfw::MouseButtonLeft),
+    ("MouseRight", glfw::MouseButtonRight),
+    ("MouseMiddle", glfw::MouseButtonMiddle),
+];
+
+static KEY_NAMES : &'static [(&'static str, glfw::Key)] = &[
+    ("A", glfw::KeyA), ("B", glfw::KeyB), ("C", glfw::KeyC), ("D", glfw::KeyD),
+    ("E", glfw::KeyE), ("F", glfw::KeyF), ("G", glfw::KeyG), ("H", glfw::KeyH),
+    ("I", glfw::KeyI), ("J", glfw::KeyJ), ("K", glfw::KeyK), ("L", glfw::KeyL),
+    ("M", glfw::KeyM), ("N", glfw::KeyN), ("O", glfw::KeyO), ("P", glfw::KeyP),
+    ("Q", glfw::KeyQ), ("R", glfw::KeyR), ("S", glfw::KeyS), ("T", glfw::KeyT),
+    ("U", glfw::KeyU), ("V", glfw::KeyV), ("W", glfw::KeyW), ("X", glfw::KeyX),
+    ("Y", glfw::KeyY), ("Z", glfw::KeyZ),
+    ("0", glfw::Key0), ("1", glfw::Key1), ("2", glfw::Key2), ("3", glfw::Key3),
+    ("4", glfw::Key4), ("5", glfw::Key5), ("6", glfw::Key6), ("7", glfw::Key7),
+    ("8", glfw::Key8), ("9", glfw::Key9),
+    ("Space", glfw::KeySpace),
+    ("Apostrophe", glfw::KeyApostrophe),
+    ("Comma", glfw::KeyComma),
+    ("Minus", glfw::KeyMinus),
+    ("Period", glfw::KeyPeriod),
+    ("Slash", glfw::KeySlash),
+    ("Semicolon", glfw::KeySemicolon),
+    ("Equal", glfw::KeyEqual),
+    ("LeftBracket", glfw::KeyLeftBracket),
+    ("Backslash", glfw::KeyBackslash),
+    ("RightBracket", glfw::KeyRightBracket),
+    ("GraveAccent", glfw::KeyGraveAccent),
+    ("Escape", glfw::KeyEscape),
+    ("Enter", glfw::KeyEnter),
+    ("Tab", glfw::KeyTab),
+    ("Backspace", glfw::KeyBackspace),
+    ("Insert", glfw::KeyInsert),
+    ("Delete", glfw::KeyDelete),
+    ("Right", glfw::KeyRight),
+    ("Left", glfw::KeyLeft),
+    ("Down", glfw::KeyDown),
+    ("Up", glfw::KeyUp),
+    ("PageUp", glfw::KeyPageUp),
+    ("PageDown", glfw::KeyPageDown),
+    ("Home", glfw::KeyHome),
+    ("End", glfw::KeyEnd),
+    ("CapsLock", glfw::KeyCapsLock),
+    ("F1", glfw::KeyF1), ("F2", glfw::KeyF2), ("F3", glfw::KeyF3), ("F4", glfw::KeyF4),
+    ("F5", glfw::KeyF5), ("F6", glfw::KeyF6), ("F7", glfw::KeyF7), ("F8", glfw::KeyF8),
+    ("F9", glfw::KeyF9), ("F10", glfw::KeyF10), ("F11", glfw::KeyF11), ("F12", glfw::KeyF12),
+    ("LeftShift", glfw::KeyLeftShift),
+    ("LeftControl", glfw::KeyLeftControl),
+    ("LeftAlt", glfw::KeyLeftAlt),
+    ("LeftSuper", glfw::KeyLeftSuper),
+    ("RightShift", glfw::KeyRightShift),
+    ("RightControl", glfw::KeyRightControl),
+    ("RightAlt", glfw::KeyRightAlt),
+    ("RightSuper", glfw::KeyRightSuper),
+];
+
+pub enum Input {
+    Key(glfw::Key),
+    MouseButton(glfw::MouseButton),
+}
+
+impl Input {
+    fn same(&self, other: &Input) -> bool {
+        match (self, other) {
+            (&Key(a), &Key(b)) => a as int == b as int,
+            (&MouseButton(a), &MouseButton(b)) => a as int == b as int,
+            _ => false,
+        }
+    }
+}
+
+pub struct Binding {
+    input: Input,
+    /// Modifiers that must be held. Others may be held too.
+    modifiers: Modifiers,
+}
+
+pub struct Bindings {
+    bindings: ~[(Action, Binding)],
+}
+
+impl Bindings {
+    pub fn default() -> Bindings {
+        match Bindings::parse(DEFAULT_BINDINGS) {
+            Ok((bindings, _)) => bindings,
+            Err(msg) => fail!("bad default bindings: {}", msg),
+        }
+    }
+
+    /// Reads `path`, or returns the defaults if it doesn't exist.
+    pub fn load(path: &Path) -> Result<Bindings, ~str> {
+        if !path.exists() {
+            return Ok(Bindings::default());
+        }
+
+        let data = match read_file(path) {
+            Ok(data) => data,
+            Err(msg) => return Err(msg),
+        };
+
+        let text = match str::from_utf8_opt(data) {
+            Some(text) => text,
+            None => return Err(format!("{} is not UTF-8", path.display())),
+        };
+
+        match Bindings::from_json(text) {
+            Ok(bindings) => Ok(bindings),
+            Err(msg) => Err(format!("{}: {}", path.display(), msg)),
+        }
+    }
+
+    /// Parses a config file, filling in defaults for unmentioned actions.
+    pub fn from_json(src: &str) -> Result<Bindings, ~str> {
+        let (mut result, mentioned) = match Bindings::parse(src) {
+            Ok(x) => x,
+            Err(msg) => return Err(msg),
+        };
+
+        let default = Bindings::default();
+        for &(action, binding) in default.bindings.iter() {
+            if !mentioned.contains(&action) {
+                result.bindings.push((action, binding));
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the bindings and the actions the file mentions.
+    fn parse(src: &str) -> Result<(Bindings, ~[Action]), ~str> {
+        let obj = match json::from_str(src) {
+            Ok(json::Object(obj)) => obj,
+            Ok(_) => return Err(~"expected an object of actions"),
+            Err(e) => return Err(format!("line {}: {}", e.line, e.msg)),
+        };
+
+        let mut bindings = ~[];
+        let mut mentioned = ~[];
+        for (name, value) in obj.iter() {
+            let action = match ACTIONS.iter().find(|&&(n, _)| n == name.as_slice()) {
+                Some(&(_, action)) => action,
+                None => return Err(format!("unknown action {}", *name)),
+            };
+            mentioned.push(action);
+
+            let list = match *value {
+                json::List(ref list) => list,
+                _ => return Err(format!("expected a list of bindings for {}", *name)),
+            };
+
+            for entry in list.iter() {
+                let binding = match *entry {
+                    json::String(ref s) => match parse_binding(s.as_slice()) {
+                        Ok(binding) => binding,
+                        Err(msg) => return Err(format!("{}: {}", *name, msg)),
+                    },
+                    _ => return Err(format!("expected a string binding for {}", *name)),
+                };
+                bindings.push((action, binding));
+            }
+        }
+
+        Ok((Bindings { bindings: bindings }, mentioned))
+    }
+
+    /// Actions triggered by pressing `key` with `modifiers` held.
+    pub fn key_actions(&self, key: glfw::Key, modifiers: Modifiers) -> ~[Action] {
+        self.pressed(&Key(key), modifiers)
+    }
+
+    /// Actions triggered by pressing `button` with `modifiers` held.
+    pub fn mouse_actions(&self, button: glfw::MouseButton, modifiers: Modifiers) -> ~[Action] {
+        self.pressed(&MouseButton(button), modifiers)
+    }
+
+    /// Only the bindings for `input` that require the most modifiers fire,
+    /// so binding both "S" and "Ctrl+S" works as expected.
+    fn pressed(&self, input: &Input, modifiers: Modifiers) -> ~[Action] {
+        let matches = |b: &Binding| b.input.same(input) && b.modifiers & modifiers == b.modifiers;
+
+        let mut most = None;
+        for &(_, ref b) in self.bindings.iter() {
+            if matches(b) {
+                let n = count_bits(b.modifiers);
+                most = match most {
+                    Some(m) if m >= n => Some(m),
+                    _ => Some(n),
+                };
+            }
+        }
+
+        let mut actions = ~[];
+        for &(action, ref b) in self.bindings.iter() {
+            if matches(b) && Some(count_bits(b.modifiers)) == most && !actions.contains(&action) {
+                actions.push(action);
+            }
+        }
+        actions
+    }
+
+    /// Whether any binding for `action` is held down. `is_down` reports
+    /// the state of a key or mouse button.
+    pub fn is_held(&self, action: Action, is_down: |&Input| -> bool) -> bool {
+        let modifiers = held_modifiers(|input| is_down(input));
+        self.bindings.iter().any(|&(a, ref b)| {
+            a == action && b.modifiers & modifiers == b.modifiers && is_down(&b.input)
+        })
+    }
+}
+
+/// Modifier bits for the modifier keys `is_down` reports as held.
+pub fn held_modifiers(is_down: |&Input| -> bool) -> Modifiers {
+    let mut modifiers = 0;
+    for &(key, bit) in MODIFIER_KEYS.iter() {
+        if is_down(&Key(key)) {
+            modifiers |= bit;
+        }
+    }
+    modifiers
+}
+
+fn parse_binding(s: &str) -> Result<Binding, ~str> {
+    let parts : ~[&str] = s.split('+').map(|p| p.trim()).collect();
+    let last = parts[parts.len() - 1];
+    let mods = parts.slice_to(parts.len() - 1);
+
+    let mut modifiers = 0;
+    for part in mods.iter() {
+        match MODIFIER_NAMES.iter().find(|&&(n, _)| n.eq_ignore_ascii_case(*part)) {
+            Some(&(_, bit)) => modifiers |= bit,
+            None => return Err(format!("unknown modifier {} in {}", *part, s)),
+        }
+    }
+
+    let input = match KEY_NAMES.iter().find(|&&(n, _)| n.eq_ignore_ascii_case(last)) {
+        Some(&(_, key)) => Key(key),
+        None => match MOUSE_BUTTON_NAMES.iter().find(|&&(n, _)| n.eq_ignore_ascii_case(last)) {
+            Some(&(_, button)) => MouseButton(button),
+            None => return Err(format!("unknown key {} in {}", last, s)),
+        },
+    };
+
+    Ok(Binding { input: input, modifiers: modifiers })
+}
+
+fn count_bits(modifiers: Modifiers) -> uint {
+    let mut n = 0;
+    for i in range(0, 8) {
+        if modifiers & (1 << i) != 0 {
+            n += 1;
+        }
+    }
+    n
+}
+
+#[test]
+fn test_bindings_default() {
+    let bindings = Bindings::default();
+    assert_eq!(bindings.key_actions(glfw::KeyW, 0), ~[MoveForward]);
+    assert_eq!(bindings.key_actions(glfw::KeyW, SHIFT), ~[MoveForward]);
+    assert_eq!(bindings.mouse_actions(glfw::MouseButtonRight, 0), ~[PlaceBlock]);
+    assert!(bindings.key_actions(glfw::KeyQ, 0).is_empty());
+
+    /* Left control is both a modifier and the descend binding */
+    let down = |input: &Input| input.same(&Key(glfw::KeyLeftControl));
+    assert!(bindings.is_held(Descend, |i| down(i)));
+    assert!(!bindings.is_held(MoveForward, |i| down(i)));
+}
+
+#[test]
+fn test_bindings_parse() {
+    let bindings = Bindings::from_json("{
+        \"move_forward\": [\"Z\", \"up\"],
+        \"place_block\": [\"Ctrl+MouseLeft\"],
+        \"quit\": []
+    }").unwrap();
+
+    assert_eq!(bindings.key_actions(glfw::KeyZ, 0), ~[MoveForward]);
+    assert_eq!(bindings.key_actions(glfw::KeyUp, 0), ~[MoveForward]);
+    assert!(bindings.key_actions(glfw::KeyW, 0).is_empty());
+    assert!(bindings.key_actions(glfw::KeyEscape, 0).is_empty());
+
+    /* Unmentioned actions keep their defaults */
+    assert_eq!(bindings.key_actions(glfw::KeyS, 0), ~[MoveBack]);
+
+    /* The binding needing more modifiers wins */
+    assert_eq!(bindings.mouse_actions(glfw::MouseButtonLeft, 0), ~[BreakBlock]);
+    assert_eq!(bindings.mouse_actions(glfw::MouseButtonLeft, CONTROL), ~[PlaceBlock]);
+}
+
+#[test]
+fn test_bindings_errors() {
+    assert!(Bindings::from_json("[]").is_err());
+    assert!(Bindings::from_json("{\"fly_to_moon\": [\"M\"]}").is_err());
+    assert!(Bindings::from_json("{\"quit\": [\"Hyper+Q\"]}").is_err());
+    assert!(Bindings::from_json("{\"quit\": [\"NoSuchKey\"]}").is_err());
+    assert!(Bindings::from_json("{\"quit\": \"Escape\"}").is_err());
+}
@@ -0,0 +1,170 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern mod noise;
+
+use noise::Perlin;
+
+/// Spread of each biome in climate space. Larger values give wider
+/// transitions between biomes.
+static BLEND_SIGMA : f64 = 0.3;
+
+pub struct Biome {
+    name: &'static str,
+    /// Position in climate space, each axis in [-1, 1]
+    temperature: f64,
+    humidity: f64,
+    base_height: f64,
+    variance: f64,
+    /// Name of the top two blocks of soil
+    surface: &'static str,
+    /// Name of the remaining soil above the stone
+    subsurface: &'static str,
+    /// Columns taller than this are bare stone
+    max_soil_height: f64,
+}
+
+pub static biomes : [Biome, ..4] = [
+    Biome {
+        name: "ocean",
+        temperature: 0.0, humidity: 1.0,
+        base_height: 0.0, variance: 3.0,
+        surface: "sand", subsurface: "sand",
+        max_soil_height: 20.0,
+    },
+    Biome {
+        name: "plains",
+        temperature: 0.2, humidity: 0.2,
+        base_height: 15.0, variance: 10.0,
+        surface: "grass", subsurface: "dirt",
+        max_soil_height: 20.0,
+    },
+    Biome {
+        name: "desert",
+        temperature: 1.0, humidity: -0.8,
+        base_height: 14.0, variance: 4.0,
+        surface: "sand", subsurface: "sand",
+        max_soil_height: 40.0,
+    },
+    Biome {
+        name: "snowy peaks",
+        temperature: -1.0, humidity: -0.2,
+        base_height: 35.0, variance: 25.0,
+        surface: "snow", subsurface: "stone",
+        max_soil_height: 1000.0,
+    },
+];
+
+/// Biome parameters for one column, blended from nearby biomes.
+pub struct ColumnBiome {
+    base_height: f64,
+    variance: f64,
+    max_soil_height: f64,
+    /// The biome with the largest weight, which picks the soil blocks
+    dominant: &'static Biome,
+    /// Index of `dominant` in `biomes`
+    dominant_index: uint,
+}
+
+/// Temperature and humidity noise fields over the world.
+pub struct BiomeMap {
+    temperature: Perlin,
+    humidity: Perlin,
+}
+
+impl BiomeMap {
+    pub fn new(seed: u32) -> BiomeMap {
+        BiomeMap {
+            temperature: Perlin::from_seed([seed as uint * 31]),
+            humidity: Perlin::from_seed([seed as uint * 37]),
+        }
+    }
+
+    pub fn column(&self, x: i64, z: i64) -> ColumnBiome {
+        let temperature = self.temperature.gen([x as f64 * 0.002, z as f64 * 0.002]);
+        let humidity = self.humidity.gen([x as f64 * 0.002, z as f64 * 0.002]);
+        blend(temperature, humidity)
+    }
+}
+
+/// Weights every biome by a Gaussian of its distance in climate space, so
+/// height parameters change smoothly across biome boundaries.
+pub fn blend(temperature: f64, humidity: f64) -> ColumnBiome {
+    let mut total_weight = 0.0;
+    let mut base_height = 0.0;
+    let mut variance = 0.0;
+    let mut max_soil_height = 0.0;
+    let mut dominant = &biomes[0];
+    let mut dominant_index = 0;
+    let mut dominant_weight = -1.0;
+
+    for (i, biome) in biomes.iter().enumerate() {
+        let dt = temperature - biome.temperature;
+        let dh = humidity - biome.humidity;
+        let weight = (-(dt * dt + dh * dh) / (2.0 * BLEND_SIGMA * BLEND_SIGMA)).exp();
+
+        total_weight += weight;
+        base_height += biome.base_height * weight;
+        variance += biome.variance * weight;
+        max_soil_height += biome.max_soil_height * weight;
+
+        if weight > dominant_weight {
+            dominant = biome;
+            dominant_index = i;
+            dominant_weight = weight;
+        }
+    }
+
+    /* Far from every biome all weights underflow; fall back to the nearest */
+    if total_weight == 0.0 {
+        return ColumnBiome {
+            base_height: dominant.base_height,
+            variance: dominant.variance,
+            max_soil_height: dominant.max_soil_height,
+            dominant: dominant,
+            dominant_index: dominant_index,
+        };
+    }
+
+    ColumnBiome {
+        base_height: base_height / total_weight,
+        variance: variance / total_weight,
+        max_soil_height: max_soil_height / total_weight,
+        dominant: dominant,
+        dominant_index: dominant_index,
+    }
+}
+
+#[test]
+fn test_blend_centres() {
+    for biome in biomes.iter() {
+        let column = blend(biome.temperature, biome.humidity);
+        assert_eq!(column.dominant.name, biome.name);
+    }
+}
+
+#[test]
+fn test_blend_is_smooth() {
+    let plains = &biomes[1];
+    let desert = &biomes[2];
+
+    let mut last = blend(plains.temperature, plains.humidity).base_height;
+    for i in range(1, 101) {
+        let t = i as f64 / 100.0;
+        let column = blend(plains.temperature + (desert.temperature - plains.temperature) * t,
+                           plains.humidity + (desert.humidity - plains.humidity) * t);
+        assert!((column.base_height - last).abs() < 1.0);
+        last = column.base_height;
+    }
+}
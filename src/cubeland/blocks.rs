@@ -0,0 +1,317 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Block types, loaded from a JSON config file.
+//!
+//! The file is a list of objects, one per block type:
+//!
+//!   {
+//!       "id": 1, "name": "grass",
+//!       "opaque": true, "solid": true, "transparent": false,
+//!       "color": [0.0, 0.8, 0.2, 1.0],
+//!       "texture_scale": [0.5, 16.0], "texture_factor": 0.8
+//!   }
+//!
+//! `opaque` blocks hide the faces of their neighbours and stop light,
+//! `solid` blocks stop the player and `transparent` blocks are drawn with
+//! blending. The texture parameters scale the two noise texture lookups and
+//! set how much the noise darkens `color`. An optional `light` from 0 to 15
+//! makes the block glow, like a torch. Id 0 must be "air".
+
+extern mod extra;
+
+use std::path::Path;
+use std::str;
+
+use extra::json;
+use extra::treemap::TreeMap;
+
+use light::MAX_LIGHT;
+use read_file;
+
+pub type BlockId = u8;
+
+pub static AIR : BlockId = 0;
+
+/// Number of entries in the shader's block uniform arrays.
+pub static MAX_BLOCKS : uint = 64;
+
+#[deriving(Clone)]
+pub struct BlockInfo {
+    id: BlockId,
+    name: ~str,
+    opaque: bool,
+    solid: bool,
+    transparent: bool,
+    color: [f32, ..4],
+    texture_scale: [f32, ..2],
+    texture_factor: f32,
+    /// Block light given off, up to light::MAX_LIGHT
+    light: u8,
+}
+
+pub struct BlockRegistry {
+    /// Indexed by id. Gaps in the config are filled with unknown blocks.
+    blocks: ~[BlockInfo],
+}
+
+impl BlockRegistry {
+    pub fn load(path: &Path) -> Result<BlockRegistry, ~str> {
+        if !path.exists() {
+            return Err(format!("{} not found", path.display()));
+        }
+
+        let data = match read_file(path) {
+            Ok(data) => data,
+            Err(msg) => return Err(msg),
+        };
+
+        let text = match str::from_utf8_opt(data) {
+            Some(text) => text,
+            None => return Err(format!("{} is not UTF-8", path.display())),
+        };
+
+        match BlockRegistry::from_json(text) {
+            Ok(registry) => Ok(registry),
+            Err(msg) => Err(format!("{}: {}", path.display(), msg)),
+        }
+    }
+
+    pub fn from_json(src: &str) -> Result<BlockRegistry, ~str> {
+        let entries = match json::from_str(src) {
+            Ok(json::List(entries)) => entries,
+            Ok(_) => return Err(~"expected a list of blocks"),
+            Err(e) => return Err(format!("line {}: {}", e.line, e.msg)),
+        };
+
+        let mut blocks : ~[BlockInfo] = ~[];
+        for entry in entries.iter() {
+            let info = match parse_block(entry) {
+                Ok(info) => info,
+                Err(msg) => return Err(msg),
+            };
+
+            let id = info.id as uint;
+            if id >= MAX_BLOCKS {
+                return Err(format!("block {} has id {}, maximum is {}", info.name, id, MAX_BLOCKS - 1));
+            }
+            while blocks.len() <= id {
+                blocks.push(unknown_block(blocks.len() as BlockId));
+            }
+            if blocks[id].name != ~"" {
+                return Err(format!("blocks {} and {} share id {}", blocks[id].name, info.name, id));
+            }
+            blocks[id] = info;
+        }
+
+        if blocks.is_empty() || blocks[AIR].name != ~"air" {
+            return Err(~"block 0 must be air");
+        }
+
+        Ok(BlockRegistry { blocks: blocks })
+    }
+
+    pub fn get<'a>(&'a self, id: BlockId) -> &'a BlockInfo {
+        if (id as uint) < self.blocks.len() {
+            &self.blocks[id]
+        } else {
+            &self.blocks[AIR]
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<BlockId> {
+        self.blocks.iter().find(|b| b.name.as_slice() == name).map(|b| b.id)
+    }
+
+    /// Like find, but with an error message naming the missing block.
+    pub fn require(&self, name: &str) -> Result<BlockId, ~str> {
+        match self.find(name) {
+            Some(id) => Ok(id),
+            None => Err(format!("block registry has no \"{}\" block", name)),
+        }
+    }
+
+    pub fn is_opaque(&self, id: BlockId) -> bool {
+        self.get(id).opaque
+    }
+
+    pub fn is_solid(&self, id: BlockId) -> bool {
+        self.get(id).solid
+    }
+
+    pub fn len(&self) -> uint {
+        self.blocks.len()
+    }
+
+    /// Shader uniform arrays: color, and (scale1, scale2, factor) per id.
+    pub fn uniforms(&self) -> (~[f32], ~[f32]) {
+        let mut colors = ~[];
+        let mut textures = ~[];
+        for b in self.blocks.iter() {
+            colors.push_all(b.color);
+            textures.push_all([b.texture_scale[0], b.texture_scale[1], b.texture_factor]);
+        }
+        (colors, textures)
+    }
+}
+
+/// Placeholder for ids missing from the config, drawn in the shader's
+/// error colour.
+fn unknown_block(id: BlockId) -> BlockInfo {
+    BlockInfo {
+        id: id,
+        name: ~"",
+        opaque: true,
+        solid: true,
+        transparent: false,
+        color: [1.0, 0.0, 0.0, 1.0],
+        texture_scale: [16.0, 16.0],
+        texture_factor: 0.5,
+        light: 0,
+    }
+}
+
+fn parse_block(entry: &json::Json) -> Result<BlockInfo, ~str> {
+    let obj = match *entry {
+        json::Object(ref obj) => obj,
+        _ => return Err(~"expected an object for each block"),
+    };
+
+    let name = match obj.find(&~"name") {
+        Some(&json::String(ref name)) if !name.is_empty() => name.clone(),
+        _ => return Err(~"block without a name"),
+    };
+
+    let id = match get_number(*obj, "id") {
+        Some(id) if id >= 0.0 && id < 256.0 => id as BlockId,
+        _ => return Err(format!("block {} has no valid id", name)),
+    };
+
+    let color = match get_numbers(*obj, "color", 4) {
+        Some(v) => [v[0], v[1], v[2], v[3]],
+        None => return Err(format!("block {} needs a 4 element color", name)),
+    };
+
+    let texture_scale = match get_numbers(*obj, "texture_scale", 2) {
+        Some(v) => [v[0], v[1]],
+        None => [1.0, 1.0],
+    };
+
+    let light = match get_number(*obj, "light") {
+        Some(n) if n >= 0.0 && n <= MAX_LIGHT as f64 => n as u8,
+        Some(_) => return Err(format!("block {} has light outside 0 to {}", name, MAX_LIGHT)),
+        None => 0,
+    };
+
+    Ok(BlockInfo {
+        id: id,
+        name: name,
+        opaque: get_bool(*obj, "opaque").unwrap_or(true),
+        solid: get_bool(*obj, "solid").unwrap_or(true),
+        transparent: get_bool(*obj, "transparent").unwrap_or(false),
+        color: color,
+        texture_scale: texture_scale,
+        texture_factor: get_number(*obj, "texture_factor").unwrap_or(0.5) as f32,
+        light: light,
+    })
+}
+
+fn get_number(obj: &TreeMap<~str, json::Json>, key: &str) -> Option<f64> {
+    match obj.find(&key.to_owned()) {
+        Some(&json::Number(n)) => Some(n),
+        _ => None,
+    }
+}
+
+fn get_bool(obj: &TreeMap<~str, json::Json>, key: &str) -> Option<bool> {
+    match obj.find(&key.to_owned()) {
+        Some(&json::Boolean(b)) => Some(b),
+        _ => None,
+    }
+}
+
+fn get_numbers(obj: &TreeMap<~str, json::Json>, key: &str, len: uint) -> Option<~[f32]> {
+    match obj.find(&key.to_owned()) {
+        Some(&json::List(ref list)) if list.len() == len => {
+            let mut v = ~[];
+            for x in list.iter() {
+                match *x {
+                    json::Number(n) => v.push(n as f32),
+                    _ => return None,
+                }
+            }
+            Some(v)
+        },
+        _ => None,
+    }
+}
+
+/// A registry with the block names used by the built-in generators. As in
+/// blocks.json, water is the only transparent block.
+#[cfg(test)]
+pub fn test_registry() -> BlockRegistry {
+    let mut src = ~"[";
+    let names = ["air", "grass", "stone", "dirt", "water", "sand", "snow"];
+    for (i, name) in names.iter().enumerate() {
+        if i > 0 {
+            src.push_str(",");
+        }
+        let water = *name == "water";
+        src.push_str(format!("\\{\"id\": {}, \"name\": \"{}\", \"opaque\": {}, \"transparent\": {}, \"color\": [1, 1, 1, 1]\\}",
+                             i, *name, i != 0 && !water, water));
+    }
+    src.push_str("]");
+    BlockRegistry::from_json(src).unwrap()
+}
+
+#[test]
+fn test_registry_parse() {
+    let registry = test_registry();
+    assert_eq!(registry.len(), 7);
+    assert_eq!(registry.find("stone"), Some(2));
+    assert_eq!(registry.find("lava"), None);
+    assert!(!registry.is_opaque(AIR));
+    assert!(registry.is_opaque(registry.find("snow").unwrap()));
+    assert_eq!(registry.get(200).name, ~"air");
+
+    let (colors, textures) = registry.uniforms();
+    assert_eq!(colors.len(), 7 * 4);
+    assert_eq!(textures.len(), 7 * 3);
+}
+
+#[test]
+fn test_registry_gaps() {
+    let registry = BlockRegistry::from_json(
+        "[{\"id\": 0, \"name\": \"air\", \"color\": [0, 0, 0, 0]},
+          {\"id\": 3, \"name\": \"glass\", \"opaque\": false, \"transparent\": true,
+           \"color\": [1, 1, 1, 0.3]}]").unwrap();
+    assert_eq!(registry.len(), 4);
+    assert_eq!(registry.get(1).name, ~"");
+    assert!(registry.get(3).transparent);
+}
+
+#[test]
+fn test_registry_errors() {
+    assert!(BlockRegistry::from_json("{}").is_err());
+    assert!(BlockRegistry::from_json("[]").is_err());
+    assert!(BlockRegistry::from_json(
+        "[{\"id\": 0, \"name\": \"stone\", \"color\": [0, 0, 0, 0]}]").is_err());
+    assert!(BlockRegistry::from_json(
+        "[{\"id\": 0, \"name\": \"air\", \"color\": [0, 0, 0, 0]},
+          {\"id\": 0, \"name\": \"dup\", \"color\": [0, 0, 0, 0]}]").is_err());
+    assert!(BlockRegistry::from_json(
+        "[{\"id\": 0, \"name\": \"air\", \"color\": [0, 0]}]").is_err());
+    assert!(BlockRegistry::from_json(
+        "[{\"id\": 0, \"name\": \"air\", \"color\": [0, 0, 0, 0], \"light\": 16}]").is_err());
+}
@@ -0,0 +1,310 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern mod cgmath;
+
+use std;
+
+use cgmath::matrix::Matrix;
+use cgmath::matrix::Mat3;
+use cgmath::matrix::Mat4;
+use cgmath::matrix::ToMat4;
+use cgmath::vector::Vector;
+use cgmath::vector::Vec3;
+use cgmath::vector::Vec4;
+use cgmath::angle::{rad, deg};
+
+use CHUNK_SIZE;
+
+/// Pitch is kept just short of straight up or down, where yaw would be
+/// meaningless. 89 degrees, in radians.
+static MAX_PITCH : f32 = 1.5533430;
+
+static NEAR : f32 = 0.1;
+
+pub struct Camera {
+    position: Vec3<f32>,
+    /// Radians, increasing to the right
+    yaw: f32,
+    /// Radians, increasing downwards
+    pitch: f32,
+    /// Radians per pixel of cursor movement
+    sensitivity: f32,
+    /// Vertical field of view in degrees
+    fov: f32,
+    /// Distance to the far clipping plane
+    far: f32,
+    /// Radius in blocks of the planet the world is bent around, or 0 for
+    /// a flat world
+    planet_radius: f32,
+}
+
+/// Bends the world down with horizontal distance from a point, as if on
+/// the surface of a planet. Shadows are still cast by the flat world.
+pub struct Curvature {
+    /// Where the world is flat; only x and z matter
+    center: Vec3<f32>,
+    /// Planet radius in blocks, or 0 for a flat world
+    radius: f32,
+}
+
+impl Camera {
+    /// Angles and sensitivity are in degrees.
+    pub fn new(position: Vec3<f32>, pitch: f32, yaw: f32,
+               fov: f32, sensitivity: f32, far: f32) -> Camera {
+        let mut camera = Camera {
+            position: position,
+            yaw: 0.0,
+            pitch: 0.0,
+            sensitivity: to_radians(sensitivity),
+            fov: fov,
+            far: far,
+            planet_radius: 0.0,
+        };
+        camera.set_angles(to_radians(pitch), to_radians(yaw));
+        camera
+    }
+
+    /// Turns by a cursor movement of `dx`, `dy` pixels.
+    pub fn look(&mut self, dx: f64, dy: f64) {
+        let pitch = self.pitch + dy as f32 * self.sensitivity;
+        let yaw = self.yaw + dx as f32 * self.sensitivity;
+        self.set_angles(pitch, yaw);
+    }
+
+    /// Sets the angles in radians, clamping pitch and wrapping yaw.
+    pub fn set_angles(&mut self, pitch: f32, yaw: f32) {
+        let two_pi = std::f32::consts::PI * 2.0;
+        self.pitch = pitch.max(-MAX_PITCH).min(MAX_PITCH);
+        self.yaw = yaw % two_pi;
+        if self.yaw < 0.0 {
+            self.yaw += two_pi;
+        }
+    }
+
+    /// Rotates world directions into view space.
+    pub fn rotation(&self) -> Mat3<f32> {
+        Mat3::from_angle_x(rad(self.pitch)).mul_m(&Mat3::from_angle_y(rad(self.yaw)))
+    }
+
+    /// Rotates view space directions into the world.
+    pub fn inverse_rotation(&self) -> Mat3<f32> {
+        Mat3::from_angle_y(rad(-self.yaw)).mul_m(&Mat3::from_angle_x(rad(-self.pitch)))
+    }
+
+    /// Like inverse_rotation, but ignoring pitch, for walking.
+    pub fn heading(&self) -> Mat3<f32> {
+        Mat3::from_angle_y(rad(-self.yaw))
+    }
+
+    /// Unit vector in the direction the camera faces.
+    pub fn forward(&self) -> Vec3<f32> {
+        self.inverse_rotation().mul_v(&Vec3::new(0.0f32, 0.0f32, -1.0f32))
+    }
+
+    pub fn view(&self) -> Mat4<f32> {
+        let translation = Mat4::<f32>::from_cols(
+            Vec4::<f32>::unit_x(),
+            Vec4::<f32>::unit_y(),
+            Vec4::<f32>::unit_z(),
+            self.position.mul_s(-1.0f32).extend(1.0f32));
+        self.rotation().to_mat4().mul_m(&translation)
+    }
+
+    pub fn projection(&self, aspect: f32) -> Mat4<f32> {
+        cgmath::projection::perspective(deg(self.fov), aspect, NEAR, self.far)
+    }
+
+    /// Transforms world coordinates to clip coordinates.
+    pub fn clip_transform(&self, aspect: f32) -> Mat4<f32> {
+        self.projection(aspect).mul_m(&self.view())
+    }
+
+    /// How the world is bent around the camera.
+    pub fn curvature(&self) -> Curvature {
+        Curvature { center: self.position, radius: self.planet_radius }
+    }
+
+    /// Half the width and height of the view one unit in front of the camera.
+    pub fn half_extents(&self, aspect: f32) -> (f32, f32) {
+        let half_height = (to_radians(self.fov) / 2.0).tan();
+        (half_height * aspect, half_height)
+    }
+
+    /// Centre and radius of a sphere around the part of the view frustum
+    /// between distances `near` and `far`.
+    pub fn frustum_slice_bounds(&self, aspect: f32, near: f32, far: f32) -> (Vec3<f32>, f32) {
+        let (half_width, half_height) = self.half_extents(aspect);
+        let half_width = half_width * far;
+        let half_height = half_height * far;
+        let half_depth = (far - near) / 2.0;
+
+        /* The far corners are the furthest points from the middle of the slice */
+        let center = self.position.add_v(&self.forward().mul_s(near + half_depth));
+        let radius = (half_depth * half_depth + half_width * half_width +
+                      half_height * half_height).sqrt();
+        (center, radius)
+    }
+}
+
+impl Curvature {
+    pub fn flat() -> Curvature {
+        Curvature { center: Vec3::new(0.0f32, 0.0f32, 0.0f32), radius: 0.0 }
+    }
+
+    /// How far the world at (x, z) sinks below its flat height. Must match
+    /// curvature.glsl.
+    pub fn sink(&self, x: f32, z: f32) -> f32 {
+        if self.radius <= 0.0 {
+            return 0.0;
+        }
+        let dx = x - self.center.x;
+        let dz = z - self.center.z;
+        let squared_radius = self.radius * self.radius;
+        let squared_distance = (dx * dx + dz * dz).min(squared_radius);
+        self.radius - (squared_radius - squared_distance).sqrt()
+    }
+
+    /// Least and greatest sink over the column of width `side` with
+    /// minimum corner (x, z).
+    fn sink_range(&self, x: f32, z: f32, side: f32) -> (f32, f32) {
+        let nearest_x = self.center.x.max(x).min(x + side);
+        let nearest_z = self.center.z.max(z).min(z + side);
+        let farthest_x = if self.center.x < x + side / 2.0 { x + side } else { x };
+        let farthest_z = if self.center.z < z + side / 2.0 { z + side } else { z };
+        (self.sink(nearest_x, nearest_z), self.sink(farthest_x, farthest_z))
+    }
+}
+
+/// Returns true if the chunk with minimum corner `p` is entirely outside
+/// the view frustum of `clip_transform`, once bent by `curvature`.
+pub fn view_frustum_cull(clip_transform : &Mat4<f32>, p: &Vec4<f32>,
+                         curvature: &Curvature) -> bool {
+    static L : f32 = CHUNK_SIZE as f32;
+
+    static vertices : [Vec4<f32>, ..8] = [
+        Vec4 { x: 0.0, y: 0.0, z: L,   w: 1.0 }, /* front bottom left */
+        Vec4 { x: L,   y: 0.0, z: L,   w: 1.0 }, /* front bottom right */
+        Vec4 { x: 0.0, y: L,   z: L,   w: 1.0 }, /* front top left */
+        Vec4 { x: L,   y: L,   z: L,   w: 1.0 }, /* front top right */
+        Vec4 { x: L,   y: 0.0, z: 0.0, w: 1.0 }, /* back bottom right */
+        Vec4 { x: 0.0, y: 0.0, z: 0.0, w: 1.0 }, /* back bottom left */
+        Vec4 { x: L,   y: L,   z: 0.0, w: 1.0 }, /* back top right */
+        Vec4 { x: 0.0, y: L,   z: 0.0, w: 1.0 }, /* back top left */
+    ];
+
+    /* Stretch the box down to cover the chunk wherever it is bent to */
+    let (least, greatest) = curvature.sink_range(p.x, p.z, L);
+
+    let clip_vertices = vertices.map(|v| {
+        let mut vertex = p.add_v(v);
+        vertex.y -= if v.y == 0.0 { greatest } else { least };
+        clip_transform.mul_v(&vertex)
+    });
+
+    if clip_vertices.iter().all(|v| v.x < -v.w) {
+        return true;
+    }
+
+    if clip_vertices.iter().all(|v| v.x > v.w) {
+        return true;
+    }
+
+    if clip_vertices.iter().all(|v| v.y < -v.w) {
+        return true;
+    }
+
+    if clip_vertices.iter().all(|v| v.y > v.w) {
+        return true;
+    }
+
+    if clip_vertices.iter().all(|v| v.z < -v.w) {
+        return true;
+    }
+
+    if clip_vertices.iter().all(|v| v.z > v.w) {
+        return true;
+    }
+
+    return false;
+}
+
+fn to_radians(degrees: f32) -> f32 {
+    degrees * std::f32::consts::PI / 180.0
+}
+
+#[cfg(test)]
+fn near(a: &Vec3<f32>, b: &Vec3<f32>) -> bool {
+    (a.x - b.x).abs() < 0.001 && (a.y - b.y).abs() < 0.001 && (a.z - b.z).abs() < 0.001
+}
+
+#[test]
+fn test_camera_look() {
+    let mut camera = Camera::new(Vec3::new(0.0f32, 0.0f32, 0.0f32), 0.0, 0.0, 75.0, 1.0, 100.0);
+    assert!(near(&camera.forward(), &Vec3::new(0.0f32, 0.0f32, -1.0f32)));
+
+    /* 90 pixels right at one degree per pixel */
+    camera.look(90.0, 0.0);
+    assert!(near(&camera.forward(), &Vec3::new(1.0f32, 0.0f32, 0.0f32)));
+
+    /* Wraps instead of growing without bound */
+    camera.look(360.0, 0.0);
+    assert!((camera.yaw - to_radians(90.0)).abs() < 0.001);
+
+    /* Pitch stops short of straight down */
+    camera.look(0.0, 1000.0);
+    assert_eq!(camera.pitch, MAX_PITCH);
+    assert!(camera.forward().y < -0.99);
+    camera.look(0.0, -2000.0);
+    assert_eq!(camera.pitch, -MAX_PITCH);
+}
+
+#[test]
+fn test_camera_cull() {
+    let camera = Camera::new(Vec3::new(16.0f32, 16.0f32, 100.0f32), 0.0, 0.0, 75.0, 1.0, 1000.0);
+    let clip = camera.clip_transform(1.0);
+
+    /* The camera looks down -z */
+    let flat = Curvature::flat();
+    assert!(!view_frustum_cull(&clip, &Vec4::new(0.0f32, 0.0f32, 0.0f32, 0.0f32), &flat));
+    assert!(view_frustum_cull(&clip, &Vec4::new(0.0f32, 0.0f32, 200.0f32, 0.0f32), &flat));
+    assert!(view_frustum_cull(&clip, &Vec4::new(500.0f32, 0.0f32, 0.0f32, 0.0f32), &flat));
+}
+
+#[test]
+fn test_camera_curvature() {
+    let mut camera = Camera::new(Vec3::new(16.0f32, 16.0f32, 0.0f32), 0.0, 0.0, 75.0, 1.0, 1000.0);
+    let clip = camera.clip_transform(1.0);
+
+    /* A distant chunk above the view is only visible once bent down */
+    let p = Vec4::new(0.0f32, 600.0f32, -600.0f32, 0.0f32);
+    assert!(view_frustum_cull(&clip, &p, &camera.curvature()));
+    camera.planet_radius = 1000.0;
+    assert!(!view_frustum_cull(&clip, &p, &camera.curvature()));
+
+    let curvature = camera.curvature();
+    assert_eq!(curvature.sink(16.0, 0.0), 0.0);
+    assert!((curvature.sink(16.0, 600.0) - 200.0).abs() < 0.01);
+    assert_eq!(curvature.sink(16.0, 5000.0), 1000.0);
+}
+
+#[test]
+fn test_camera_frustum_slice() {
+    let camera = Camera::new(Vec3::new(0.0f32, 0.0f32, 0.0f32), 0.0, 0.0, 90.0, 1.0, 1000.0);
+    let (center, radius) = camera.frustum_slice_bounds(2.0, 10.0, 30.0);
+    assert!(near(&center, &Vec3::new(0.0f32, 0.0f32, -20.0f32)));
+
+    /* A far corner is at (±60, ±30, -30) */
+    assert!((radius - (10.0f32 * 10.0 + 60.0 * 60.0 + 30.0 * 30.0).sqrt()).abs() < 0.01);
+}
@@ -16,72 +16,473 @@ extern mod extra;
 extern mod glfw;
 extern mod gl;
 extern mod cgmath;
-extern mod noise;
 
 use std::cast;
 use std::ptr;
 use std::hashmap::HashMap;
+use std::hashmap::HashSet;
 use std;
-use std::num::clamp;
+use std::path::Path;
 
-use extra::time::precise_time_ns;
-use extra::bitv::BitvSet;
+use extra::arc::Arc;
 
 use gl::types::*;
 
 use cgmath::vector::Vector;
+use cgmath::vector::Vec2;
 use cgmath::vector::Vec3;
 
-use noise::Perlin;
-
 use CHUNK_SIZE;
-use VISIBLE_RADIUS;
 use GraphicsResources;
-
-static NUM_FACES : uint = 6;
-static MAX_CHUNKS : uint = (VISIBLE_RADIUS*2)*(VISIBLE_RADIUS*2)*2;
-
-#[repr(u8)]
-#[deriving(Eq)]
-pub enum BlockType {
-    BlockAir = 0,
-    BlockGrass = 1,
-    BlockStone = 2,
-    BlockDirt = 3,
-    BlockWater = 4,
-}
+use blocks::{BlockId, BlockRegistry, AIR};
+use light;
+use light::{LightMap, Channel};
+use region::RegionStore;
+use terrain::TerrainGenerator;
+use worker::{WorkerPool, ChunkReady, ChunkMeshed, ChunkCancelled};
+use mesher;
+use texture;
+use mesher::{MeshData, Neighbours, Face, NUM_FACES};
+
+static NUM_WORKERS : uint = 4;
+/// Leftover light updates run per call to upload(), besides its budget
+static LIGHT_JOBS_PER_UPLOAD : uint = 4;
 
 pub struct ChunkLoader {
-    seed : u32,
-    cache : HashMap<(i64, i64), ~Chunk>
+    cache : HashMap<(i64, i64, i64), ~Chunk>,
+    store : RegionStore,
+    workers : WorkerPool,
+    pending : HashSet<(i64, i64, i64)>,
+    registry : Arc<BlockRegistry>,
+    /// Remesh requests sent to the workers and not yet returned
+    remeshing : uint,
+    /// Least recently used chunks are evicted beyond this
+    max_chunks : uint,
+    /// Chunks whose meshes saw light change since they were last remeshed
+    relit : HashSet<(i64, i64, i64)>,
+    /// Light updates that reached too far to finish at once
+    light_jobs : ~[light::Job],
+    /// Chunk holding the camera when the visible set was last updated
+    visible_from : Option<(i64, i64, i64)>,
 }
 
 impl ChunkLoader {
-    pub fn new(seed : u32) -> ChunkLoader {
+    pub fn new(generator : ~TerrainGenerator:Send+Freeze,
+               registry : Arc<BlockRegistry>,
+               world_dir : Path,
+               max_chunks : uint,
+               merge_types : bool) -> ChunkLoader {
+        let store = RegionStore::new(world_dir);
         ChunkLoader {
-            seed: seed,
             cache: HashMap::new(),
+            workers: WorkerPool::new(NUM_WORKERS, generator, registry.clone(), store.clone(),
+                                     merge_types),
+            store: store,
+            pending: HashSet::new(),
+            registry: registry,
+            remeshing: 0,
+            max_chunks: max_chunks,
+            relit: HashSet::new(),
+            light_jobs: ~[],
+            visible_from: None,
+        }
+    }
+
+    /// Queues a chunk for loading on the worker pool, to be meshed at level
+    /// of detail `lod`, unless it is already loaded or queued.
+    pub fn request(&mut self, cx : i64, cy : i64, cz : i64, lod : uint) {
+        let coords = (cx, cy, cz);
+        if self.cache.contains_key(&coords) || self.pending.contains(&coords) {
+            return;
         }
+        self.pending.insert(coords);
+        self.workers.request(coords, lod);
     }
 
-    pub fn load(&mut self, cx : i64, cz: i64) {
-        println!("loading chunk ({}, {})", cx, cz);
-        let chunk = chunk_gen(self.seed, cx, cz);
-        self.cache.insert((cx, cz), chunk);
+    /// Changes the level of detail of a loaded chunk. Its old mesh is drawn
+    /// until the new one arrives. Neighbours are remeshed too, since faces
+    /// are only culled between chunks at the same level.
+    pub fn set_lod(&mut self, coords : (i64, i64, i64), lod : uint) {
+        match self.cache.find_mut(&coords) {
+            Some(chunk) if chunk.lod != lod => chunk.lod = lod,
+            _ => return,
+        }
+
+        let neighbours = self.neighbours(coords);
+        self.queue_remesh(coords, neighbours);
+
+        for face in mesher::faces.iter() {
+            let nc = neighbour_coords(coords, face);
+            if self.cache.contains_key(&nc) {
+                let neighbours = self.neighbours(nc);
+                self.queue_remesh(nc, neighbours);
+            }
+        }
+    }
+
+    /// Calls set_visible with `coords`, the chunks visible from
+    /// `camera_chunk`, if the camera has moved into another chunk since the
+    /// last call. Cheap enough to call every frame.
+    pub fn update_visible(&mut self, camera_chunk : (i64, i64, i64),
+                          coords : &[(i64, i64, i64)]) {
+        if self.visible_from == Some(camera_chunk) {
+            return;
+        }
+        self.set_visible(coords);
+        self.visible_from = Some(camera_chunk);
+    }
+
+    /// Tells the workers which chunks are still needed. Queued chunks outside
+    /// this set are cancelled.
+    pub fn set_visible(&mut self, coords : &[(i64, i64, i64)]) {
+        self.visible_from = None;
+        self.workers.set_wanted(coords);
+
+        let visible : HashSet<(i64, i64, i64)> = coords.iter().map(|&c| c).collect();
+        self.pending = self.pending.iter().filter(|c| visible.contains(*c)).map(|&c| c).collect();
+    }
+
+    /// Loads the given chunks at their levels of detail and blocks until
+    /// they are uploaded with meshes that account for their neighbours.
+    /// Used to render a complete frame without a window.
+    pub fn load_all(&mut self, chunks : &[((i64, i64, i64), uint)]) {
+        let coords : ~[(i64, i64, i64)] = chunks.iter().map(|&(c, _)| c).collect();
+        self.set_visible(coords);
+        for &((cx, cy, cz), lod) in chunks.iter() {
+            self.request(cx, cy, cz, lod);
+        }
+
+        loop {
+            self.upload(coords.len());
+            if self.remeshing == 0 && self.light_jobs.is_empty() &&
+                    coords.iter().all(|c| self.cache.contains_key(c)) {
+                break;
+            }
+            std::io::timer::sleep(10);
+        }
+    }
+
+    /// Uploads up to `budget` finished chunks and meshes from the workers,
+    /// then runs a few leftover light updates.
+    pub fn upload(&mut self, budget : uint) {
+        let mut uploaded = 0;
+        while uploaded < budget {
+            match self.workers.try_recv() {
+                Some(ChunkReady(coords, map, light, lod, data)) => {
+                    if !self.pending.remove(&coords) {
+                        continue;
+                    }
+                    let (cx, cy, cz) = coords;
+                    println!("loaded chunk ({}, {}, {})", cx, cy, cz);
+                    self.insert(coords, new_chunk(cx, cy, cz, map, light, lod));
+                    /* A mesh that ignores the neighbours would pop when the remesh lands */
+                    if !self.neighbour_arrived(coords) {
+                        self.cache.get_mut(&coords).mesh = mesh_upload(data);
+                    }
+                    uploaded += 1;
+                },
+                Some(ChunkMeshed(coords, data, version)) => {
+                    self.remeshing -= 1;
+                    match self.cache.find_mut(&coords) {
+                        Some(chunk) if chunk.mesh_version == version => {
+                            chunk.mesh = mesh_upload(data);
+                            uploaded += 1;
+                        },
+                        _ => {}
+                    }
+                },
+                Some(ChunkCancelled(_)) => {},
+                None => break,
+            }
+        }
+
+        for _ in range(0, LIGHT_JOBS_PER_UPLOAD) {
+            match self.light_jobs.pop() {
+                Some(job) => self.run_light_job(job),
+                None => break,
+            }
+        }
+    }
+
+    /// The worker lit and meshed a new chunk without knowing its
+    /// neighbours. Join its light with theirs, then remesh it and each
+    /// loaded neighbour whose border faces it can now hide or whose light
+    /// changed. Returns true if the new chunk itself is being remeshed.
+    fn neighbour_arrived(&mut self, coords : (i64, i64, i64)) -> bool {
+        let registry = self.registry.clone();
+        let (cx, cy, cz) = coords;
+        let jobs = light::chunk_arrived(self, registry.get(), cx, cy, cz);
+        self.light_jobs.push_all_move(jobs);
+
+        let mut remesh = self.take_relit_meshes();
+
+        if self.neighbours(coords).any_blocks() {
+            remesh.insert(coords);
+        }
+
+        let lod = self.cache.get(&coords).lod;
+        for face in mesher::faces.iter() {
+            let nc = neighbour_coords(coords, face);
+            match self.cache.find(&nc) {
+                Some(neighbour) if neighbour.lod == lod => {},
+                _ => continue,
+            }
+
+            let hides_faces = {
+                let chunk = self.cache.get(&coords);
+                mesher::border(chunk.map, face).iter().any(|&id| id != AIR)
+            };
+            if hides_faces {
+                remesh.insert(nc);
+            }
+        }
+
+        for &c in remesh.iter() {
+            let neighbours = self.neighbours(c);
+            self.queue_remesh(c, neighbours);
+        }
+
+        remesh.contains(&coords)
+    }
+
+    /// Continues a light update, then remeshes the chunks it relit.
+    fn run_light_job(&mut self, job : light::Job) {
+        let registry = self.registry.clone();
+        let jobs = light::run_job(self, registry.get(), job);
+        self.light_jobs.push_all_move(jobs);
+
+        let remesh = self.take_relit_meshes();
+        for &c in remesh.iter() {
+            let neighbours = self.neighbours(c);
+            self.queue_remesh(c, neighbours);
+        }
+    }
+
+    fn take_relit(&mut self) -> HashSet<(i64, i64, i64)> {
+        std::util::replace(&mut self.relit, HashSet::new())
+    }
+
+    /// Takes the relit chunks whose meshes show light. Coarse meshes don't.
+    fn take_relit_meshes(&mut self) -> HashSet<(i64, i64, i64)> {
+        let relit = self.take_relit();
+        relit.iter().filter(|c| {
+            match self.cache.find(*c) {
+                Some(chunk) => chunk.lod == 0,
+                None => false,
+            }
+        }).map(|&c| c).collect()
+    }
 
-        while self.cache.len() > MAX_CHUNKS {
+    fn queue_remesh(&mut self, coords : (i64, i64, i64), neighbours : ~Neighbours) {
+        let chunk = self.cache.get_mut(&coords);
+        chunk.mesh_version += 1;
+        self.remeshing += 1;
+        self.workers.remesh(coords, chunk.map.clone(), chunk.light.clone(), chunk.lod,
+                            neighbours, chunk.mesh_version);
+    }
+
+    /// Collects the borders of the loaded chunks adjacent to `coords` that
+    /// are at the same level of detail.
+    fn neighbours(&self, coords : (i64, i64, i64)) -> ~Neighbours {
+        let lod = self.cache.get(&coords).lod;
+        let mut neighbours = ~Neighbours::new();
+        for face in mesher::faces.iter() {
+            match self.cache.find(&neighbour_coords(coords, face)) {
+                Some(chunk) if chunk.lod == lod => neighbours.set(face, chunk.map, chunk.light, lod),
+                _ => {}
+            }
+        }
+        neighbours
+    }
+
+    fn insert(&mut self, coords : (i64, i64, i64), chunk : ~Chunk) {
+        self.cache.insert(coords, chunk);
+
+        while self.cache.len() > self.max_chunks {
             let (&k, _) = self.cache.iter().min_by(|&(_, chunk)| chunk.used_time).unwrap();
-            self.cache.remove(&k);
+            match self.cache.pop(&k) {
+                Some(chunk) => self.save_chunk(chunk),
+                None => {}
+            }
+        }
+    }
+
+    /// Writes every chunk with unsaved edits to disk.
+    pub fn save_all(&mut self) {
+        let failed = {
+            let unsaved : ~[((i64, i64, i64), &Map)] = self.cache.iter()
+                .filter(|&(_, chunk)| chunk.unsaved)
+                .map(|(&coords, chunk)| (coords, &*chunk.map))
+                .collect();
+            self.store.save_all(unsaved)
+        };
+
+        for (_, chunk) in self.cache.mut_iter() {
+            chunk.unsaved = false;
+        }
+        for &((cx, cy, cz), ref msg) in failed.iter() {
+            println!("failed to save chunk ({}, {}, {}): {}", cx, cy, cz, *msg);
+            self.cache.get_mut(&(cx, cy, cz)).unsaved = true;
+        }
+    }
+
+    fn save_chunk(&self, chunk: &Chunk) {
+        if chunk.unsaved {
+            match self.store.save(chunk.x, chunk.y, chunk.z, chunk.map) {
+                Ok(()) => {},
+                Err(msg) => println!("failed to save chunk ({}, {}, {}): {}",
+                                     chunk.x, chunk.y, chunk.z, msg),
+            }
+        }
+    }
+
+    /// Returns the block at the given world coordinates, or None if the
+    /// owning chunk is not loaded.
+    pub fn get_block(&self, x: i64, y: i64, z: i64) -> Option<Block> {
+        let (cx, cy, cz) = chunk_coords(x, y, z);
+        match self.cache.find(&(cx, cy, cz)) {
+            Some(chunk) => Some(chunk.map.blocks[(x - cx) as uint][(y - cy) as uint][(z - cz) as uint]),
+            None => None,
+        }
+    }
+
+    /// Whether the block at the given world coordinates stops the player.
+    /// Chunks that are not loaded yet count as solid, so the player waits
+    /// for the ground to appear instead of falling through it.
+    pub fn is_solid(&self, x: i64, y: i64, z: i64) -> bool {
+        match self.get_block(x, y, z) {
+            Some(block) => self.registry.get().is_solid(block.blocktype),
+            None => true,
+        }
+    }
+
+    /// Replaces the block at the given world coordinates and marks the
+    /// owning chunk, plus any neighbour sharing the edited face, as dirty.
+    /// Full-detail chunks whose light changed are remeshed. Returns false if
+    /// the owning chunk is not loaded.
+    pub fn set_block(&mut self, x: i64, y: i64, z: i64, block: Block) -> bool {
+        let (cx, cy, cz) = chunk_coords(x, y, z);
+        match self.cache.find_mut(&(cx, cy, cz)) {
+            Some(chunk) => {
+                chunk.map.blocks[(x - cx) as uint][(y - cy) as uint][(z - cz) as uint] = block;
+                chunk.dirty = true;
+                chunk.unsaved = true;
+            },
+            None => return false,
+        }
+
+        let size = CHUNK_SIZE as i64;
+        let lx = x - cx;
+        let ly = y - cy;
+        let lz = z - cz;
+
+        if lx == 0 { self.mark_dirty(cx - size, cy, cz); }
+        if lx == size - 1 { self.mark_dirty(cx + size, cy, cz); }
+        if ly == 0 { self.mark_dirty(cx, cy - size, cz); }
+        if ly == size - 1 { self.mark_dirty(cx, cy + size, cz); }
+        if lz == 0 { self.mark_dirty(cx, cy, cz - size); }
+        if lz == size - 1 { self.mark_dirty(cx, cy, cz + size); }
+
+        let registry = self.registry.clone();
+        let jobs = light::block_changed(self, registry.get(), x, y, z);
+        self.light_jobs.push_all_move(jobs);
+
+        /* Dirty chunks are remeshed by remesh_dirty */
+        let remesh = self.take_relit_meshes();
+        for &c in remesh.iter() {
+            if !self.cache.get(&c).dirty {
+                let neighbours = self.neighbours(c);
+                self.queue_remesh(c, neighbours);
+            }
+        }
+
+        true
+    }
+
+    fn mark_dirty(&mut self, cx: i64, cy: i64, cz: i64) {
+        match self.cache.find_mut(&(cx, cy, cz)) {
+            Some(chunk) => chunk.dirty = true,
+            None => {}
+        }
+    }
+
+    /// Queues remeshes of every chunk modified since the last call. The old
+    /// meshes are drawn until upload() installs the new ones.
+    pub fn remesh_dirty(&mut self) {
+        let dirty : ~[(i64, i64, i64)] =
+            self.cache.iter().filter(|&(_, chunk)| chunk.dirty).map(|(&k, _)| k).collect();
+
+        for &coords in dirty.iter() {
+            self.cache.get_mut(&coords).dirty = false;
+            /* Bumps the mesh version, so in-flight remeshes of the old blocks are dropped */
+            let neighbours = self.neighbours(coords);
+            self.queue_remesh(coords, neighbours);
+        }
+    }
+}
+
+impl light::World for ChunkLoader {
+    fn block(&self, x: i64, y: i64, z: i64) -> Option<BlockId> {
+        self.get_block(x, y, z).map(|b| b.blocktype)
+    }
+
+    fn light(&self, channel: Channel, x: i64, y: i64, z: i64) -> Option<u8> {
+        let (cx, cy, cz) = chunk_coords(x, y, z);
+        match self.cache.find(&(cx, cy, cz)) {
+            Some(chunk) => Some(chunk.light.get(channel, (x - cx) as uint, (y - cy) as uint, (z - cz) as uint)),
+            None => None,
+        }
+    }
+
+    fn set_light(&mut self, channel: Channel, x: i64, y: i64, z: i64, level: u8) {
+        let (cx, cy, cz) = chunk_coords(x, y, z);
+        match self.cache.find_mut(&(cx, cy, cz)) {
+            Some(chunk) => chunk.light.set(channel, (x - cx) as uint, (y - cy) as uint, (z - cz) as uint, level),
+            None => return,
+        }
+        self.relit.insert((cx, cy, cz));
+
+        /* Faces in the neighbouring chunks look into border blocks */
+        let last = CHUNK_SIZE as i64 - 1;
+        let (lx, ly, lz) = (x - cx, y - cy, z - cz);
+        if lx == 0 || lx == last || ly == 0 || ly == last || lz == 0 || lz == last {
+            for face in mesher::faces.iter() {
+                self.relit.insert(chunk_coords(x + face.normal.x as i64,
+                                               y + face.normal.y as i64,
+                                               z + face.normal.z as i64));
+            }
         }
     }
 }
 
+/// Returns the origin of the chunk across `face` from the chunk at `coords`.
+fn neighbour_coords(coords : (i64, i64, i64), face : &Face) -> (i64, i64, i64) {
+    let (cx, cy, cz) = coords;
+    let size = CHUNK_SIZE as i64;
+    (cx + face.normal.x as i64 * size,
+     cy + face.normal.y as i64 * size,
+     cz + face.normal.z as i64 * size)
+}
+
+/// Returns the origin of the chunk containing the given world coordinates.
+pub fn chunk_coords(x: i64, y: i64, z: i64) -> (i64, i64, i64) {
+    let mask : i64 = !(CHUNK_SIZE as i64 - 1);
+    (x & mask, y & mask, z & mask)
+}
+
 pub struct Chunk {
     x: i64,
+    y: i64,
     z: i64,
     map: ~Map,
+    light: ~LightMap,
     mesh: ~Mesh,
+    /// Level of detail of the mesh, or of the remesh in flight
+    lod: uint,
     used_time: u64,
+    dirty: bool,
+    unsaved: bool,
+    mesh_version: uint,
 }
 
 impl Chunk {
@@ -90,21 +491,23 @@ impl Chunk {
     }
 }
 
-struct Block {
-    blocktype: BlockType,
-}
-
-impl Block {
-    pub fn is_opaque(&self) -> bool {
-        self.blocktype != BlockAir
-    }
+#[deriving(Clone)]
+pub struct Block {
+    blocktype: BlockId,
 }
 
-struct Map {
+pub struct Map {
     blocks: [[[Block, ..CHUNK_SIZE], ..CHUNK_SIZE], ..CHUNK_SIZE],
 }
 
 impl Map {
+    pub fn new() -> ~Map {
+        let def_block = Block { blocktype: AIR };
+        ~Map {
+            blocks: [[[def_block, ..CHUNK_SIZE], ..CHUNK_SIZE], ..CHUNK_SIZE],
+        }
+    }
+
     pub fn index<'a>(&'a self, x: int, y: int, z: int) -> Option<&'a Block> {
         if x < 0 || x >= CHUNK_SIZE as int || y < 0 || y >= CHUNK_SIZE as int || z < 0 || z >= CHUNK_SIZE as int {
             None
@@ -114,15 +517,44 @@ impl Map {
     }
 }
 
+impl Clone for Map {
+    fn clone(&self) -> Map {
+        Map { blocks: self.blocks }
+    }
+}
+
 struct Mesh {
     vertex_buffer: GLuint,
     normal_buffer: GLuint,
     blocktype_buffer: GLuint,
+    occlusion_buffer: GLuint,
+    light_buffer: GLuint,
     element_buffer: GLuint,
     face_ranges: [(uint, uint), ..NUM_FACES],
+    /// Indexes the same vertex buffers; drawn after the opaque geometry
+    transparent_element_buffer: GLuint,
+    transparent_count: uint,
+    /// 3D texture of block ids, for meshes that merge block types; else 0
+    block_texture: GLuint,
 }
 
 impl Mesh {
+    /// A mesh with no faces and no GL objects.
+    pub fn empty() -> ~Mesh {
+        ~Mesh {
+            vertex_buffer: 0,
+            normal_buffer: 0,
+            blocktype_buffer: 0,
+            occlusion_buffer: 0,
+            light_buffer: 0,
+            element_buffer: 0,
+            face_ranges: [(0, 0), ..NUM_FACES],
+            transparent_element_buffer: 0,
+            transparent_count: 0,
+            block_texture: 0,
+        }
+    }
+
     pub fn bind_arrays(&self, res: &GraphicsResources) {
         unsafe {
             let vert_attr = "position".with_c_str(|ptr| gl::GetAttribLocation(res.program, ptr));
@@ -146,8 +578,55 @@ impl Mesh {
             gl::VertexAttribPointer(blocktype_attr as GLuint, 1, gl::FLOAT,
                                     gl::FALSE as GLboolean, 0, ptr::null());
 
+            let occlusion_attr = "occlusion".with_c_str(|ptr| gl::GetAttribLocation(res.program, ptr));
+            assert!(occlusion_attr as u32 != gl::INVALID_VALUE);
+            gl::BindBuffer(gl::ARRAY_BUFFER, self.occlusion_buffer);
+            gl::EnableVertexAttribArray(occlusion_attr as GLuint);
+            gl::VertexAttribPointer(occlusion_attr as GLuint, 1, gl::FLOAT,
+                                    gl::FALSE as GLboolean, 0, ptr::null());
+
+            let light_attr = "light".with_c_str(|ptr| gl::GetAttribLocation(res.program, ptr));
+            assert!(light_attr as u32 != gl::INVALID_VALUE);
+            gl::BindBuffer(gl::ARRAY_BUFFER, self.light_buffer);
+            gl::EnableVertexAttribArray(light_attr as GLuint);
+            gl::VertexAttribPointer(light_attr as GLuint, 2, gl::FLOAT,
+                                    gl::FALSE as GLboolean, 0, ptr::null());
+
             gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, self.element_buffer);
         }
+
+        if self.block_texture != 0 {
+            gl::ActiveTexture(gl::TEXTURE1);
+            gl::BindTexture(gl::TEXTURE_3D, self.block_texture);
+            gl::ActiveTexture(gl::TEXTURE0);
+        }
+    }
+
+    /// Switches the element buffer to the transparent quads. Call after
+    /// bind_arrays.
+    pub fn bind_transparent_elements(&self) {
+        gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, self.transparent_element_buffer);
+    }
+
+    /// Binds only the vertex positions and the opaque elements, for
+    /// depth-only passes with `program`.
+    pub fn bind_positions(&self, program: GLuint) {
+        unsafe {
+            let vert_attr = "position".with_c_str(|ptr| gl::GetAttribLocation(program, ptr));
+            assert!(vert_attr as u32 != gl::INVALID_VALUE);
+            gl::BindBuffer(gl::ARRAY_BUFFER, self.vertex_buffer);
+            gl::EnableVertexAttribArray(vert_attr as GLuint);
+            gl::VertexAttribPointer(vert_attr as GLuint, 3, gl::FLOAT,
+                                    gl::FALSE as GLboolean, 0, ptr::null());
+        }
+
+        gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, self.element_buffer);
+    }
+
+    /// Number of elements in the opaque faces, which fill the element
+    /// buffer in order.
+    pub fn opaque_count(&self) -> uint {
+        self.face_ranges.iter().fold(0, |total, &(_, count)| total + count)
     }
 }
 
@@ -157,217 +636,56 @@ impl Drop for Mesh {
             gl::DeleteBuffers(1, &self.vertex_buffer);
             gl::DeleteBuffers(1, &self.normal_buffer);
             gl::DeleteBuffers(1, &self.blocktype_buffer);
+            gl::DeleteBuffers(1, &self.occlusion_buffer);
+            gl::DeleteBuffers(1, &self.light_buffer);
             gl::DeleteBuffers(1, &self.element_buffer);
+            gl::DeleteBuffers(1, &self.transparent_element_buffer);
+            gl::DeleteTextures(1, &self.block_texture);
         }
     }
 }
 
-pub struct Face {
-    index: uint,
-    normal: Vec3<f32>,
-    di: Vec3<uint>,
-    dj: Vec3<uint>,
-    dk: Vec3<uint>,
-    vertices: [Vec3<f32>, ..4],
-}
-
-pub fn chunk_gen(seed: u32, chunk_x: i64, chunk_z: i64) -> ~Chunk {
-    let def_block = Block { blocktype: BlockAir };
-    let mut map = ~Map {
-        blocks: [[[def_block, ..CHUNK_SIZE], ..CHUNK_SIZE], ..CHUNK_SIZE],
-    };
-
-    terrain_gen(seed, chunk_x, chunk_z, map);
-
-    let mesh = mesh_gen(chunk_x, chunk_z, map);
-
+/// Creates a chunk with nothing to draw until it is given a mesh.
+fn new_chunk(chunk_x: i64, chunk_y: i64, chunk_z: i64, map: ~Map, light: ~LightMap,
+             lod: uint) -> ~Chunk {
     return ~Chunk {
         x: chunk_x,
+        y: chunk_y,
         z: chunk_z,
         map: map,
-        mesh: mesh,
+        light: light,
+        mesh: Mesh::empty(),
+        lod: lod,
         used_time: extra::time::precise_time_ns(),
+        dirty: false,
+        unsaved: false,
+        mesh_version: 0,
     };
 }
 
-fn block_exists(map: &Map, x: int, y: int, z: int) -> bool {
-    if y < 0 {
-        return true;
-    }
-
-    match map.index(x, y, z) {
-        Some(block) => block.is_opaque(),
-        None => false
-    }
-}
-
-fn terrain_gen(seed: u32, chunk_x: i64, chunk_z: i64, map: &mut Map) {
-    let start_time = precise_time_ns();
-
-    let perlin1 = Perlin::from_seed([seed as uint]);
-    let perlin2 = Perlin::from_seed([seed as uint * 7]);
-    let perlin3 = Perlin::from_seed([seed as uint * 13]);
-    let perlin4 = Perlin::from_seed([seed as uint * 17]);
-
-    for block_x in std::iter::range(0, CHUNK_SIZE) {
-        for block_z in std::iter::range(0, CHUNK_SIZE) {
-            let noise1 = perlin1.gen([
-                (chunk_x + block_x as i64) as f64 * 0.07,
-                (chunk_z + block_z as i64) as f64 * 0.04
-            ]);
-            let noise2 = perlin2.gen([
-                (chunk_x + block_x as i64) as f64 * 0.05,
-                (chunk_z + block_z as i64) as f64 * 0.05
-            ]);
-            let noise3 = perlin3.gen([
-                (chunk_x + block_x as i64) as f64 * 0.005,
-                (chunk_z + block_z as i64) as f64 * 0.005
-            ]);
-            let noise4 = perlin4.gen([
-                (chunk_x + block_x as i64) as f64 * 0.001,
-                (chunk_z + block_z as i64) as f64 * 0.001
-            ]);
-
-            let base_height = 15.0;
-            let base_variance = 10.0;
-            let height = clamp(
-                (
-                    base_height +
-                    noise4 * 10.0 +
-                    base_variance *
-                        std::num::pow(noise3 + 1.0, 2.5) *
-                        noise1
-                ) as int,
-                1, CHUNK_SIZE as int - 1) as uint;
-
-            for y in range(0, height) {
-                let mut blocktype = BlockStone;
-
-                let dirt_height = (4.0 + noise2 * 8.0) as uint;
-                if (height <= 20) && (y + dirt_height >= height) {
-                    if y < height - 2 {
-                        blocktype = BlockDirt;
-                    } else {
-                        blocktype = BlockGrass;
-                    }
-                }
-
-                map.blocks[block_x][y][block_z] = Block { blocktype: blocktype };
-            }
-
-            let water_height = 10;
-            for y in range(height, water_height) {
-                map.blocks[block_x][y][block_z] = Block { blocktype: BlockWater };
-            }
-        }
-    }
-
-    let end_time = precise_time_ns();
-
-    println!("terrain gen : {}us",
-             (end_time - start_time)/1000);
-}
-
-fn mesh_gen(chunk_x: i64, chunk_z: i64, map: &Map) -> ~Mesh {
-    let start_time = precise_time_ns();
-
-    let mut vertices : ~[Vec3<f32>] = ~[];
-    let mut normals : ~[Vec3<f32>] = ~[];
-    let mut blocktypes : ~[f32] = ~[];
-    let mut elements : ~[GLuint] = ~[];
-
-    static expected_vertices : uint = 8000;
-    static expected_elements : uint = expected_vertices * 3 / 2;
-    vertices.reserve(expected_vertices);
-    normals.reserve(expected_vertices);
-    blocktypes.reserve(expected_vertices);
-    elements.reserve(expected_elements);
-
-    let mut face_ranges = [(0, 0), ..6];
-
-    let chunk_position = Vec3 {
-        x: chunk_x as f32,
-        y: 0.0f32,
-        z: chunk_z as f32,
-    };
-
-    for face in faces.iter() {
-        let num_elements_start = elements.len();
-
-        let face_normal_int = Vec3 { x: face.normal.x as int, y: face.normal.y as int, z: face.normal.z as int };
-
-        let mut unmeshed_faces = BlockBitmap::new();
-        for x in std::iter::range(0, CHUNK_SIZE) {
-            for y in std::iter::range(0, CHUNK_SIZE) {
-                for z in std::iter::range(0, CHUNK_SIZE) {
-                    let block = &map.blocks[x][y][z];
-
-                    if (block.blocktype == BlockAir) {
-                        continue;
-                    }
-
-                    if block_exists(map,
-                                    x as int + face_normal_int.x,
-                                    y as int + face_normal_int.y,
-                                    z as int + face_normal_int.z) {
-                        continue;
-                    }
-
-                    unmeshed_faces.insert(x, y, z);
-                }
-            }
-        }
-
-        for i in std::iter::range(0, CHUNK_SIZE) {
-            for j in std::iter::range(0, CHUNK_SIZE) {
-                for k in std::iter::range(0, CHUNK_SIZE) {
-                    let Vec3 { x: x, y: y, z: z } = face.di.mul_s(i).add_v(&face.dj.mul_s(j)).add_v(&face.dk.mul_s(k));
-                    let block = &map.blocks[x][y][z];
-
-                    if !unmeshed_faces.contains(x, y, z) {
-                        continue;
-                    }
-
-                    let block_position = Vec3 {
-                        x: x as f32,
-                        y: y as f32,
-                        z: z as f32,
-                    };
-
-                    let dim = expand_face(map, &unmeshed_faces, face, Vec3 { x: x, y: y, z: z });
-                    let dim_f = Vec3 { x: dim.x as f32, y: dim.y as f32, z: dim.z as f32 };
-
-                    for dx in range(0, dim.x) {
-                        for dy in range(0, dim.y) {
-                            for dz in range(0, dim.z) {
-                                unmeshed_faces.remove(x + dx, y + dy, z + dz);
-                            }
-                        }
-                    }
-
-                    let vertex_offset = vertices.len();
-                    for v in face.vertices.iter() {
-                        vertices.push(v.mul_v(&dim_f).add_v(&block_position).add_v(&chunk_position));
-                        normals.push(face.normal);
-                        blocktypes.push(block.blocktype as f32);
-                    }
-
-                    for e in face_elements.iter() {
-                        elements.push(vertex_offset as GLuint + *e);
-                    }
-                }
-            }
-        }
-
-        face_ranges[face.index] = (num_elements_start, elements.len() - num_elements_start);
-    }
+/// Copies mesh data into GL buffers. Must be called on the render thread.
+pub fn mesh_upload(data: &MeshData) -> ~Mesh {
+    let MeshData {
+        vertices: ref vertices,
+        normals: ref normals,
+        blocktypes: ref blocktypes,
+        occlusion: ref occlusion,
+        light: ref light,
+        elements: ref elements,
+        face_ranges: face_ranges,
+        transparent_elements: ref transparent_elements,
+        materials: ref materials,
+    } = *data;
 
     let mut vertex_buffer = 0;
     let mut normal_buffer = 0;
     let mut blocktype_buffer = 0;
+    let mut occlusion_buffer = 0;
+    let mut light_buffer = 0;
     let mut element_buffer = 0;
+    let mut transparent_element_buffer = 0;
 
-    if !elements.is_empty() {
+    if !vertices.is_empty() {
         unsafe {
             // Create a Vertex Buffer Object and copy the vertex data to it
             gl::GenBuffers(1, &mut vertex_buffer);
@@ -393,192 +711,62 @@ fn mesh_gen(chunk_x: i64, chunk_z: i64, map: &Map) -> ~Mesh {
                         cast::transmute(&blocktypes[0]),
                         gl::STATIC_DRAW);
 
-            // Create a Vertex Buffer Object and copy the element data to it
-            gl::GenBuffers(1, &mut element_buffer);
-            gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, element_buffer);
-            gl::BufferData(gl::ELEMENT_ARRAY_BUFFER,
-                        (elements.len() * std::mem::size_of::<GLuint>()) as GLsizeiptr,
-                        cast::transmute(&elements[0]),
+            // Create a Vertex Buffer Object and copy the occlusion data to it
+            gl::GenBuffers(1, &mut occlusion_buffer);
+            gl::BindBuffer(gl::ARRAY_BUFFER, occlusion_buffer);
+            gl::BufferData(gl::ARRAY_BUFFER,
+                        (occlusion.len() * std::mem::size_of::<f32>()) as GLsizeiptr,
+                        cast::transmute(&occlusion[0]),
+                        gl::STATIC_DRAW);
+
+            // Create a Vertex Buffer Object and copy the light data to it
+            gl::GenBuffers(1, &mut light_buffer);
+            gl::BindBuffer(gl::ARRAY_BUFFER, light_buffer);
+            gl::BufferData(gl::ARRAY_BUFFER,
+                        (light.len() * std::mem::size_of::<Vec2<f32>>()) as GLsizeiptr,
+                        cast::transmute(&light[0]),
                         gl::STATIC_DRAW);
         }
     }
 
-    let end_time = precise_time_ns();
+    if !elements.is_empty() {
+        element_buffer = element_upload(*elements);
+    }
 
-    println!("mesh gen : {}us; vertices={}; elements={}",
-             (end_time - start_time)/1000,
-             vertices.len(), elements.len())
+    if !transparent_elements.is_empty() {
+        transparent_element_buffer = element_upload(*transparent_elements);
+    }
+
+    let block_texture = if materials.is_empty() {
+        0
+    } else {
+        texture::make_block_texture(*materials)
+    };
 
     ~Mesh {
         vertex_buffer: vertex_buffer,
         normal_buffer: normal_buffer,
         blocktype_buffer: blocktype_buffer,
+        occlusion_buffer: occlusion_buffer,
+        light_buffer: light_buffer,
         element_buffer: element_buffer,
         face_ranges: face_ranges,
+        transparent_element_buffer: transparent_element_buffer,
+        transparent_count: transparent_elements.len(),
+        block_texture: block_texture,
     }
 }
 
-fn expand_face(map : &Map,
-               unmeshed_faces : &BlockBitmap,
-               face: &Face,
-               p: Vec3<uint>) -> Vec3<uint> {
-
-    let len_k = run_length(map, unmeshed_faces, p, face.dk);
-    let len_j = range(0, len_k).
-        map(|k| run_length(map, unmeshed_faces, p.add_v(&face.dk.mul_s(k)), face.dj)).
-        min().unwrap();
-
-    (Vec3 { x: 1, y: 1, z: 1 }).
-        add_v(&face.dk.mul_s(len_k - 1)).
-        add_v(&face.dj.mul_s(len_j - 1))
-}
-
-fn run_length(map : &Map,
-              unmeshed_faces : &BlockBitmap,
-              mut p: Vec3<uint>,
-              dp: Vec3<uint>) -> uint {
-    let block = &map.blocks[p.x][p.y][p.z];
-    let mut len = 1;
-
-    loop {
-        p.add_self_v(&dp);
-        if unmeshed_faces.contains(p.x, p.y, p.z) {
-            match map.index(p.x as int, p.y as int, p.z as int) {
-                Some(b) if b.blocktype == block.blocktype => {
-                    len += 1;
-                }
-                _ => {
-                    break;
-                }
-            }
-        } else {
-            break;
-        }
-    }
-
-    len
-}
-
-struct BlockBitmap {
-    set : BitvSet
-}
-
-impl BlockBitmap {
-    pub fn new() -> BlockBitmap {
-        BlockBitmap {
-            set: BitvSet::new()
-        }
-    }
-
-    pub fn contains(&self, x: uint, y: uint, z: uint) -> bool {
-        self.set.contains(&BlockBitmap::index(x, y, z))
-    }
-
-    pub fn insert(&mut self, x: uint, y: uint, z: uint) {
-        self.set.insert(BlockBitmap::index(x, y, z));
-    }
-
-    pub fn remove(&mut self, x: uint, y: uint, z: uint) {
-        self.set.remove(&BlockBitmap::index(x, y, z));
-    }
-
-    fn index(x: uint, y: uint, z: uint) -> uint {
-        x*CHUNK_SIZE*CHUNK_SIZE + y*CHUNK_SIZE + z
+/// Creates an element buffer holding `elements`.
+fn element_upload(elements: &[u32]) -> GLuint {
+    let mut buffer = 0;
+    unsafe {
+        gl::GenBuffers(1, &mut buffer);
+        gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, buffer);
+        gl::BufferData(gl::ELEMENT_ARRAY_BUFFER,
+                    (elements.len() * std::mem::size_of::<u32>()) as GLsizeiptr,
+                    cast::transmute(&elements[0]),
+                    gl::STATIC_DRAW);
     }
+    buffer
 }
-
-static face_elements : [GLuint, ..6] = [
-    0, 1, 2, 3, 2, 1,
-];
-
-pub static faces : [Face, ..NUM_FACES] = [
-    /* front */
-    Face {
-        index: 0,
-        normal: Vec3 { x: 0.0, y: 0.0, z: 1.0 },
-        di: Vec3 { x: 0, y: 0, z: 1 },
-        dj: Vec3 { x: 1, y: 0, z: 0 },
-        dk: Vec3 { x: 0, y: 1, z: 0 },
-        vertices: [
-            Vec3 { x: 0.0, y: 0.0, z: 1.0 }, /* bottom left */
-            Vec3 { x: 1.0, y: 0.0, z: 1.0 },  /* bottom right */
-            Vec3 { x: 0.0, y: 1.0, z: 1.0 }, /* top left */
-            Vec3 { x: 1.0, y: 1.0, z: 1.0 },  /* top right */
-        ],
-    },
-
-    /* back */
-    Face {
-        index: 1,
-        normal: Vec3 { x: 0.0, y: 0.0, z: -1.0 },
-        di: Vec3 { x: 0, y: 0, z: 1 },
-        dj: Vec3 { x: 1, y: 0, z: 0 },
-        dk: Vec3 { x: 0, y: 1, z: 0 },
-        vertices: [
-            Vec3 { x: 1.0, y: 0.0, z: 0.0 }, /* bottom right */
-            Vec3 { x: 0.0, y: 0.0, z: 0.0 },  /* bottom left */
-            Vec3 { x: 1.0, y: 1.0, z: 0.0 }, /* top right */
-            Vec3 { x: 0.0, y: 1.0, z: 0.0 },  /* top left */
-        ],
-    },
-
-    /* right */
-    Face {
-        index: 2,
-        normal: Vec3 { x: 1.0, y: 0.0, z: 0.0 },
-        di: Vec3 { x: 1, y: 0, z: 0 },
-        dj: Vec3 { x: 0, y: 1, z: 0 },
-        dk: Vec3 { x: 0, y: 0, z: 1 },
-        vertices: [
-            Vec3 { x: 1.0, y: 0.0, z: 1.0 }, /* bottom front */
-            Vec3 { x: 1.0, y: 0.0, z: 0.0 }, /* bottom back */
-            Vec3 { x: 1.0, y: 1.0, z: 1.0 }, /* top front */
-            Vec3 { x: 1.0, y: 1.0, z: 0.0 }, /* top back */
-        ],
-    },
-
-    /* left */
-    Face {
-        index: 3,
-        normal: Vec3 { x: -1.0, y: 0.0, z: 0.0 },
-        di: Vec3 { x: 1, y: 0, z: 0 },
-        dj: Vec3 { x: 0, y: 1, z: 0 },
-        dk: Vec3 { x: 0, y: 0, z: 1 },
-        vertices: [
-            Vec3 { x: 0.0, y: 0.0, z: 0.0 }, /* bottom back */
-            Vec3 { x: 0.0, y: 0.0, z: 1.0 }, /* bottom front */
-            Vec3 { x: 0.0, y: 1.0, z: 0.0 }, /* top back */
-            Vec3 { x: 0.0, y: 1.0, z: 1.0 }, /* top front */
-        ],
-    },
-
-    /* top */
-    Face {
-        index: 4,
-        normal: Vec3 { x: 0.0, y: 1.0, z: 0.0 },
-        di: Vec3 { x: 0, y: 1, z: 0 },
-        dj: Vec3 { x: 1, y: 0, z: 0 },
-        dk: Vec3 { x: 0, y: 0, z: 1 },
-        vertices: [
-            Vec3 { x: 0.0, y: 1.0, z: 1.0 }, /* front left */
-            Vec3 { x: 1.0, y: 1.0, z: 1.0 }, /* front right */
-            Vec3 { x: 0.0, y: 1.0, z: 0.0 }, /* back left */
-            Vec3 { x: 1.0, y: 1.0, z: 0.0 }, /* back right */
-        ],
-    },
-
-    /* bottom */
-    Face {
-        index: 5,
-        normal: Vec3 { x: 0.0, y: -1.0, z: 0.0 },
-        di: Vec3 { x: 0, y: 1, z: 0 },
-        dj: Vec3 { x: 1, y: 0, z: 0 },
-        dk: Vec3 { x: 0, y: 0, z: 1 },
-        vertices: [
-            Vec3 { x: 0.0, y: 0.0, z: 0.0 }, /* back left */
-            Vec3 { x: 1.0, y: 0.0, z: 0.0 }, /* back right */
-            Vec3 { x: 0.0, y: 0.0, z: 1.0 }, /* front left */
-            Vec3 { x: 1.0, y: 0.0, z: 1.0 }, /* front right */
-        ],
-    },
-];
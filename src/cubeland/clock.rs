@@ -0,0 +1,182 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The world clock. It moves the sun across the sky and sets the colours
+//! of sunlight, ambient light and the sky for the time of day.
+
+extern mod cgmath;
+
+use std;
+
+use cgmath::vector::Vec3;
+
+/// Tilt of the sun's path from straight overhead, in radians, so that
+/// shadows never point straight down.
+static SUN_TILT : f32 = 0.4;
+
+/// Colours for one height of the sun.
+struct Keyframe {
+    /// Sine of the sun's elevation above the horizon
+    elevation: f32,
+    sun: [f32, ..3],
+    ambient: [f32, ..3],
+    sky: [f32, ..3],
+    zenith: [f32, ..3],
+}
+
+/// Lowest sun first. Below the first and above the last the colours are
+/// constant.
+static KEYFRAMES : [Keyframe, ..4] = [
+    /* Night */
+    Keyframe { elevation: -0.2, sun: [0.0, 0.0, 0.0],
+               ambient: [0.05, 0.05, 0.1], sky: [0.02, 0.03, 0.08],
+               zenith: [0.0, 0.0, 0.02] },
+    /* Sunrise and sunset */
+    Keyframe { elevation: 0.0, sun: [0.6, 0.35, 0.2],
+               ambient: [0.15, 0.12, 0.12], sky: [0.9, 0.5, 0.3],
+               zenith: [0.2, 0.3, 0.6] },
+    /* Morning */
+    Keyframe { elevation: 0.15, sun: [0.75, 0.7, 0.6],
+               ambient: [0.2, 0.2, 0.2], sky: [0.3, 0.7, 0.95],
+               zenith: [0.15, 0.4, 0.8] },
+    /* Day */
+    Keyframe { elevation: 0.3, sun: [0.8, 0.8, 0.8],
+               ambient: [0.2, 0.2, 0.2], sky: [0.0, 0.75, 1.0],
+               zenith: [0.1, 0.4, 0.9] },
+];
+
+pub struct Clock {
+    /// Fraction of the day since midnight, from 0 to 1
+    time: f64,
+    /// Real seconds per day
+    day_length: f64,
+    frozen: bool,
+}
+
+/// The sun and sky for one moment.
+pub struct Lighting {
+    /// Direction the sunlight travels, normalized
+    sun_direction: Vec3<f32>,
+    /// Diffuse sunlight, black while the sun is down
+    sun_color: Vec3<f32>,
+    ambient_color: Vec3<f32>,
+    /// The sky at the horizon, also used for the clear colour and the fog
+    sky_color: Vec3<f32>,
+    /// The sky straight up
+    zenith_color: Vec3<f32>,
+}
+
+impl Clock {
+    /// Starts at `hour` o'clock, with days `day_length` seconds long.
+    pub fn new(hour: f64, day_length: f64) -> Clock {
+        Clock {
+            time: hour / 24.0,
+            day_length: day_length,
+            frozen: false,
+        }
+    }
+
+    /// Moves time on by `seconds`, unless frozen.
+    pub fn advance(&mut self, seconds: f64) {
+        if self.frozen {
+            return;
+        }
+        self.time = (self.time + seconds / self.day_length) % 1.0;
+    }
+
+    pub fn toggle_frozen(&mut self) {
+        self.frozen = !self.frozen;
+    }
+
+    /// Hours since midnight.
+    pub fn hour(&self) -> f64 {
+        self.time * 24.0
+    }
+
+    pub fn lighting(&self) -> Lighting {
+        let sun_direction = sun_direction(self.time as f32);
+        let elevation = -sun_direction.y;
+
+        let first = &KEYFRAMES[0];
+        let last = &KEYFRAMES[KEYFRAMES.len() - 1];
+        let (a, b, t) = if elevation <= first.elevation {
+            (first, first, 0.0)
+        } else if elevation >= last.elevation {
+            (last, last, 0.0)
+        } else {
+            let i = range(1, KEYFRAMES.len()).find(|&i| elevation < KEYFRAMES[i].elevation).unwrap();
+            let (a, b) = (&KEYFRAMES[i - 1], &KEYFRAMES[i]);
+            (a, b, (elevation - a.elevation) / (b.elevation - a.elevation))
+        };
+
+        Lighting {
+            sun_direction: sun_direction,
+            sun_color: mix(&a.sun, &b.sun, t),
+            ambient_color: mix(&a.ambient, &b.ambient, t),
+            sky_color: mix(&a.sky, &b.sky, t),
+            zenith_color: mix(&a.zenith, &b.zenith, t),
+        }
+    }
+}
+
+/// The sun rises in +x at 6, is highest at noon and sets in -x at 18.
+fn sun_direction(time: f32) -> Vec3<f32> {
+    let angle = (time - 0.25) * 2.0 * std::f32::consts::PI;
+    /* Points towards the sun, so negate it */
+    Vec3::new(-angle.cos(),
+              -angle.sin() * SUN_TILT.cos(),
+              -angle.sin() * SUN_TILT.sin())
+}
+
+fn mix(a: &[f32, ..3], b: &[f32, ..3], t: f32) -> Vec3<f32> {
+    Vec3::new(a[0] + (b[0] - a[0]) * t,
+              a[1] + (b[1] - a[1]) * t,
+              a[2] + (b[2] - a[2]) * t)
+}
+
+#[test]
+fn test_clock_sun() {
+    /* Overhead at noon, coming from the east at sunrise, below at midnight */
+    assert!(Clock::new(12.0, 600.0).lighting().sun_direction.y < -0.9);
+    assert!(Clock::new(6.0, 600.0).lighting().sun_direction.x < -0.99);
+    assert!(Clock::new(0.0, 600.0).lighting().sun_direction.y > 0.9);
+
+    let noon = Clock::new(12.0, 600.0).lighting();
+    assert_eq!(noon.sky_color, Vec3::new(0.0f32, 0.75f32, 1.0f32));
+    assert_eq!(noon.sun_color, Vec3::new(0.8f32, 0.8f32, 0.8f32));
+
+    let midnight = Clock::new(0.0, 600.0).lighting();
+    assert_eq!(midnight.sun_color, Vec3::new(0.0f32, 0.0f32, 0.0f32));
+
+    /* Between keyframes */
+    let dusk = Clock::new(18.2, 600.0).lighting();
+    assert!(dusk.sun_color.x > 0.0 && dusk.sun_color.x < 0.6);
+}
+
+#[test]
+fn test_clock_advance() {
+    let mut clock = Clock::new(23.0, 240.0);
+
+    /* Ten seconds is an hour, wrapping past midnight */
+    clock.advance(20.0);
+    assert!((clock.hour() - 1.0).abs() < 0.001);
+
+    clock.toggle_frozen();
+    clock.advance(100.0);
+    assert!((clock.hour() - 1.0).abs() < 0.001);
+
+    clock.toggle_frozen();
+    clock.advance(10.0);
+    assert!((clock.hour() - 2.0).abs() < 0.001);
+}
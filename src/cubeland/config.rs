@@ -0,0 +1,485 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runtime settings, from the command line.
+//!
+//! CHUNK_SIZE is deliberately not here: it fixes the layout of `Map` and of
+//! the region files, so changing it is a compile-time decision.
+
+extern mod extra;
+extern mod cgmath;
+
+use std::path::Path;
+
+use extra::getopts::groups::{optopt, optflag, getopts, usage};
+use extra::getopts::groups::OptGroup;
+
+use cgmath::vector::Vec3;
+
+use CHUNK_SIZE;
+use mesher::MAX_LOD;
+
+/// Height of the ground for --generator flat without a height
+static DEFAULT_FLAT_HEIGHT : i64 = 20;
+
+/// Which terrain::TerrainGenerator fills new chunks.
+#[deriving(Eq, Clone)]
+pub enum Generator {
+    /// Noise-based hills, water and caves, from the seed
+    DefaultTerrain,
+    /// Ground at a fixed height
+    FlatTerrain(i64),
+    /// Heights from a binary PGM image
+    HeightmapTerrain(Path),
+}
+
+pub struct Config {
+    seed: u32,
+    generator: Generator,
+    /// Chunks drawn around the camera horizontally
+    visible_radius: uint,
+    /// Chunk layers drawn above and below the camera
+    visible_height: uint,
+    /// Rings of chunks around the camera drawn at full detail. Each
+    /// coarser level of detail covers twice the distance of the previous
+    /// one. Zero draws everything at full detail.
+    lod_distance: uint,
+    window_width: u32,
+    window_height: u32,
+    fullscreen: bool,
+    vsync: bool,
+    /// Merge faces of different block types into the same quads, looking
+    /// up each fragment's block type in a per-chunk texture instead
+    merge_faces: bool,
+    /// Width and height of each sun shadow map, or 0 for no shadows
+    shadow_size: u32,
+    /// Initial camera position
+    position: Vec3<f32>,
+    /// Initial camera pitch and yaw, in degrees
+    pitch: f32,
+    yaw: f32,
+    /// Vertical field of view in degrees
+    fov: f32,
+    /// Degrees turned per pixel of mouse movement
+    mouse_sensitivity: f32,
+    frame_time_target_ms: u64,
+    /// Flying speed in blocks per second
+    camera_speed: f32,
+    /// Start with the world bent around a planet of planet_radius
+    curvature: bool,
+    /// In blocks
+    planet_radius: f32,
+    /// Starting time of day, in hours since midnight
+    time_of_day: f64,
+    /// Real seconds per day
+    day_length: f64,
+    world_dir: Path,
+    /// Render one frame to this PNG instead of opening a window
+    headless: Option<Path>,
+    /// Golden image the headless frame must match
+    compare: Option<Path>,
+    /// Largest per-channel difference from the golden image that still matches
+    tolerance: u8,
+}
+
+impl Config {
+    pub fn default() -> Config {
+        Config {
+            seed: 42,
+            generator: DefaultTerrain,
+            visible_radius: 8,
+            visible_height: 2,
+            lod_distance: 4,
+            window_width: 800,
+            window_height: 600,
+            fullscreen: false,
+            vsync: true,
+            merge_faces: false,
+            shadow_size: 2048,
+            position: Vec3::new(0.0f32, 30.0f32, 40.0f32),
+            pitch: 0.0,
+            yaw: 0.0,
+            fov: 75.0,
+            mouse_sensitivity: 0.18,
+            frame_time_target_ms: 16,
+            camera_speed: 30.0,
+            curvature: false,
+            /* The Earth scaled down 5000 times */
+            planet_radius: 1274.0,
+            time_of_day: 10.0,
+            day_length: 1200.0,
+            world_dir: Path::new("world"),
+            headless: None,
+            compare: None,
+            tolerance: 0,
+        }
+    }
+
+    /// Parses the arguments following the program name. Returns Err with a
+    /// message, or the usage text for --help.
+    pub fn from_args(args: &[~str]) -> Result<Config, ~str> {
+        let opts = options();
+        let matches = match getopts(args, opts) {
+            Ok(m) => m,
+            Err(f) => return Err(f.to_err_msg()),
+        };
+
+        if matches.opt_present("help") {
+            return Err(usage("Usage: cubeland [options]", opts));
+        }
+        if !matches.free.is_empty() {
+            return Err(format!("unexpected argument {}", matches.free[0]));
+        }
+
+        let mut config = Config::default();
+
+        match matches.opt_str("seed") {
+            Some(s) => match from_str(s) {
+                Some(x) => config.seed = x,
+                None => return Err(format!("bad seed {}", s)),
+            },
+            None => {}
+        }
+
+        match matches.opt_str("generator") {
+            Some(s) => match parse_generator(s) {
+                Some(x) => config.generator = x,
+                None => return Err(format!("bad generator {}, expected default, flat[:HEIGHT] or heightmap:FILE", s)),
+            },
+            None => {}
+        }
+
+        match matches.opt_str("radius") {
+            Some(s) => match from_str(s) {
+                Some(x) => config.visible_radius = x,
+                None => return Err(format!("bad render distance {}", s)),
+            },
+            None => {}
+        }
+
+        match matches.opt_str("height") {
+            Some(s) => match from_str(s) {
+                Some(x) => config.visible_height = x,
+                None => return Err(format!("bad render height {}", s)),
+            },
+            None => {}
+        }
+
+        match matches.opt_str("lod-distance") {
+            Some(s) => match from_str(s) {
+                Some(x) => config.lod_distance = x,
+                None => return Err(format!("bad level of detail distance {}", s)),
+            },
+            None => {}
+        }
+
+        match matches.opt_str("size") {
+            Some(s) => match parse_list(s, 'x') {
+                Some(ref v) if v.len() == 2 && v[0] >= 1.0 && v[1] >= 1.0 => {
+                    config.window_width = v[0] as u32;
+                    config.window_height = v[1] as u32;
+                },
+                _ => return Err(format!("bad size {}, expected WIDTHxHEIGHT", s)),
+            },
+            None => {}
+        }
+
+        match matches.opt_str("shadow-size") {
+            Some(s) => match from_str(s) {
+                Some(x) => config.shadow_size = x,
+                None => return Err(format!("bad shadow map size {}", s)),
+            },
+            None => {}
+        }
+
+        match matches.opt_str("position") {
+            Some(s) => match parse_list(s, ',') {
+                Some(ref v) if v.len() == 3 => {
+                    config.position = Vec3::new(v[0] as f32, v[1] as f32, v[2] as f32);
+                },
+                _ => return Err(format!("bad position {}, expected X,Y,Z", s)),
+            },
+            None => {}
+        }
+
+        match matches.opt_str("angles") {
+            Some(s) => match parse_list(s, ',') {
+                Some(ref v) if v.len() == 2 => {
+                    config.pitch = v[0] as f32;
+                    config.yaw = v[1] as f32;
+                },
+                _ => return Err(format!("bad angles {}, expected PITCH,YAW", s)),
+            },
+            None => {}
+        }
+
+        match matches.opt_str("fov") {
+            Some(s) => match from_str(s) {
+                Some(x) => config.fov = x,
+                None => return Err(format!("bad field of view {}", s)),
+            },
+            None => {}
+        }
+
+        match matches.opt_str("sensitivity") {
+            Some(s) => match from_str(s) {
+                Some(x) => config.mouse_sensitivity = x,
+                None => return Err(format!("bad mouse sensitivity {}", s)),
+            },
+            None => {}
+        }
+
+        match matches.opt_str("planet-radius") {
+            Some(s) => match from_str(s) {
+                Some(x) => config.planet_radius = x,
+                None => return Err(format!("bad planet radius {}", s)),
+            },
+            None => {}
+        }
+
+        match matches.opt_str("time") {
+            Some(s) => match from_str(s) {
+                Some(x) => config.time_of_day = x,
+                None => return Err(format!("bad time of day {}", s)),
+            },
+            None => {}
+        }
+
+        match matches.opt_str("day-length") {
+            Some(s) => match from_str(s) {
+                Some(x) => config.day_length = x,
+                None => return Err(format!("bad day length {}", s)),
+            },
+            None => {}
+        }
+
+        match matches.opt_str("tolerance") {
+            Some(s) => match from_str(s) {
+                Some(x) => config.tolerance = x,
+                None => return Err(format!("bad tolerance {}", s)),
+            },
+            None => {}
+        }
+
+        config.fullscreen = matches.opt_present("fullscreen");
+        config.vsync = !matches.opt_present("no-vsync");
+        config.merge_faces = matches.opt_present("merge-faces");
+        config.curvature = matches.opt_present("curvature");
+        match matches.opt_str("world") {
+            Some(s) => config.world_dir = Path::new(s),
+            None => {}
+        }
+        config.headless = matches.opt_str("headless").map(|s| Path::new(s));
+        config.compare = matches.opt_str("compare").map(|s| Path::new(s));
+
+        match config.validate() {
+            Ok(()) => Ok(config),
+            Err(msg) => Err(msg),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ~str> {
+        match self.generator {
+            FlatTerrain(height) if height < -1000000 || height > 1000000 => {
+                return Err(format!("flat terrain height must be between -1000000 and 1000000, not {}",
+                                   height));
+            },
+            HeightmapTerrain(ref path) if !path.exists() => {
+                return Err(format!("heightmap {} not found", path.display()));
+            },
+            _ => {}
+        }
+        if self.visible_radius < 1 || self.visible_radius > 32 {
+            return Err(format!("render distance must be between 1 and 32 chunks, not {}",
+                               self.visible_radius));
+        }
+        if self.visible_height > 8 {
+            return Err(format!("render height must be at most 8 chunks, not {}",
+                               self.visible_height));
+        }
+        if self.window_width < 1 || self.window_height < 1 ||
+                self.window_width > 16384 || self.window_height > 16384 {
+            return Err(format!("bad window size {}x{}", self.window_width, self.window_height));
+        }
+        if self.shadow_size != 0 && (self.shadow_size < 256 || self.shadow_size > 8192) {
+            return Err(format!("shadow map size must be 0 or between 256 and 8192, not {}",
+                               self.shadow_size));
+        }
+        if self.fov < 10.0 || self.fov > 150.0 {
+            return Err(format!("field of view must be between 10 and 150 degrees, not {}", self.fov));
+        }
+        if self.mouse_sensitivity <= 0.0 {
+            return Err(format!("mouse sensitivity must be positive, not {}", self.mouse_sensitivity));
+        }
+        if self.planet_radius <= 0.0 {
+            return Err(format!("planet radius must be positive, not {}", self.planet_radius));
+        }
+        if self.time_of_day < 0.0 || self.time_of_day >= 24.0 {
+            return Err(format!("time of day must be from 0 to 24 hours, not {}", self.time_of_day));
+        }
+        if self.day_length <= 0.0 {
+            return Err(format!("day length must be positive, not {}", self.day_length));
+        }
+        if self.compare.is_some() && self.headless.is_none() {
+            return Err(~"--compare requires --headless");
+        }
+        if self.fullscreen && self.headless.is_some() {
+            return Err(~"--fullscreen cannot be used with --headless");
+        }
+        Ok(())
+    }
+
+    /// Level of detail for chunks `ring` chunks away from the camera
+    /// horizontally.
+    pub fn lod(&self, ring: uint) -> uint {
+        if self.lod_distance == 0 {
+            return 0;
+        }
+        let mut lod = 0;
+        let mut limit = self.lod_distance;
+        while ring >= limit && lod < MAX_LOD {
+            lod += 1;
+            limit *= 2;
+        }
+        lod
+    }
+
+    /// Distance to the far clipping plane, far enough to see the corners
+    /// of the visible area.
+    pub fn far_plane(&self) -> f32 {
+        (self.visible_radius as f32 * 1.5 * CHUNK_SIZE as f32).max(1000.0f32)
+    }
+
+    /// Number of chunks kept loaded: twice the visible set, so turning
+    /// around doesn't reload everything.
+    pub fn max_chunks(&self) -> uint {
+        (self.visible_radius*2)*(self.visible_radius*2)*(self.visible_height*2+1)*2
+    }
+}
+
+fn options() -> ~[OptGroup] {
+    ~[
+        optopt("s", "seed", "world seed", "N"),
+        optopt("", "generator", "terrain: default, flat[:HEIGHT] or heightmap:FILE.pgm", "NAME"),
+        optopt("r", "radius", "render distance in chunks (default 8)", "CHUNKS"),
+        optopt("", "height", "chunk layers drawn above and below the camera (default 2)", "CHUNKS"),
+        optopt("", "lod-distance", "rings of chunks drawn at full detail, 0 for all (default 4)", "CHUNKS"),
+        optopt("", "size", "window or image size (default 800x600)", "WIDTHxHEIGHT"),
+        optflag("f", "fullscreen", "fullscreen on the primary monitor"),
+        optflag("", "no-vsync", "don't wait for vertical sync"),
+        optflag("", "merge-faces", "mesh faces of different block types together"),
+        optopt("", "shadow-size", "sun shadow map resolution, 0 for no shadows (default 2048)", "PIXELS"),
+        optopt("p", "position", "starting camera position", "X,Y,Z"),
+        optopt("", "angles", "starting camera pitch and yaw in degrees", "PITCH,YAW"),
+        optopt("", "fov", "vertical field of view in degrees (default 75)", "DEGREES"),
+        optopt("", "sensitivity", "mouse look in degrees per pixel (default 0.18)", "DEGREES"),
+        optflag("", "curvature", "bend the world as if on a small planet"),
+        optopt("", "planet-radius", "planet radius in blocks for curvature (default 1274)", "BLOCKS"),
+        optopt("", "time", "starting time of day in hours (default 10)", "HOURS"),
+        optopt("", "day-length", "real seconds per day (default 1200)", "SECONDS"),
+        optopt("w", "world", "directory for saved chunks (default world)", "DIR"),
+        optopt("", "headless", "render one frame offscreen to a PNG and exit", "FILE"),
+        optopt("", "compare", "with --headless, fail unless the frame matches this PNG", "FILE"),
+        optopt("", "tolerance", "per-channel difference allowed by --compare (default 0)", "N"),
+        optflag("h", "help", "show this message"),
+    ]
+}
+
+fn parse_generator(s: &str) -> Option<Generator> {
+    if s == "default" {
+        Some(DefaultTerrain)
+    } else if s == "flat" {
+        Some(FlatTerrain(DEFAULT_FLAT_HEIGHT))
+    } else if s.starts_with("flat:") {
+        from_str(s.slice_from("flat:".len())).map(|height| FlatTerrain(height))
+    } else if s.starts_with("heightmap:") && s.len() > "heightmap:".len() {
+        Some(HeightmapTerrain(Path::new(s.slice_from("heightmap:".len()))))
+    } else {
+        None
+    }
+}
+
+fn parse_list(s: &str, sep: char) -> Option<~[f64]> {
+    let mut values = ~[];
+    for part in s.split(sep) {
+        match from_str(part.trim()) {
+            Some(x) => values.push(x),
+            None => return None,
+        }
+    }
+    Some(values)
+}
+
+#[test]
+fn test_config_defaults() {
+    let config = Config::from_args([]).unwrap();
+    assert_eq!(config.seed, 42);
+    assert_eq!(config.visible_radius, 8);
+    assert!(config.vsync);
+    assert!(config.headless.is_none());
+    assert!(config.generator == DefaultTerrain);
+    assert_eq!(config.shadow_size, 2048);
+    assert_eq!(config.max_chunks(), 16 * 16 * 5 * 2);
+}
+
+#[test]
+fn test_config_parse() {
+    let config = Config::from_args([~"--seed", ~"7", ~"-r", ~"4", ~"--size", ~"64x48",
+                                    ~"--position", ~"1,2.5,-3", ~"--no-vsync",
+                                    ~"--headless", ~"out.png"]).unwrap();
+    assert_eq!(config.seed, 7);
+    assert_eq!(config.visible_radius, 4);
+    assert_eq!((config.window_width, config.window_height), (64, 48));
+    assert_eq!(config.position, Vec3::new(1.0f32, 2.5f32, -3.0f32));
+    assert!(!config.vsync);
+    assert!(config.headless.is_some());
+
+    let config = Config::from_args([~"--generator", ~"flat:-7"]).unwrap();
+    assert!(config.generator == FlatTerrain(-7));
+    let config = Config::from_args([~"--generator", ~"flat"]).unwrap();
+    assert!(config.generator == FlatTerrain(DEFAULT_FLAT_HEIGHT));
+}
+
+#[test]
+fn test_config_errors() {
+    assert!(Config::from_args([~"--seed", ~"x"]).is_err());
+    assert!(Config::from_args([~"--radius", ~"0"]).is_err());
+    assert!(Config::from_args([~"--fov", ~"180"]).is_err());
+    assert!(Config::from_args([~"--size", ~"64"]).is_err());
+    assert!(Config::from_args([~"--shadow-size", ~"100"]).is_err());
+    assert!(Config::from_args([~"--time", ~"24"]).is_err());
+    assert!(Config::from_args([~"--planet-radius", ~"-5"]).is_err());
+    assert!(Config::from_args([~"--day-length", ~"0"]).is_err());
+    assert!(Config::from_args([~"--compare", ~"golden.png"]).is_err());
+    assert!(Config::from_args([~"--generator", ~"hills"]).is_err());
+    assert!(Config::from_args([~"--generator", ~"flat:x"]).is_err());
+    assert!(Config::from_args([~"--generator", ~"heightmap:"]).is_err());
+    assert!(Config::from_args([~"--generator", ~"heightmap:/nonexistent.pgm"]).is_err());
+    assert!(Config::from_args([~"--bogus"]).is_err());
+    assert!(Config::from_args([~"extra"]).is_err());
+}
+
+#[test]
+fn test_config_lod() {
+    let config = Config::from_args([~"--lod-distance", ~"4"]).unwrap();
+    assert_eq!(config.lod(0), 0);
+    assert_eq!(config.lod(3), 0);
+    assert_eq!(config.lod(4), 1);
+    assert_eq!(config.lod(8), 2);
+    assert_eq!(config.lod(16), 3);
+    assert_eq!(config.lod(1000), MAX_LOD);
+
+    let config = Config::from_args([~"--lod-distance", ~"0"]).unwrap();
+    assert_eq!(config.lod(1000), 0);
+}
@@ -0,0 +1,207 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Renders a single frame into an offscreen framebuffer and writes it as a
+//! PNG, for screenshots and golden-image tests.
+//!
+//! GLFW still needs a window to create a GL context, but it is never shown.
+//! On machines without a GPU this runs under Xvfb with Mesa's llvmpipe:
+//!
+//!   LIBGL_ALWAYS_SOFTWARE=1 xvfb-run ./cubeland --headless out.png
+
+extern mod extra;
+extern mod glfw;
+extern mod gl;
+extern mod cgmath;
+
+use std;
+use std::cast;
+use std::vec;
+
+use extra::tempfile::TempDir;
+
+use gl::types::*;
+
+use png;
+use camera::Camera;
+use clock::Clock;
+use config::Config;
+use {load_registry, init_graphics, new_chunk_loader, new_shadow_maps, render, visible_chunks,
+     check_gl};
+
+/// Renders one frame as described by `config` to `config.headless`. Sets a
+/// failing exit status if the frame does not match `config.compare`.
+pub fn run(config: &Config) {
+    let output = config.headless.clone().expect("no output path");
+
+    glfw::window_hint::visible(false);
+
+    let window = glfw::Window::create(config.window_width, config.window_height,
+        "Cubeland", glfw::Windowed)
+        .expect("Failed to create GLFW window.");
+
+    window.make_context_current();
+
+    gl::load_with(glfw::get_proc_address);
+
+    let framebuffer = match Framebuffer::new(config.window_width, config.window_height) {
+        Ok(x) => x,
+        Err(msg) => fail!("Error creating framebuffer: {}", msg),
+    };
+
+    let registry = load_registry();
+    let graphics_resources = init_graphics(registry.get());
+
+    /* Generate everything from the seed; saved worlds are neither read nor written */
+    let world_dir = TempDir::new("cubeland").expect("Failed to create temporary directory.");
+    let mut chunk_loader = new_chunk_loader(config, registry.clone(),
+                                            world_dir.path().clone());
+
+    let p = config.position;
+    chunk_loader.load_all(visible_chunks(config, p.x as i64, p.y as i64, p.z as i64));
+
+    let shadow_maps = new_shadow_maps(config);
+
+    let mut camera = Camera::new(p, config.pitch, config.yaw, config.fov,
+                                 config.mouse_sensitivity, config.far_plane());
+    if config.curvature {
+        camera.planet_radius = config.planet_radius;
+    }
+    /* Time stands still at --time, so frames are reproducible */
+    let clock = Clock::new(config.time_of_day, config.day_length);
+    render(config, &graphics_resources, &mut chunk_loader, &camera, &clock.lighting(),
+           shadow_maps.as_ref(), config.window_width, config.window_height);
+
+    gl::Finish();
+    check_gl("headless render");
+
+    let image = framebuffer.read();
+    match png::save(&output, &image) {
+        Ok(()) => println!("wrote {}", output.display()),
+        Err(msg) => fail!("Error writing screenshot: {}", msg),
+    }
+
+    match config.compare {
+        Some(ref path) => {
+            let golden = match png::load(path) {
+                Ok(x) => x,
+                Err(msg) => fail!("Error loading golden image: {}", msg),
+            };
+
+            if (golden.width, golden.height) != (image.width, image.height) {
+                println!("{} is {}x{}, expected {}x{}", path.display(),
+                         golden.width, golden.height, image.width, image.height);
+                std::os::set_exit_status(1);
+                return;
+            }
+
+            let differences = image.count_differences(&golden, config.tolerance);
+            if differences > 0 {
+                println!("{} pixels differ from {}", differences, path.display());
+                std::os::set_exit_status(1);
+            } else {
+                println!("matches {}", path.display());
+            }
+        },
+        None => {}
+    }
+}
+
+/// A color and depth render target the size of the output image.
+struct Framebuffer {
+    framebuffer: GLuint,
+    color_buffer: GLuint,
+    depth_buffer: GLuint,
+    width: u32,
+    height: u32,
+}
+
+impl Framebuffer {
+    /// Creates the framebuffer and leaves it bound for drawing.
+    fn new(width: u32, height: u32) -> Result<Framebuffer, ~str> {
+        let mut framebuffer = 0;
+        let mut color_buffer = 0;
+        let mut depth_buffer = 0;
+
+        unsafe {
+            gl::GenFramebuffers(1, &mut framebuffer);
+            gl::GenRenderbuffers(1, &mut color_buffer);
+            gl::GenRenderbuffers(1, &mut depth_buffer);
+        }
+
+        gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+
+        gl::BindRenderbuffer(gl::RENDERBUFFER, color_buffer);
+        gl::RenderbufferStorage(gl::RENDERBUFFER, gl::RGBA8, width as GLsizei, height as GLsizei);
+        gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0,
+                                    gl::RENDERBUFFER, color_buffer);
+
+        gl::BindRenderbuffer(gl::RENDERBUFFER, depth_buffer);
+        gl::RenderbufferStorage(gl::RENDERBUFFER, gl::DEPTH_COMPONENT24, width as GLsizei, height as GLsizei);
+        gl::FramebufferRenderbuffer(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT,
+                                    gl::RENDERBUFFER, depth_buffer);
+
+        let result = Framebuffer {
+            framebuffer: framebuffer,
+            color_buffer: color_buffer,
+            depth_buffer: depth_buffer,
+            width: width,
+            height: height,
+        };
+
+        let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            return Err(format!("incomplete framebuffer, status {:x}", status));
+        }
+
+        Ok(result)
+    }
+
+    fn read(&self) -> png::Image {
+        let stride = self.width as uint * 4;
+        let height = self.height as uint;
+        let mut pixels = vec::from_elem(stride * height, 0u8);
+
+        gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+        unsafe {
+            gl::ReadPixels(0, 0, self.width as GLsizei, self.height as GLsizei,
+                           gl::RGBA, gl::UNSIGNED_BYTE,
+                           cast::transmute(&mut pixels[0]));
+        }
+
+        /* GL returns the bottom row first */
+        let mut flipped = vec::with_capacity(stride * height);
+        for y in range(0, height).invert() {
+            flipped.push_all(pixels.slice(y * stride, (y + 1) * stride));
+        }
+
+        /* Blending leaves alpha below one where water was drawn */
+        for i in range(0, self.width as uint * height) {
+            flipped[i * 4 + 3] = 255;
+        }
+
+        png::Image { width: self.width as uint, height: height, pixels: flipped }
+    }
+}
+
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.framebuffer);
+            gl::DeleteRenderbuffers(1, &self.color_buffer);
+            gl::DeleteRenderbuffers(1, &self.depth_buffer);
+        }
+    }
+}
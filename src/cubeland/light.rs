@@ -0,0 +1,620 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Light levels from 0 to 15 on two channels: skylight, which falls
+//! straight down without fading and spreads sideways one level per block,
+//! and block light, which spreads from emissive blocks such as torches.
+//! Opaque blocks stop both.
+//!
+//! A worker lights each new chunk on its own, as if it were open to the sky
+//! and had nothing around it. The chunk loader then fixes up the borders
+//! with the loaded neighbours, and updates light incrementally when blocks
+//! are edited. Both go through the World trait, so light floods across
+//! chunk borders.
+//!
+//! To keep that work small, it only changes light within MARGIN blocks of
+//! the chunk where it started. Where it would reach further, which only
+//! skylight falling at full strength can, the rest is returned as Jobs to
+//! be run later, each confined around the chunk it reached.
+//!
+//! A block with no loaded chunk above it counts as open sky.
+
+use std::vec;
+
+use blocks::{BlockId, BlockRegistry};
+use chunk::{Map, chunk_coords};
+use CHUNK_SIZE;
+#[cfg(test)]
+use std::util;
+#[cfg(test)]
+use chunk::Block;
+
+pub static MAX_LIGHT : u8 = 15;
+
+#[deriving(Eq, Clone)]
+pub enum Channel {
+    SkyLight,
+    BlockLight,
+}
+
+static CHANNELS : [Channel, ..2] = [SkyLight, BlockLight];
+
+/// Distance light can spread sideways, so work started in a chunk rarely
+/// needs to change anything further away.
+static MARGIN : i64 = MAX_LIGHT as i64;
+
+static DIRECTIONS : [(i64, i64, i64), ..6] = [
+    (0, 0, 1), (0, 0, -1), (1, 0, 0), (-1, 0, 0), (0, 1, 0), (0, -1, 0),
+];
+
+/// Light levels of a chunk, indexed like `Map::blocks`. Each byte holds
+/// skylight in the high nibble and block light in the low one.
+pub struct LightMap {
+    levels: ~[u8],
+}
+
+impl LightMap {
+    pub fn new() -> ~LightMap {
+        ~LightMap { levels: vec::from_elem(CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE, 0u8) }
+    }
+
+    pub fn get(&self, channel: Channel, x: uint, y: uint, z: uint) -> u8 {
+        let packed = self.packed(x, y, z);
+        match channel {
+            SkyLight => packed >> 4,
+            BlockLight => packed & 0xf,
+        }
+    }
+
+    pub fn set(&mut self, channel: Channel, x: uint, y: uint, z: uint, level: u8) {
+        let i = LightMap::index(x, y, z);
+        self.levels[i] = match channel {
+            SkyLight => (self.levels[i] & 0xf) | (level << 4),
+            BlockLight => (self.levels[i] & 0xf0) | level,
+        };
+    }
+
+    /// Both channels at once, as stored.
+    pub fn packed(&self, x: uint, y: uint, z: uint) -> u8 {
+        self.levels[LightMap::index(x, y, z)]
+    }
+
+    fn index(x: uint, y: uint, z: uint) -> uint {
+        x*CHUNK_SIZE*CHUNK_SIZE + y*CHUNK_SIZE + z
+    }
+}
+
+impl Clone for LightMap {
+    fn clone(&self) -> LightMap {
+        LightMap { levels: self.levels.clone() }
+    }
+}
+
+/// Light work that reached beyond the margin of the chunk it started in,
+/// continued by `run_job` within the margin of the chunk it reached.
+pub struct Job {
+    /// Origin of the chunk the work is confined around
+    chunk: (i64, i64, i64),
+    channel: Channel,
+    /// Darkened positions to darken further from, with their old levels
+    darkened: ~[((i64, i64, i64), u8)],
+    /// Positions to spread light from
+    lit: ~[(i64, i64, i64)],
+}
+
+/// Blocks and light levels addressed by world coordinates.
+pub trait World {
+    /// The block at the given position, or None if it is not loaded.
+    fn block(&self, x: i64, y: i64, z: i64) -> Option<BlockId>;
+
+    /// The light level at the given position, or None if it is not loaded.
+    fn light(&self, channel: Channel, x: i64, y: i64, z: i64) -> Option<u8>;
+
+    /// Changes a light level. Ignored if the position is not loaded.
+    fn set_light(&mut self, channel: Channel, x: i64, y: i64, z: i64, level: u8);
+}
+
+/// Lights a chunk on its own, as if it were open to the sky.
+pub fn light_chunk(map: &Map, registry: &BlockRegistry) -> ~LightMap {
+    let mut light = LightMap::new();
+
+    {
+        let mut world = ChunkWorld { map: &*map, light: &mut *light };
+        let size = CHUNK_SIZE as i64;
+        /* Nothing outside the chunk is loaded, so no jobs are left over */
+        let mut jobs = ~[];
+
+        for &channel in CHANNELS.iter() {
+            let mut queue = ~[];
+            for x in range(0, size) {
+                for y in range(0, size) {
+                    for z in range(0, size) {
+                        let level = source(&world, registry, channel, x, y, z);
+                        if level > 0 {
+                            world.set_light(channel, x, y, z, level);
+                            queue.push((x, y, z));
+                        }
+                    }
+                }
+            }
+            spread(&mut world, registry, channel, (0, 0, 0), queue, &mut jobs);
+        }
+    }
+
+    light
+}
+
+/// Joins the light of a newly loaded chunk, whose origin is given, with its
+/// loaded neighbours. Light flows across every shared border, and blocks
+/// that were lit as open sky before the chunk above them arrived are
+/// darkened. Returns the work left for `run_job`.
+pub fn chunk_arrived<W: World>(world: &mut W, registry: &BlockRegistry,
+                               cx: i64, cy: i64, cz: i64) -> ~[Job] {
+    let size = CHUNK_SIZE as i64;
+    let chunk = (cx, cy, cz);
+    let mut jobs = ~[];
+
+    /* The top layer of this chunk, and the top layer of the chunk below */
+    let mut darkened = ~[];
+    for u in range(0, size) {
+        for v in range(0, size) {
+            for &y in [cy + size - 1, cy - 1].iter() {
+                let (x, z) = (cx + u, cz + v);
+                match (world.light(SkyLight, x, y, z), world.light(SkyLight, x, y + 1, z)) {
+                    (Some(level), Some(above)) if level == MAX_LIGHT && above < MAX_LIGHT => {
+                        world.set_light(SkyLight, x, y, z, 0);
+                        darkened.push(((x, y, z), level));
+                    },
+                    _ => {}
+                }
+            }
+        }
+    }
+    unspread(world, registry, SkyLight, chunk, darkened, &mut jobs);
+
+    /* Every block on either side of the chunk's surface */
+    let mut queue = ~[];
+    for x in range(cx - 1, cx + size + 1) {
+        for y in range(cy - 1, cy + size + 1) {
+            for z in range(cz - 1, cz + size + 1) {
+                let on_x = x == cx - 1 || x == cx || x == cx + size - 1 || x == cx + size;
+                let on_y = y == cy - 1 || y == cy || y == cy + size - 1 || y == cy + size;
+                let on_z = z == cz - 1 || z == cz || z == cz + size - 1 || z == cz + size;
+                if on_x || on_y || on_z {
+                    queue.push((x, y, z));
+                }
+            }
+        }
+    }
+
+    for &channel in CHANNELS.iter() {
+        spread(world, registry, channel, chunk, queue.clone(), &mut jobs);
+    }
+
+    jobs
+}
+
+/// Updates light after the block at the given position changed. Light that
+/// reached other blocks through it is removed, then whatever still shines
+/// in from around it, or from the block itself, spreads again. Returns the
+/// work left for `run_job`.
+pub fn block_changed<W: World>(world: &mut W, registry: &BlockRegistry,
+                               x: i64, y: i64, z: i64) -> ~[Job] {
+    let chunk = chunk_coords(x, y, z);
+    let mut jobs = ~[];
+
+    for &channel in CHANNELS.iter() {
+        let old = world.light(channel, x, y, z).unwrap_or(0);
+        world.set_light(channel, x, y, z, 0);
+        unspread(world, registry, channel, chunk, ~[((x, y, z), old)], &mut jobs);
+
+        let mut queue = ~[(x, y, z)];
+        for &(dx, dy, dz) in DIRECTIONS.iter() {
+            queue.push((x + dx, y + dy, z + dz));
+        }
+        spread(world, registry, channel, chunk, queue, &mut jobs);
+    }
+
+    jobs
+}
+
+/// Continues work left over by chunk_arrived, block_changed or an earlier
+/// job. Returns the work left after this one.
+pub fn run_job<W: World>(world: &mut W, registry: &BlockRegistry, job: Job) -> ~[Job] {
+    let Job { chunk, channel, darkened, lit } = job;
+    let mut jobs = ~[];
+    unspread(world, registry, channel, chunk, darkened, &mut jobs);
+    spread(world, registry, channel, chunk, lit, &mut jobs);
+    jobs
+}
+
+/// Floods light outward from each position in `queue`, within the margin
+/// of `chunk`. Light that would leave it is added to `jobs`.
+pub fn spread<W: World>(world: &mut W, registry: &BlockRegistry, channel: Channel,
+                        chunk: (i64, i64, i64), mut queue: ~[(i64, i64, i64)],
+                        jobs: &mut ~[Job]) {
+    let mut i = 0;
+    while i < queue.len() {
+        let (x, y, z) = queue[i];
+        i += 1;
+
+        let level = match world.light(channel, x, y, z) {
+            Some(level) if level > 1 => level,
+            _ => continue,
+        };
+
+        for &(dx, dy, dz) in DIRECTIONS.iter() {
+            let (nx, ny, nz) = (x + dx, y + dy, z + dz);
+            if !passes_light(&*world, registry, nx, ny, nz) {
+                continue;
+            }
+
+            let new_level = falloff(channel, level, dy);
+            match world.light(channel, nx, ny, nz) {
+                Some(old) if old < new_level => {
+                    if near(chunk, nx, ny, nz) {
+                        world.set_light(channel, nx, ny, nz, new_level);
+                        queue.push((nx, ny, nz));
+                    } else {
+                        job_for(jobs, channel, nx, ny, nz).lit.push((x, y, z));
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Removes light that came from the positions in `queue`, which have
+/// already been darkened and are paired with their old levels. Light
+/// sources in the darkened region are restored, and light from outside it
+/// spreads back in. Only light within the margin of `chunk` is changed;
+/// darkening that would leave it is added to `jobs`.
+pub fn unspread<W: World>(world: &mut W, registry: &BlockRegistry, channel: Channel,
+                          chunk: (i64, i64, i64), mut queue: ~[((i64, i64, i64), u8)],
+                          jobs: &mut ~[Job]) {
+    let mut darkened : ~[(i64, i64, i64)] = queue.iter().map(|&(p, _)| p).collect();
+    let mut relight = ~[];
+
+    let mut i = 0;
+    while i < queue.len() {
+        let ((x, y, z), level) = queue[i];
+        i += 1;
+
+        if level == 0 {
+            continue;
+        }
+
+        for &(dx, dy, dz) in DIRECTIONS.iter() {
+            let (nx, ny, nz) = (x + dx, y + dy, z + dz);
+            let neighbour_level = match world.light(channel, nx, ny, nz) {
+                Some(l) if l > 0 => l,
+                _ => continue,
+            };
+
+            /* Anything dimmer may have been lit from here */
+            if neighbour_level < level ||
+                    (neighbour_level == MAX_LIGHT && falloff(channel, level, dy) == MAX_LIGHT) {
+                if near(chunk, nx, ny, nz) {
+                    world.set_light(channel, nx, ny, nz, 0);
+                    queue.push(((nx, ny, nz), neighbour_level));
+                    darkened.push((nx, ny, nz));
+                } else {
+                    job_for(jobs, channel, nx, ny, nz).darkened.push(((x, y, z), level));
+                }
+            } else {
+                relight.push((nx, ny, nz));
+            }
+        }
+    }
+
+    /* A job's darkened positions may have been lit again since */
+    for &(x, y, z) in darkened.iter() {
+        let level = source(&*world, registry, channel, x, y, z);
+        if level > world.light(channel, x, y, z).unwrap_or(0) {
+            world.set_light(channel, x, y, z, level);
+            relight.push((x, y, z));
+        }
+    }
+
+    spread(world, registry, channel, chunk, relight, jobs);
+}
+
+/// Whether the given position is within MARGIN blocks of the chunk whose
+/// origin is `chunk`.
+fn near(chunk: (i64, i64, i64), x: i64, y: i64, z: i64) -> bool {
+    let (cx, cy, cz) = chunk;
+    let size = CHUNK_SIZE as i64;
+    x >= cx - MARGIN && x < cx + size + MARGIN &&
+        y >= cy - MARGIN && y < cy + size + MARGIN &&
+        z >= cz - MARGIN && z < cz + size + MARGIN
+}
+
+/// The job in `jobs` for light in the chunk containing the given position,
+/// added if there is none yet.
+fn job_for<'a>(jobs: &'a mut ~[Job], channel: Channel, x: i64, y: i64, z: i64) -> &'a mut Job {
+    let chunk = chunk_coords(x, y, z);
+    let i = match jobs.iter().position(|job| job.chunk == chunk && job.channel == channel) {
+        Some(i) => i,
+        None => {
+            jobs.push(Job { chunk: chunk, channel: channel, darkened: ~[], lit: ~[] });
+            jobs.len() - 1
+        }
+    };
+    &mut jobs[i]
+}
+
+/// Light level of the block at the given position, moving one block in the
+/// direction `dy`. Skylight at full strength keeps it going down.
+fn falloff(channel: Channel, level: u8, dy: i64) -> u8 {
+    if channel == SkyLight && dy == -1 && level == MAX_LIGHT {
+        MAX_LIGHT
+    } else {
+        level - 1
+    }
+}
+
+fn passes_light<W: World>(world: &W, registry: &BlockRegistry, x: i64, y: i64, z: i64) -> bool {
+    match world.block(x, y, z) {
+        Some(id) => !registry.is_opaque(id),
+        None => false,
+    }
+}
+
+/// Light the block at the given position gives off by itself.
+fn source<W: World>(world: &W, registry: &BlockRegistry, channel: Channel,
+                    x: i64, y: i64, z: i64) -> u8 {
+    let id = match world.block(x, y, z) {
+        Some(id) => id,
+        None => return 0,
+    };
+
+    match channel {
+        BlockLight => registry.get(id).light,
+        SkyLight => {
+            if !registry.is_opaque(id) && world.block(x, y + 1, z).is_none() {
+                MAX_LIGHT
+            } else {
+                0
+            }
+        },
+    }
+}
+
+/// A single chunk with nothing around it, in chunk coordinates.
+struct ChunkWorld<'a> {
+    map: &'a Map,
+    light: &'a mut LightMap,
+}
+
+impl<'a> ChunkWorld<'a> {
+    fn contains(&self, x: i64, y: i64, z: i64) -> bool {
+        let size = CHUNK_SIZE as i64;
+        x >= 0 && x < size && y >= 0 && y < size && z >= 0 && z < size
+    }
+}
+
+impl<'a> World for ChunkWorld<'a> {
+    fn block(&self, x: i64, y: i64, z: i64) -> Option<BlockId> {
+        if self.contains(x, y, z) {
+            Some(self.map.blocks[x][y][z].blocktype)
+        } else {
+            None
+        }
+    }
+
+    fn light(&self, channel: Channel, x: i64, y: i64, z: i64) -> Option<u8> {
+        if self.contains(x, y, z) {
+            Some(self.light.get(channel, x as uint, y as uint, z as uint))
+        } else {
+            None
+        }
+    }
+
+    fn set_light(&mut self, channel: Channel, x: i64, y: i64, z: i64, level: u8) {
+        if self.contains(x, y, z) {
+            self.light.set(channel, x as uint, y as uint, z as uint, level);
+        }
+    }
+}
+
+#[cfg(test)]
+static STONE : u8 = 1;
+#[cfg(test)]
+static TORCH : u8 = 2;
+
+#[cfg(test)]
+fn light_registry() -> BlockRegistry {
+    BlockRegistry::from_json(
+        "[{\"id\": 0, \"name\": \"air\", \"opaque\": false, \"color\": [0, 0, 0, 0]},
+          {\"id\": 1, \"name\": \"stone\", \"color\": [1, 1, 1, 1]},
+          {\"id\": 2, \"name\": \"torch\", \"light\": 14, \"color\": [1, 1, 0, 1]}]").unwrap()
+}
+
+/// A stack of chunks at x and z from 0 to CHUNK_SIZE, the first at y = 0.
+#[cfg(test)]
+struct ColumnWorld {
+    maps: ~[~Map],
+    lights: ~[~LightMap],
+    /// Every position whose light was set
+    changed: ~[(i64, i64, i64)],
+}
+
+#[cfg(test)]
+impl ColumnWorld {
+    /// The chunk holding a position, and the position within it.
+    fn index(&self, x: i64, y: i64, z: i64) -> Option<(uint, uint, uint, uint)> {
+        let size = CHUNK_SIZE as i64;
+        if x < 0 || x >= size || z < 0 || z >= size || y < 0 || y >= size * self.maps.len() as i64 {
+            None
+        } else {
+            Some(((y / size) as uint, x as uint, (y % size) as uint, z as uint))
+        }
+    }
+}
+
+#[cfg(test)]
+impl World for ColumnWorld {
+    fn block(&self, x: i64, y: i64, z: i64) -> Option<BlockId> {
+        match self.index(x, y, z) {
+            Some((i, x, y, z)) => Some(self.maps[i].blocks[x][y][z].blocktype),
+            None => None,
+        }
+    }
+
+    fn light(&self, channel: Channel, x: i64, y: i64, z: i64) -> Option<u8> {
+        match self.index(x, y, z) {
+            Some((i, x, y, z)) => Some(self.lights[i].get(channel, x, y, z)),
+            None => None,
+        }
+    }
+
+    fn set_light(&mut self, channel: Channel, x: i64, y: i64, z: i64, level: u8) {
+        match self.index(x, y, z) {
+            Some((i, lx, ly, lz)) => {
+                self.lights[i].set(channel, lx, ly, lz, level);
+                self.changed.push((x, y, z));
+            },
+            None => {}
+        }
+    }
+}
+
+#[test]
+fn test_light_sky() {
+    let registry = light_registry();
+    let mut map = Map::new();
+    /* A roof over the middle of the chunk */
+    for x in range(8, 24) {
+        for z in range(8, 24) {
+            map.blocks[x][20][z] = Block { blocktype: STONE };
+        }
+    }
+
+    let light = light_chunk(map, &registry);
+    assert_eq!(light.get(SkyLight, 0, 0, 0), MAX_LIGHT);
+    assert_eq!(light.get(SkyLight, 16, 21, 16), MAX_LIGHT);
+    assert_eq!(light.get(SkyLight, 16, 20, 16), 0);
+    /* Under the roof, light only arrives sideways */
+    assert_eq!(light.get(SkyLight, 8, 19, 16), MAX_LIGHT - 1);
+    assert_eq!(light.get(SkyLight, 16, 19, 16), MAX_LIGHT - 8);
+    assert_eq!(light.get(BlockLight, 16, 19, 16), 0);
+}
+
+#[test]
+fn test_light_torch() {
+    let registry = light_registry();
+    let mut map = Map::new();
+    map.blocks[16][16][16] = Block { blocktype: TORCH };
+
+    let light = light_chunk(map, &registry);
+    assert_eq!(light.get(BlockLight, 16, 16, 16), 14);
+    assert_eq!(light.get(BlockLight, 17, 16, 16), 13);
+    assert_eq!(light.get(BlockLight, 18, 17, 16), 11);
+    assert_eq!(light.get(BlockLight, 0, 0, 0), 0);
+    /* The torch casts a skylight shadow */
+    assert_eq!(light.get(SkyLight, 16, 15, 16), MAX_LIGHT - 1);
+    assert_eq!(light.get(SkyLight, 16, 17, 16), MAX_LIGHT);
+}
+
+#[test]
+fn test_light_edits() {
+    let registry = light_registry();
+    let mut map = Map::new();
+    let mut light = light_chunk(map, &registry);
+
+    /* Roofing over a column darkens it */
+    map.blocks[16][20][16] = Block { blocktype: STONE };
+    {
+        let mut world = ChunkWorld { map: &*map, light: &mut *light };
+        block_changed(&mut world, &registry, 16, 20, 16);
+    }
+    assert_eq!(light.get(SkyLight, 16, 20, 16), 0);
+    assert_eq!(light.get(SkyLight, 16, 19, 16), MAX_LIGHT - 1);
+    assert_eq!(light.get(SkyLight, 16, 0, 16), MAX_LIGHT - 1);
+    assert_eq!(light.get(SkyLight, 16, 21, 16), MAX_LIGHT);
+
+    /* A torch lights its surroundings, and takes its light away again */
+    map.blocks[4][4][4] = Block { blocktype: TORCH };
+    {
+        let mut world = ChunkWorld { map: &*map, light: &mut *light };
+        block_changed(&mut world, &registry, 4, 4, 4);
+    }
+    assert_eq!(light.get(BlockLight, 4, 4, 4), 14);
+    assert_eq!(light.get(BlockLight, 4, 5, 4), 13);
+
+    map.blocks[4][4][4] = Block { blocktype: 0 };
+    {
+        let mut world = ChunkWorld { map: &*map, light: &mut *light };
+        block_changed(&mut world, &registry, 4, 4, 4);
+    }
+    assert_eq!(light.get(BlockLight, 4, 4, 4), 0);
+    assert_eq!(light.get(BlockLight, 4, 5, 4), 0);
+    assert_eq!(light.get(SkyLight, 4, 4, 4), MAX_LIGHT);
+
+    /* Removing the roof lets the sky back in */
+    map.blocks[16][20][16] = Block { blocktype: 0 };
+    {
+        let mut world = ChunkWorld { map: &*map, light: &mut *light };
+        block_changed(&mut world, &registry, 16, 20, 16);
+    }
+    assert_eq!(light.get(SkyLight, 16, 0, 16), MAX_LIGHT);
+}
+
+#[test]
+fn test_light_arrival_bounded() {
+    let registry = light_registry();
+    let size = CHUNK_SIZE as i64;
+
+    /* Three open chunks, lit by the sky */
+    let mut world = ColumnWorld { maps: ~[], lights: ~[], changed: ~[] };
+    for _ in range(0, 3) {
+        let map = Map::new();
+        world.lights.push(light_chunk(map, &registry));
+        world.maps.push(map);
+    }
+
+    /* A roofed chunk arrives on top */
+    let mut roof = Map::new();
+    for x in range(0, CHUNK_SIZE) {
+        for z in range(0, CHUNK_SIZE) {
+            roof.blocks[x][CHUNK_SIZE - 1][z] = Block { blocktype: STONE };
+        }
+    }
+    world.lights.push(light_chunk(roof, &registry));
+    world.maps.push(roof);
+
+    /* Only light near the new chunk changes at once */
+    let mut jobs = chunk_arrived(&mut world, &registry, 0, 3 * size, 0);
+    assert!(world.changed.len() > 0);
+    assert!(world.changed.iter().all(|&(x, y, z)| near((0, 3 * size, 0), x, y, z)));
+    assert!(!jobs.is_empty());
+    assert_eq!(world.light(SkyLight, 5, 0, 5), Some(MAX_LIGHT));
+
+    /* Each job darkens the column a chunk further down */
+    let mut rounds = 0;
+    while !jobs.is_empty() {
+        let pending = util::replace(&mut jobs, ~[]);
+        for job in pending.move_iter() {
+            let chunk = job.chunk;
+            world.changed = ~[];
+            jobs.push_all_move(run_job(&mut world, &registry, job));
+            assert!(world.changed.iter().all(|&(x, y, z)| near(chunk, x, y, z)));
+        }
+        rounds += 1;
+        assert!(rounds <= 3);
+    }
+    assert_eq!(world.light(SkyLight, 5, 0, 5), Some(0));
+    assert_eq!(world.light(SkyLight, 5, 3 * size - 1, 5), Some(0));
+}
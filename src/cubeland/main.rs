@@ -26,17 +26,15 @@ use std::str;
 use std::vec;
 use std::libc;
 use std::io::Timer;
+use std::io::File;
 
 use gl::types::*;
 
 use cgmath::matrix::Matrix;
-use cgmath::matrix::Mat3;
 use cgmath::matrix::Mat4;
-use cgmath::matrix::ToMat4;
 use cgmath::vector::Vector;
 use cgmath::vector::Vec3;
 use cgmath::vector::Vec4;
-use cgmath::angle::{rad, deg};
 use cgmath::ptr::Ptr;
 
 use spiral::Spiral;
@@ -49,23 +47,66 @@ mod chunk;
 mod ratelimiter;
 mod texture;
 mod spiral;
-
-pub static VISIBLE_RADIUS: uint = 8;
+mod raycast;
+mod region;
+mod worker;
+mod mesher;
+mod terrain;
+mod biome;
+mod blocks;
+mod player;
+mod png;
+mod headless;
+mod config;
+mod bindings;
+mod camera;
+mod light;
+mod shadow;
+mod clock;
+mod sky;
+mod shader;
+
+/// Fixed at compile time: it sets the layout of chunk::Map and of the region
+/// files. Runtime settings are in config::Config.
 pub static CHUNK_SIZE: uint = 32;
-pub static WORLD_SEED: u32 = 42;
-
-static FRAME_TIME_TARGET_MS : u64 = 16;
-static CAMERA_SPEED : f32 = 30.0f32;
+pub static BLOCKS_PATH: &'static str = "blocks.json";
+pub static BINDINGS_PATH: &'static str = "bindings.json";
+
+/// Fog density at the default view distance of 8 chunks
+static FOG_DENSITY : f32 = 0.003;
+static REACH_DISTANCE : f32 = 8.0f32;
+static UPLOADS_PER_FRAME : uint = 4;
+/// Height difference between black and white in a --generator heightmap
+static HEIGHTMAP_SCALE : f64 = 64.0;
+/// How much faster time passes while fast forwarding
+static FAST_FORWARD_SPEED : f64 = 60.0;
+/// Vertex attributes disabled before switching programs
+static MAX_ATTRIBUTES : GLuint = 16;
 
 struct GraphicsResources {
     program: GLuint,
     vertex_shader: GLuint,
     fragment_shader: GLuint,
     texture: GLuint,
+    sky: sky::Sky,
     uniform_view: GLint,
     uniform_projection: GLint,
     uniform_camera_position: GLint,
     uniform_texture: GLint,
+    uniform_block_color: GLint,
+    uniform_block_texture: GLint,
+    uniform_fog_density: GLint,
+    uniform_chunk_blocks: GLint,
+    uniform_chunk_origin: GLint,
+    uniform_light_direction: GLint,
+    uniform_planet_radius: GLint,
+    uniform_sun_color: GLint,
+    uniform_ambient_color: GLint,
+    uniform_fog_color: GLint,
+    uniform_shadows_enabled: GLint,
+    uniform_shadow_maps: GLint,
+    uniform_shadow_transform: GLint,
+    uniform_shadow_splits: GLint,
 }
 
 #[start]
@@ -78,65 +119,101 @@ fn start(argc: int, argv: **u8) -> int {
 fn main() {
    glfw::set_error_callback(~ErrorContext);
 
+    let config = match config::Config::from_args(std::os::args().tail()) {
+        Ok(x) => x,
+        Err(msg) => {
+            println!("{}", msg);
+            std::os::set_exit_status(2);
+            return;
+        }
+    };
+
     do glfw::start {
-        let mut window_width = 800;
-        let mut window_height = 600;
+        if config.headless.is_some() {
+            headless::run(&config);
+            return;
+        }
+
+        let mut window_width = config.window_width;
+        let mut window_height = config.window_height;
 
         glfw::window_hint::samples(8);
 
+        let window_mode = if config.fullscreen {
+            match glfw::Monitor::get_primary() {
+                Ok(monitor) => glfw::FullScreen(monitor),
+                Err(_) => fail!("No monitor found for fullscreen mode."),
+            }
+        } else {
+            glfw::Windowed
+        };
+
         let window = glfw::Window::create(window_width, window_height,
-            "Cubeland", glfw::Windowed)
+            "Cubeland", window_mode)
             .expect("Failed to create GLFW window.");
 
+        /* The framebuffer can differ from the requested size, e.g. in fullscreen */
+        let (fb_width, fb_height) = window.get_framebuffer_size();
+        window_width = fb_width as u32;
+        window_height = fb_height as u32;
+
         window.set_cursor_mode(glfw::CursorDisabled);
         window.make_context_current();
 
         gl::load_with(glfw::get_proc_address);
 
-        gl::Enable(gl::TEXTURE_2D);
-        gl::Enable(gl::DEPTH_TEST);
-        gl::Enable(gl::CULL_FACE);
+        glfw::set_swap_interval(if config.vsync { 1 } else { 0 });
+
+        let registry = load_registry();
 
-        glfw::set_swap_interval(1);
+        let mut graphics_resources = init_graphics(registry.get());
 
-        let mut graphics_resources = match load_graphics_resources() {
+        let bindings = match bindings::Bindings::load(&std::path::Path::new(BINDINGS_PATH)) {
             Ok(x) => x,
-            Err(msg) => fail!("Error loading graphics resources: {}", msg),
+            Err(msg) => fail!("Error loading key bindings: {}", msg),
         };
 
-        check_gl("after loading graphics resources");
-
-        gl::UseProgram(graphics_resources.program);
+        let mut chunk_loader = new_chunk_loader(&config, registry.clone(),
+                                                config.world_dir.clone());
 
-        gl::ActiveTexture(gl::TEXTURE0);
-        gl::Uniform1i(graphics_resources.uniform_texture, 0);
-
-        gl::BindTexture(gl::TEXTURE_2D, graphics_resources.texture);
-
-        let mut chunk_loader = chunk::ChunkLoader::new(WORLD_SEED);
+        let mut shadow_maps = new_shadow_maps(&config);
+        let mut shader_watcher = shader::Watcher::new(std::path::Path::new("."));
+        let mut shadows_enabled = shadow_maps.is_some();
 
         let (key_port, key_chan) = std::comm::Chan::new();
         window.set_key_callback(~KeyContext { chan: key_chan });
 
+        let (mouse_button_port, mouse_button_chan) = std::comm::Chan::new();
+        window.set_mouse_button_callback(~MouseButtonContext { chan: mouse_button_chan });
+
         let (fb_size_port, fb_size_chan): (Port<(u32,u32)>, Chan<(u32,u32)>) = std::comm::Chan::new();
         window.set_framebuffer_size_callback(~FramebufferSizeContext { chan: fb_size_chan });
 
         let mut fps_display_limiter = ratelimiter::RateLimiter::new(1000*1000*1000);
         let mut fps_frame_counter = 0;
 
-        let mut camera_position = Vec3::<f32>::new(0.0f32, 30.0f32, 40.0f32);
+        let mut camera = camera::Camera::new(config.position,
+                                             config.pitch, config.yaw,
+                                             config.fov, config.mouse_sensitivity,
+                                             config.far_plane());
+        if config.curvature {
+            camera.planet_radius = config.planet_radius;
+        }
+        let mut player = player::Player::new(camera.position);
+        let mut clock = clock::Clock::new(config.time_of_day, config.day_length);
 
         //let mut timer = Timer::new().unwrap();
 
-        let mut needed_chunks : ~[(i64, i64)] = ~[];
-        let mut load_limiter = ratelimiter::RateLimiter::new(1000*1000*10);
-
         let mut last_tick = extra::time::precise_time_ns();
 
         let mut grabbed = true;
 
-        let mut camera_angle_x = 0.0;
-        let mut camera_angle_y = 0.0;
+        let mut last_cursor_pos = window.get_cursor_pos();
+
+        let mut selected_blocktype = match registry.get().find("stone") {
+            Some(id) => id,
+            None => (registry.get().len() - 1) as blocks::BlockId,
+        };
 
         while !window.should_close() {
             let frame_start_time = extra::time::precise_time_ns();
@@ -153,9 +230,46 @@ fn main() {
                 }
             }
 
+            let modifiers = bindings::held_modifiers(|input| input_down(&window, input));
+            let mut actions = ~[];
+
+            if shader_watcher.changed() {
+                println!("shaders changed, reloading");
+                actions.push(bindings::ReloadShaders);
+            }
+
             loop {
                 match key_port.try_recv() {
-                    Some((glfw::Press, glfw::KeyR)) => {
+                    Some((glfw::Press, key)) => {
+                        actions.push_all(bindings.key_actions(key, modifiers));
+
+                        /* Number keys select the block with that id */
+                        match number_key(key) {
+                            Some(id) if (id as uint) < registry.get().len() => {
+                                selected_blocktype = id;
+                                println!("selected block: {}", registry.get().get(id).name);
+                            },
+                            _ => {}
+                        }
+                    },
+                    None => break,
+                    _ => {}
+                }
+            }
+
+            loop {
+                match mouse_button_port.try_recv() {
+                    Some((glfw::Press, button)) => {
+                        actions.push_all(bindings.mouse_actions(button, modifiers));
+                    },
+                    None => break,
+                    _ => {}
+                }
+            }
+
+            for &action in actions.iter() {
+                match action {
+                    bindings::ReloadShaders => {
                         match load_graphics_resources() {
                             Ok(x) => {
                                 unsafe { gl::DeleteTextures(1, &graphics_resources.texture); }
@@ -165,28 +279,55 @@ fn main() {
 
                                 graphics_resources = x;
 
-                                gl::UseProgram(graphics_resources.program);
-
-                                gl::ActiveTexture(gl::TEXTURE0);
-                                gl::Uniform1i(graphics_resources.uniform_texture, 0);
-
-                                gl::BindTexture(gl::TEXTURE_2D, graphics_resources.texture);
+                                use_graphics_resources(&graphics_resources, registry.get());
                             },
                             Err(msg) => println!("Error reloading graphics resources: {}", msg),
                         };
+
+                        if shadow_maps.is_some() {
+                            match shadow::ShadowMaps::new(config.shadow_size) {
+                                Ok(x) => shadow_maps = Some(x),
+                                Err(msg) => println!("Error reloading shadow maps: {}", msg),
+                            }
+                        }
                     },
-                    Some((glfw::Press, glfw::KeyEscape)) => {
+                    bindings::Quit => {
                         window.set_should_close(true);
                     },
-                    Some((glfw::Press, glfw::KeyG)) => {
+                    bindings::ToggleGrab => {
                         grabbed = !grabbed;
+                        last_cursor_pos = window.get_cursor_pos();
                         if grabbed {
                             window.set_cursor_mode(glfw::CursorDisabled);
                         } else {
                             window.set_cursor_mode(glfw::CursorNormal);
                         }
                     },
-                    Some((glfw::Press, glfw::KeyL)) => {
+                    bindings::ToggleFlying => {
+                        player.toggle_flying();
+                        println!("flying: {}", player.flying);
+                    },
+                    bindings::FreezeTime => {
+                        clock.toggle_frozen();
+                        println!("time frozen: {} at {:.1f} hours", clock.frozen, clock.hour());
+                    },
+                    bindings::ToggleCurvature => {
+                        camera.planet_radius = if camera.planet_radius > 0.0 {
+                            0.0
+                        } else {
+                            config.planet_radius
+                        };
+                        println!("curvature: {}", camera.planet_radius > 0.0);
+                    },
+                    bindings::ToggleShadows => {
+                        if shadow_maps.is_some() {
+                            shadows_enabled = !shadows_enabled;
+                            println!("shadows: {}", shadows_enabled);
+                        } else {
+                            println!("shadows are off with --shadow-size 0");
+                        }
+                    },
+                    bindings::ToggleWireframe => {
                         let mut cur_mode : GLint = 0;
                         unsafe { gl::GetIntegerv(gl::POLYGON_MODE, &mut cur_mode); }
                         if cur_mode == gl::FILL as i32 {
@@ -197,150 +338,106 @@ fn main() {
                             gl::Enable(gl::CULL_FACE);
                         }
                     },
-                    None => break,
                     _ => {}
                 }
             }
 
             if grabbed {
                 let (cursor_x, cursor_y) = window.get_cursor_pos();
-                camera_angle_y = ((cursor_x * 0.0005) % 1.0) * std::f64::consts::PI * 2.0;
-                camera_angle_x = ((cursor_y * 0.0005) % 1.0) * std::f64::consts::PI * 2.0;
+                let (last_x, last_y) = last_cursor_pos;
+                camera.look(cursor_x - last_x, cursor_y - last_y);
+                last_cursor_pos = (cursor_x, cursor_y);
             }
 
             let mut camera_velocity = Vec3::<f32>::new(0.0f32, 0.0f32, 0.0f32);
 
-            match window.get_key(glfw::KeySpace) {
-                glfw::Press => { camera_velocity.y += 1.0f32 }
-                _ => {}
-            }
+            let held = |action: bindings::Action| bindings.is_held(action, |input| input_down(&window, input));
 
-            match window.get_key(glfw::KeyLeftControl) {
-                glfw::Press => { camera_velocity.y += -1.0f32 }
-                _ => {}
+            if held(bindings::Jump) {
+                camera_velocity.y += 1.0f32;
             }
-
-            match window.get_key(glfw::KeyS) {
-                glfw::Press => { camera_velocity.z += 1.0f32 }
-                _ => {}
+            if held(bindings::Descend) {
+                camera_velocity.y += -1.0f32;
             }
-
-            match window.get_key(glfw::KeyW) {
-                glfw::Press => { camera_velocity.z += -1.0f32 }
-                _ => {}
+            if held(bindings::MoveBack) {
+                camera_velocity.z += 1.0f32;
             }
-
-            match window.get_key(glfw::KeyD) {
-                glfw::Press => { camera_velocity.x += 1.0f32 }
-                _ => {}
+            if held(bindings::MoveForward) {
+                camera_velocity.z += -1.0f32;
             }
-
-            match window.get_key(glfw::KeyA) {
-                glfw::Press => { camera_velocity.x += -1.0f32 }
-                _ => {}
+            if held(bindings::MoveRight) {
+                camera_velocity.x += 1.0f32;
+            }
+            if held(bindings::MoveLeft) {
+                camera_velocity.x += -1.0f32;
             }
 
             let now = extra::time::precise_time_ns();
             let tick_length = (now - last_tick) as f32 / (1000 * 1000 * 1000) as f32;
             last_tick = now;
 
-            gl::Viewport(0,0, window_width as GLint, window_height as GLint);
-
-            gl::ClearColor(0.0, 0.75, 1.0, 1.0);
-            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
-
-            let projection = cgmath::projection::perspective(
-                deg(75.0f32),
-                window_width as f32 / window_height as f32,
-                0.1f32, 1000.0f32);
-
-            unsafe {
-                gl::UniformMatrix4fv(graphics_resources.uniform_projection, 1, gl::FALSE, projection.ptr());
+            if held(bindings::FastForwardTime) {
+                clock.advance(tick_length as f64 * FAST_FORWARD_SPEED);
+            } else {
+                clock.advance(tick_length as f64);
             }
 
-            let camera_translation = Mat4::<f32>::from_cols(
-                Vec4::<f32>::unit_x(),
-                Vec4::<f32>::unit_y(),
-                Vec4::<f32>::unit_z(),
-                camera_position.mul_s(-1.0f32).extend(1.0f32));
-            let camera_rotation_x = Mat3::<f32>::from_angle_x(rad(camera_angle_x as f32)).to_mat4();
-            let camera_rotation_y = Mat3::<f32>::from_angle_y(rad(camera_angle_y as f32)).to_mat4();
-            let camera = camera_rotation_x.mul_m(&camera_rotation_y).mul_m(&camera_translation);
-
-            let inv_camera_rotation = Mat3::<f32>::from_euler(rad(-camera_angle_x as f32), rad(-camera_angle_y as f32), rad(0.0f32));
-            let absolute_camera_velocity = inv_camera_rotation.mul_v(&camera_velocity).mul_s(CAMERA_SPEED).mul_s(tick_length);
-            camera_position.add_self_v(&absolute_camera_velocity);
-
-            unsafe {
-                gl::Uniform3fv(graphics_resources.uniform_camera_position, 1, camera_position.ptr());
-                gl::UniformMatrix4fv(graphics_resources.uniform_view, 1, gl::FALSE, camera.ptr());
+            if player.flying {
+                let absolute_camera_velocity = camera.inverse_rotation().mul_v(&camera_velocity).mul_s(config.camera_speed).mul_s(tick_length);
+                player.fly(&absolute_camera_velocity);
+            } else {
+                /* Walking ignores pitch; Space jumps */
+                let direction = camera.heading().mul_v(&Vec3::new(camera_velocity.x, 0.0f32, camera_velocity.z));
+                player.walk(tick_length, &direction, camera_velocity.y > 0.0,
+                            |x, y, z| chunk_loader.is_solid(x, y, z));
             }
+            camera.position = player.eye_position();
 
-            let clip_transform = projection.mul_m(&camera);
-
-            let coords = visible_chunks(camera_position.x as i64,
-                                        camera_position.z as i64);
-
-            let mut culled = 0;
-            let mut rendered = 0;
-
-            for &(cx, cz) in coords.iter() {
-                match chunk_loader.cache.find_mut(&(cx, cz)) {
-                    Some(chunk) => {
-                        chunk.touch();
-
-                        let chunk_pos = Vec4::new(cx as f32, 0.0f32, cz as f32, 0.0f32);
-
-                        if view_frustum_cull(&clip_transform, &chunk_pos) {
-                            culled += 1;
-                            continue;
-                        }
-
-                        rendered += 1;
-
-                        let mesh = &chunk.mesh;
-
-                        mesh.bind_arrays(&graphics_resources);
-
-                        for face in chunk::faces.iter() {
-                            if !face_visible(face, cx, cz,
-                                             camera_position.x as i64,
-                                             camera_position.z as i64) {
-                                continue;
-                            }
+            for &action in actions.iter() {
+                if !grabbed || (action != bindings::BreakBlock && action != bindings::PlaceBlock) {
+                    continue;
+                }
 
-                            let (offset, count) = mesh.face_ranges[face.index];
-                            unsafe {
-                                gl::DrawElements(
-                                    gl::TRIANGLES,
-                                    count as i32,
-                                    gl::UNSIGNED_INT,
-                                    std::cast::transmute(
-                                        offset *
-                                        std::mem::size_of::<GLuint>()));
-                            }
-                        }
+                /* Aim through air and water alike */
+                let hit = raycast::raycast(&camera.position, &camera.forward(), REACH_DISTANCE,
+                    |x, y, z| match chunk_loader.get_block(x, y, z) {
+                        Some(block) => registry.get().is_solid(block.blocktype),
+                        None => false,
+                    });
+
+                match (action, hit) {
+                    (bindings::BreakBlock, Some(hit)) => {
+                        chunk_loader.set_block(hit.block.x, hit.block.y, hit.block.z,
+                                               chunk::Block { blocktype: blocks::AIR });
                     },
-                    None => {
-                        if !needed_chunks.contains(&(cx, cz)) {
-                            needed_chunks.push((cx, cz));
+                    (bindings::PlaceBlock, Some(hit)) => {
+                        match hit.adjacent() {
+                            Some(p) => {
+                                /* Don't trap the player inside the new block */
+                                if !(registry.get().is_solid(selected_blocktype) && player.overlaps(p.x, p.y, p.z)) {
+                                    chunk_loader.set_block(p.x, p.y, p.z,
+                                                           chunk::Block { blocktype: selected_blocktype });
+                                }
+                            },
+                            /* The camera is inside the hit block */
+                            None => {}
                         }
-                    }
+                    },
+                    _ => {}
                 }
             }
 
-            if false {
-                println!("culled={} rendered={}", culled, rendered);
-            }
+            chunk_loader.remesh_dirty();
+
+            let shadows = if shadows_enabled { shadow_maps.as_ref() } else { None };
+            render(&config, &graphics_resources, &mut chunk_loader, &camera, &clock.lighting(),
+                   shadows, window_width, window_height);
 
             window.swap_buffers();
 
             check_gl("main loop");
 
-            if !needed_chunks.is_empty() && load_limiter.limit() {
-                let (cx, cz) = needed_chunks.shift();
-                chunk_loader.load(cx, cz);
-            }
+            chunk_loader.upload(UPLOADS_PER_FRAME);
 
             fps_frame_counter += 1;
             if fps_display_limiter.limit() {
@@ -350,71 +447,211 @@ fn main() {
 
             let frame_end_time = extra::time::precise_time_ns();
             let frame_time_ms = (frame_end_time - frame_start_time)/(1000*1000);
-            if (frame_time_ms < FRAME_TIME_TARGET_MS) {
-                //timer.sleep(FRAME_TIME_TARGET_MS - frame_time_ms);
+            if (frame_time_ms < config.frame_time_target_ms) {
+                //timer.sleep(config.frame_time_target_ms - frame_time_ms);
             }
         }
+
+        chunk_loader.save_all();
     }
 }
 
-fn visible_chunks(x: i64, z: i64) -> ~[(i64, i64)] {
-    static num_chunks : uint = (VISIBLE_RADIUS * 2 + 1) * (VISIBLE_RADIUS * 2 + 1);
-    let mask : i64 = !(CHUNK_SIZE as i64 - 1);
-    let mut coords = ~[];
+/// Draws the visible chunks around the camera, requesting any that are not
+/// loaded yet. With `shadows`, first renders the sun's shadow maps.
+fn render(config: &config::Config,
+          graphics_resources: &GraphicsResources,
+          chunk_loader: &mut chunk::ChunkLoader,
+          camera: &camera::Camera,
+          lighting: &clock::Lighting,
+          shadows: Option<&shadow::ShadowMaps>,
+          width: u32, height: u32) {
+    let camera_position = &camera.position;
+    let aspect = width as f32 / height as f32;
+
+    let chunks = visible_chunks(config,
+                                camera_position.x as i64,
+                                camera_position.y as i64,
+                                camera_position.z as i64);
+
+    let coords : ~[(i64, i64, i64)] = chunks.iter().map(|&(c, _)| c).collect();
 
-    for v in Spiral::<i64>::new(num_chunks) {
-        let cx : i64 = (x & mask) + v.x*CHUNK_SIZE as i64;
-        let cz : i64 = (z & mask) + v.y*CHUNK_SIZE as i64;
-        coords.push((cx, cz));
+    unsafe {
+        gl::Uniform3fv(graphics_resources.uniform_light_direction, 1, lighting.sun_direction.ptr());
+        gl::Uniform3fv(graphics_resources.uniform_sun_color, 1, lighting.sun_color.ptr());
+        gl::Uniform3fv(graphics_resources.uniform_ambient_color, 1, lighting.ambient_color.ptr());
+        gl::Uniform3fv(graphics_resources.uniform_fog_color, 1, lighting.sky_color.ptr());
     }
-    coords
-}
 
-fn view_frustum_cull(m : &Mat4<f32>, p: &Vec4<f32>) -> bool {
-    static L : f32 = CHUNK_SIZE as f32;
+    match shadows {
+        Some(shadow_maps) => {
+            let range = (config.visible_radius * CHUNK_SIZE) as f32;
+            let cascades = shadow_maps.cascades(camera, aspect, &lighting.sun_direction, range);
+            shadow_maps.draw(cascades, &*chunk_loader, coords);
+            shadow_maps.bind_textures();
 
-    static vertices : [Vec4<f32>, ..8] = [
-        Vec4 { x: 0.0, y: 0.0, z: L,   w: 1.0 }, /* front bottom left */
-        Vec4 { x: L,   y: 0.0, z: L,   w: 1.0 }, /* front bottom right */
-        Vec4 { x: 0.0, y: L,   z: L,   w: 1.0 }, /* front top left */
-        Vec4 { x: L,   y: L,   z: L,   w: 1.0 }, /* front top right */
-        Vec4 { x: L,   y: 0.0, z: 0.0, w: 1.0 }, /* back bottom right */
-        Vec4 { x: 0.0, y: 0.0, z: 0.0, w: 1.0 }, /* back bottom left */
-        Vec4 { x: L,   y: L,   z: 0.0, w: 1.0 }, /* back top right */
-        Vec4 { x: 0.0, y: L,   z: 0.0, w: 1.0 }, /* back top left */
-    ];
+            let transforms : ~[Mat4<f32>] = cascades.iter().map(|c| c.texture_transform()).collect();
+            unsafe {
+                gl::UniformMatrix4fv(graphics_resources.uniform_shadow_transform,
+                                     transforms.len() as GLsizei, gl::FALSE, transforms[0].ptr());
+            }
+            gl::Uniform3f(graphics_resources.uniform_shadow_splits,
+                          cascades[0].far, cascades[1].far, cascades[2].far);
+            gl::Uniform1i(graphics_resources.uniform_shadows_enabled, 1);
+        },
+        None => gl::Uniform1i(graphics_resources.uniform_shadows_enabled, 0),
+    }
 
-    let clip_vertices = vertices.map(|v| m.mul_v(&p.add_v(v)));
+    gl::Viewport(0,0, width as GLint, height as GLint);
 
-    if clip_vertices.iter().all(|v| v.x < -v.w) {
-        return true;
-    }
+    let sky = &lighting.sky_color;
+    gl::ClearColor(sky.x, sky.y, sky.z, 1.0);
+    gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+
+    graphics_resources.sky.draw(camera, aspect, lighting);
+
+    let projection = camera.projection(aspect);
+    let view = camera.view();
 
-    if clip_vertices.iter().all(|v| v.x > v.w) {
-        return true;
+    unsafe {
+        gl::UniformMatrix4fv(graphics_resources.uniform_projection, 1, gl::FALSE, projection.ptr());
+        gl::Uniform3fv(graphics_resources.uniform_camera_position, 1, camera_position.ptr());
+        gl::UniformMatrix4fv(graphics_resources.uniform_view, 1, gl::FALSE, view.ptr());
     }
 
-    if clip_vertices.iter().all(|v| v.y < -v.w) {
-        return true;
+    let clip_transform = projection.mul_m(&view);
+    let curvature = camera.curvature();
+    gl::Uniform1f(graphics_resources.uniform_planet_radius, camera.planet_radius);
+
+    /* Thin the fog out as the view distance grows, so it only hides the edge */
+    gl::Uniform1f(graphics_resources.uniform_fog_density,
+                  FOG_DENSITY * 8.0 / config.visible_radius as f32);
+
+    let camera_chunk = chunk::chunk_coords(camera_position.x as i64,
+                                           camera_position.y as i64,
+                                           camera_position.z as i64);
+    chunk_loader.update_visible(camera_chunk, coords);
+
+    let mut culled = 0;
+    let mut rendered = 0;
+    let mut transparent_chunks = ~[];
+
+    for &((cx, cy, cz), lod) in chunks.iter() {
+        chunk_loader.set_lod((cx, cy, cz), lod);
+
+        match chunk_loader.cache.find_mut(&(cx, cy, cz)) {
+            Some(chunk) => {
+                chunk.touch();
+
+                let chunk_pos = Vec4::new(cx as f32, cy as f32, cz as f32, 0.0f32);
+
+                if camera::view_frustum_cull(&clip_transform, &chunk_pos, &curvature) {
+                    culled += 1;
+                    continue;
+                }
+
+                rendered += 1;
+
+                let mesh = &chunk.mesh;
+
+                if mesh.transparent_count > 0 {
+                    let half = CHUNK_SIZE as i64 / 2;
+                    let dx = cx + half - camera_position.x as i64;
+                    let dy = cy + half - camera_position.y as i64;
+                    let dz = cz + half - camera_position.z as i64;
+                    transparent_chunks.push((dx*dx + dy*dy + dz*dz, (cx, cy, cz)));
+                }
+
+                mesh.bind_arrays(graphics_resources);
+                gl::Uniform3f(graphics_resources.uniform_chunk_origin, cx as f32, cy as f32, cz as f32);
+
+                for face in mesher::faces.iter() {
+                    if !face_visible(face, cx, cy, cz,
+                                     camera_position.x as i64,
+                                     camera_position.y as i64,
+                                     camera_position.z as i64) {
+                        continue;
+                    }
+
+                    let (offset, count) = mesh.face_ranges[face.index];
+                    unsafe {
+                        gl::DrawElements(
+                            gl::TRIANGLES,
+                            count as i32,
+                            gl::UNSIGNED_INT,
+                            std::cast::transmute(
+                                offset *
+                                std::mem::size_of::<GLuint>()));
+                    }
+                }
+            },
+            None => {
+                chunk_loader.request(cx, cy, cz, lod);
+            }
+        }
     }
 
-    if clip_vertices.iter().all(|v| v.y > v.w) {
-        return true;
+    /*
+     * Transparent blocks are blended over the opaque geometry, so they
+     * are drawn last and back to front. Sorting is per chunk only.
+     */
+    transparent_chunks.sort_by(|&(a, _), &(b, _)| b.cmp(&a));
+
+    let cull_face = gl::IsEnabled(gl::CULL_FACE) == gl::TRUE;
+    gl::Disable(gl::CULL_FACE);
+    gl::Enable(gl::BLEND);
+    gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+    gl::DepthMask(gl::FALSE);
+
+    for &(_, coords) in transparent_chunks.iter() {
+        let mesh = &chunk_loader.cache.get(&coords).mesh;
+        let (cx, cy, cz) = coords;
+        mesh.bind_arrays(graphics_resources);
+        gl::Uniform3f(graphics_resources.uniform_chunk_origin, cx as f32, cy as f32, cz as f32);
+        mesh.bind_transparent_elements();
+        unsafe {
+            gl::DrawElements(gl::TRIANGLES, mesh.transparent_count as i32,
+                             gl::UNSIGNED_INT, ptr::null());
+        }
     }
 
-    if clip_vertices.iter().all(|v| v.z < -v.w) {
-        return true;
+    gl::DepthMask(gl::TRUE);
+    gl::Disable(gl::BLEND);
+    if cull_face {
+        gl::Enable(gl::CULL_FACE);
     }
 
-    if clip_vertices.iter().all(|v| v.z > v.w) {
-        return true;
+    if false {
+        println!("culled={} rendered={}", culled, rendered);
     }
+}
+
+/// Returns the chunks around (x, y, z), nearest first, with the level of
+/// detail to draw each at.
+fn visible_chunks(config: &config::Config, x: i64, y: i64, z: i64) -> ~[((i64, i64, i64), uint)] {
+    let num_chunks = (config.visible_radius * 2 + 1) * (config.visible_radius * 2 + 1);
+    let mask : i64 = !(CHUNK_SIZE as i64 - 1);
+    let mut coords = ~[];
+
+    for v in Spiral::<i64>::new(num_chunks) {
+        let cx : i64 = (x & mask) + v.x*CHUNK_SIZE as i64;
+        let cz : i64 = (z & mask) + v.y*CHUNK_SIZE as i64;
+        let lod = config.lod(std::cmp::max(v.x.abs(), v.y.abs()) as uint);
 
-    return false;
+        /* Nearest layers first: 0, +1, -1, +2, -2, ... */
+        for i in range(0, config.visible_height * 2 + 1) {
+            let dy = if i % 2 == 1 { (i / 2 + 1) as i64 } else { -((i / 2) as i64) };
+            let cy : i64 = (y & mask) + dy*CHUNK_SIZE as i64;
+            coords.push(((cx, cy, cz), lod));
+        }
+    }
+    coords
 }
 
-fn face_visible(face : &chunk::Face, cx : i64, cz : i64, px : i64, pz : i64) -> bool {
+fn face_visible(face : &mesher::Face,
+                cx : i64, cy : i64, cz : i64,
+                px : i64, py : i64, pz : i64) -> bool {
     let dx = px - cx;
+    let dy = py - cy;
     let dz = pz - cz;
 
     match face.index {
@@ -422,28 +659,26 @@ fn face_visible(face : &chunk::Face, cx : i64, cz : i64, px : i64, pz : i64) ->
         1 => dz <= CHUNK_SIZE as i64,
         2 => dx >= 0,
         3 => dx <= CHUNK_SIZE as i64,
-        4 => true,
-        5 => true,
+        4 => dy >= 0,
+        5 => dy <= CHUNK_SIZE as i64,
         _ => unreachable!()
     }
 }
 
 fn load_graphics_resources() -> Result<GraphicsResources, ~str> {
-    let vs_src = std::io::fs::File::open_mode(&std::path::Path::new("main.vs.glsl"), std::io::Open, std::io::Read).unwrap().read_to_end();
-    let vs = match compile_shader(vs_src, gl::VERTEX_SHADER) {
-        Ok(vs) => vs,
-        Err(msg) => { return Err("vertex shader " + msg) },
-    };
-
-    let fs_src = std::io::fs::File::open_mode(&std::path::Path::new("main.fs.glsl"), std::io::Open, std::io::Read).unwrap().read_to_end();
-    let fs = match compile_shader(fs_src, gl::FRAGMENT_SHADER) {
-        Ok(fs) => fs,
-        Err(msg) => { return Err("fragment shader " + msg) },
+    let (program, vs, fs) = match load_program("main.vs.glsl", "main.fs.glsl") {
+        Ok(x) => x,
+        Err(msg) => return Err(msg),
     };
 
-    let program = match link_program(vs, fs) {
-        Ok(program) => program,
-        Err(msg) => { return Err("linking " + msg) },
+    let sky = match sky::Sky::new() {
+        Ok(x) => x,
+        Err(msg) => {
+            gl::DeleteProgram(program);
+            gl::DeleteShader(vs);
+            gl::DeleteShader(fs);
+            return Err(msg);
+        },
     };
 
     let texture = texture::make_noise_texture();
@@ -452,20 +687,221 @@ fn load_graphics_resources() -> Result<GraphicsResources, ~str> {
     let uniform_projection = unsafe { "projection".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
     let uniform_camera_position = unsafe { "camera_position".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
     let uniform_texture = unsafe { "texture".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+    let uniform_block_color = unsafe { "block_color".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+    let uniform_block_texture = unsafe { "block_texture".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+    let uniform_fog_density = unsafe { "fog_density".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+    let uniform_chunk_blocks = unsafe { "chunk_blocks".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+    let uniform_chunk_origin = unsafe { "chunk_origin".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+    let uniform_light_direction = unsafe { "light_direction".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+    let uniform_planet_radius = unsafe { "planet_radius".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+    let uniform_sun_color = unsafe { "sun_color".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+    let uniform_ambient_color = unsafe { "ambient_color".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+    let uniform_fog_color = unsafe { "fog_color".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+    let uniform_shadows_enabled = unsafe { "shadows_enabled".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+    let uniform_shadow_maps = unsafe { "shadow_maps".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+    let uniform_shadow_transform = unsafe { "shadow_transform".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+    let uniform_shadow_splits = unsafe { "shadow_splits".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
 
     return Ok(GraphicsResources {
         program: program,
         vertex_shader: vs,
         fragment_shader: fs,
         texture: texture,
+        sky: sky,
         uniform_view: uniform_view,
         uniform_projection: uniform_projection,
         uniform_camera_position: uniform_camera_position,
         uniform_texture: uniform_texture,
+        uniform_block_color: uniform_block_color,
+        uniform_block_texture: uniform_block_texture,
+        uniform_fog_density: uniform_fog_density,
+        uniform_chunk_blocks: uniform_chunk_blocks,
+        uniform_chunk_origin: uniform_chunk_origin,
+        uniform_light_direction: uniform_light_direction,
+        uniform_planet_radius: uniform_planet_radius,
+        uniform_sun_color: uniform_sun_color,
+        uniform_ambient_color: uniform_ambient_color,
+        uniform_fog_color: uniform_fog_color,
+        uniform_shadows_enabled: uniform_shadows_enabled,
+        uniform_shadow_maps: uniform_shadow_maps,
+        uniform_shadow_transform: uniform_shadow_transform,
+        uniform_shadow_splits: uniform_shadow_splits,
     });
 
 }
 
+/// Disables every vertex attribute array, so none left enabled by another
+/// program point past the end of a smaller buffer.
+fn disable_vertex_arrays() {
+    for i in range(0, MAX_ATTRIBUTES) {
+        gl::DisableVertexAttribArray(i);
+    }
+}
+
+/// Compiles and links the shaders in the given files. Returns the program
+/// and its vertex and fragment shaders. Creates nothing on failure.
+fn load_program(vs_path: &str, fs_path: &str) -> Result<(GLuint, GLuint, GLuint), ~str> {
+    let vs = match load_shader(vs_path, gl::VERTEX_SHADER) {
+        Ok(vs) => vs,
+        Err(msg) => { return Err(msg) },
+    };
+
+    let fs = match load_shader(fs_path, gl::FRAGMENT_SHADER) {
+        Ok(fs) => fs,
+        Err(msg) => {
+            gl::DeleteShader(vs);
+            return Err(msg);
+        },
+    };
+
+    let program = match link_program(vs, fs) {
+        Ok(program) => program,
+        Err(msg) => {
+            gl::DeleteShader(vs);
+            gl::DeleteShader(fs);
+            return Err(format!("linking {} and {}: {}", vs_path, fs_path, msg));
+        },
+    };
+
+    Ok((program, vs, fs))
+}
+
+/// Reads a shader with its includes and compiles it, giving errors with
+/// the original files and lines.
+fn load_shader(path: &str, ty: GLenum) -> Result<GLuint, ~str> {
+    let source = match shader::Source::load(&std::path::Path::new(path)) {
+        Ok(x) => x,
+        Err(msg) => { return Err(msg) },
+    };
+
+    match compile_shader(source.text.as_bytes(), ty) {
+        Ok(shader) => Ok(shader),
+        Err(msg) => Err(format!("compiling {}:\n{}", path, source.map_log(msg.as_slice()))),
+    }
+}
+
+fn load_registry() -> extra::arc::Arc<blocks::BlockRegistry> {
+    match blocks::BlockRegistry::load(&std::path::Path::new(BLOCKS_PATH)) {
+        Ok(x) => extra::arc::Arc::new(x),
+        Err(msg) => fail!("Error loading block registry: {}", msg),
+    }
+}
+
+fn new_chunk_loader(config: &config::Config, registry: extra::arc::Arc<blocks::BlockRegistry>,
+                    world_dir: std::path::Path) -> chunk::ChunkLoader {
+    let generator = match config.generator {
+        config::DefaultTerrain =>
+            terrain::DefaultGenerator::new(config.seed, registry.get())
+                .map(|x| ~x as ~terrain::TerrainGenerator:Send+Freeze),
+        config::FlatTerrain(height) =>
+            terrain::FlatGenerator::new(height, registry.get())
+                .map(|x| ~x as ~terrain::TerrainGenerator:Send+Freeze),
+        config::HeightmapTerrain(ref path) =>
+            terrain::HeightmapGenerator::load(path, 0, HEIGHTMAP_SCALE, registry.get())
+                .map(|x| ~x as ~terrain::TerrainGenerator:Send+Freeze),
+    };
+    let generator = match generator {
+        Ok(x) => x,
+        Err(msg) => fail!("Error creating terrain generator: {}", msg),
+    };
+
+    chunk::ChunkLoader::new(
+        generator,
+        registry,
+        world_dir,
+        config.max_chunks(),
+        config.merge_faces)
+}
+
+/// Creates the sun's shadow maps, unless they are turned off.
+fn new_shadow_maps(config: &config::Config) -> Option<shadow::ShadowMaps> {
+    if config.shadow_size == 0 {
+        return None;
+    }
+
+    match shadow::ShadowMaps::new(config.shadow_size) {
+        Ok(x) => Some(x),
+        Err(msg) => fail!("Error creating shadow maps: {}", msg),
+    }
+}
+
+/// Sets up GL state and loads the shaders and textures for the current
+/// context.
+fn init_graphics(registry: &blocks::BlockRegistry) -> GraphicsResources {
+    gl::Enable(gl::TEXTURE_2D);
+    gl::Enable(gl::DEPTH_TEST);
+    gl::Enable(gl::CULL_FACE);
+
+    let graphics_resources = match load_graphics_resources() {
+        Ok(x) => x,
+        Err(msg) => fail!("Error loading graphics resources: {}", msg),
+    };
+
+    check_gl("after loading graphics resources");
+
+    use_graphics_resources(&graphics_resources, registry);
+
+    graphics_resources
+}
+
+fn use_graphics_resources(graphics_resources: &GraphicsResources, registry: &blocks::BlockRegistry) {
+    gl::UseProgram(graphics_resources.program);
+
+    gl::ActiveTexture(gl::TEXTURE0);
+    gl::Uniform1i(graphics_resources.uniform_texture, 0);
+    /* Bound per chunk by Mesh::bind_arrays */
+    gl::Uniform1i(graphics_resources.uniform_chunk_blocks, 1);
+    /* Bound each frame by ShadowMaps::bind_textures */
+    let shadow_units = vec::from_fn(shadow::NUM_CASCADES,
+                                    |i| (shadow::FIRST_TEXTURE_UNIT + i) as GLint);
+    unsafe {
+        gl::Uniform1iv(graphics_resources.uniform_shadow_maps,
+                       shadow::NUM_CASCADES as GLsizei, shadow_units.unsafe_ref(0));
+    }
+
+    gl::BindTexture(gl::TEXTURE_2D, graphics_resources.texture);
+
+    set_block_uniforms(graphics_resources, registry);
+}
+
+/// Uploads each block type's color and texture parameters. The program
+/// must be in use.
+fn set_block_uniforms(graphics_resources: &GraphicsResources, registry: &blocks::BlockRegistry) {
+    let (colors, textures) = registry.uniforms();
+    unsafe {
+        gl::Uniform4fv(graphics_resources.uniform_block_color,
+                       registry.len() as GLsizei, colors.unsafe_ref(0));
+        gl::Uniform3fv(graphics_resources.uniform_block_texture,
+                       registry.len() as GLsizei, textures.unsafe_ref(0));
+    }
+}
+
+fn input_down(window: &glfw::Window, input: &bindings::Input) -> bool {
+    let action = match *input {
+        bindings::Key(key) => window.get_key(key),
+        bindings::MouseButton(button) => window.get_mouse_button(button),
+    };
+    match action {
+        glfw::Release => false,
+        _ => true,
+    }
+}
+
+fn number_key(key: glfw::Key) -> Option<blocks::BlockId> {
+    match key {
+        glfw::Key1 => Some(1),
+        glfw::Key2 => Some(2),
+        glfw::Key3 => Some(3),
+        glfw::Key4 => Some(4),
+        glfw::Key5 => Some(5),
+        glfw::Key6 => Some(6),
+        glfw::Key7 => Some(7),
+        glfw::Key8 => Some(8),
+        glfw::Key9 => Some(9),
+        _ => None,
+    }
+}
+
 extern "C" {
     fn gluErrorString(error: GLenum) -> *GLubyte;
 }
@@ -499,6 +935,7 @@ fn compile_shader(src: &[u8], ty: GLenum) -> Result<GLuint,~str> {
             gl::GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut len);
             let mut buf = vec::from_elem(len as uint - 1, 0u8);     // subtract 1 to skip the trailing null character
             gl::GetShaderInfoLog(shader, len, ptr::mut_null(), buf.as_mut_ptr() as *mut GLchar);
+            gl::DeleteShader(shader);
             return Err(str::raw::from_utf8(buf).to_owned());
         }
     }
@@ -521,12 +958,69 @@ fn link_program(vs: GLuint, fs: GLuint) -> Result<GLuint, ~str> {
             gl::GetProgramiv(program, gl::INFO_LOG_LENGTH, &mut len);
             let mut buf = vec::from_elem(len as uint - 1, 0u8);     // subtract 1 to skip the trailing null character
             gl::GetProgramInfoLog(program, len, ptr::mut_null(), buf.as_mut_ptr() as *mut GLchar);
+            gl::DeleteProgram(program);
             return Err(str::raw::from_utf8(buf).to_owned());
         }
     }
     Ok(program)
 }
 
+/// Runs `f`, turning an IO error raised inside it into Err instead of
+/// failing the task.
+fn trap_io_error<T>(f: || -> T) -> Result<T, ~str> {
+    let mut error = None;
+    let result = std::io::io_error::cond.trap(|e: std::io::IoError| {
+        if error.is_none() {
+            error = Some(match e.detail {
+                Some(ref detail) => format!("{} ({})", e.desc, *detail),
+                None => e.desc.to_owned(),
+            });
+        }
+    }).inside(f);
+    match error {
+        Some(msg) => Err(msg),
+        None => Ok(result),
+    }
+}
+
+fn read_file(path: &Path) -> Result<~[u8], ~str> {
+    let data = trap_io_error(|| {
+        match File::open(path) {
+            Some(mut file) => Some(file.read_to_end()),
+            None => None,
+        }
+    });
+    match data {
+        Ok(Some(data)) => Ok(data),
+        Ok(None) => Err(format!("failed to open {}", path.display())),
+        Err(msg) => Err(format!("failed to read {}: {}", path.display(), msg)),
+    }
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<(), ~str> {
+    let created = trap_io_error(|| {
+        match File::create(path) {
+            Some(mut file) => { file.write(data); true },
+            None => false,
+        }
+    });
+    match created {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!("failed to create {}", path.display())),
+        Err(msg) => Err(format!("failed to write {}: {}", path.display(), msg)),
+    }
+}
+
+/// Inflates raw deflate data. extra::flate fails the task on corrupt
+/// input, so it runs in a task of its own.
+fn inflate(data: &[u8]) -> Result<~[u8], ~str> {
+    let data = data.to_owned();
+    match std::task::try(proc() { extra::flate::inflate_bytes(data) }) {
+        Ok(result) => Ok(result),
+        Err(_) => Err(~"corrupt compressed data"),
+    }
+}
+
 struct ErrorContext;
 impl glfw::ErrorCallback for ErrorContext {
     fn call(&self, _: glfw::Error, description: ~str) {
@@ -543,6 +1037,15 @@ impl glfw::KeyCallback for KeyContext {
     }
 }
 
+struct MouseButtonContext {
+    chan : Chan<(glfw::Action, glfw::MouseButton)>,
+}
+impl glfw::MouseButtonCallback for MouseButtonContext {
+    fn call(&self, _: &glfw::Window, button: glfw::MouseButton, action: glfw::Action, _: glfw::Modifiers) {
+        self.chan.send((action, button));
+    }
+}
+
 struct FramebufferSizeContext {
     chan: Chan<(u32,u32)>
 }
@@ -0,0 +1,968 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern mod extra;
+extern mod cgmath;
+
+use std;
+use std::vec;
+
+use extra::time::precise_time_ns;
+use extra::bitv::BitvSet;
+
+use cgmath::vector::Vector;
+use cgmath::vector::Vec2;
+use cgmath::vector::Vec3;
+
+use blocks::{BlockId, BlockRegistry, AIR};
+use chunk::{Map, Block};
+use light::{LightMap, MAX_LIGHT};
+#[cfg(test)]
+use blocks::test_registry;
+#[cfg(test)]
+use light::{SkyLight, BlockLight};
+use CHUNK_SIZE;
+
+pub static NUM_FACES : uint = 6;
+
+/// Packed face occlusion with every corner fully lit.
+static UNOCCLUDED : u8 = 0xff;
+
+/// Packed light levels of open ground in daylight.
+static DAYLIGHT : u8 = 0xf0;
+
+/// Coarsest level of detail, where blocks are merged into cubes of 8.
+pub static MAX_LOD : uint = 3;
+
+pub struct Face {
+    index: uint,
+    normal: Vec3<f32>,
+    di: Vec3<uint>,
+    dj: Vec3<uint>,
+    dk: Vec3<uint>,
+    vertices: [Vec3<f32>, ..4],
+}
+
+/// Vertex data for a chunk, independent of any GL context.
+///
+/// Each face direction's quads are contiguous in `elements`; `face_ranges`
+/// holds the (offset, count) of each direction so the renderer can skip
+/// faces pointing away from the camera.
+///
+/// Quads of transparent blocks share the vertex arrays but are indexed by
+/// `transparent_elements` instead, so they can be drawn in a later blended
+/// pass.
+///
+/// When faces of different block types are merged, each vertex's block type
+/// is -1 and `materials` holds the block id of every position in the chunk,
+/// indexed like `Map::blocks`, for the fragment shader to look up.
+///
+/// `occlusion` is the ambient occlusion of each vertex, from 0 in the open
+/// to 1 in a corner enclosed by opaque blocks on both sides. `light` holds
+/// the skylight and block light in front of each face, scaled to 0 to 1.
+pub struct MeshData {
+    vertices: ~[Vec3<f32>],
+    normals: ~[Vec3<f32>],
+    blocktypes: ~[f32],
+    occlusion: ~[f32],
+    light: ~[Vec2<f32>],
+    elements: ~[u32],
+    face_ranges: [(uint, uint), ..NUM_FACES],
+    transparent_elements: ~[u32],
+    materials: ~[u8],
+}
+
+/// The blocks and light levels just outside a chunk, one layer per face.
+///
+/// A face with no neighbour layer is treated as bordering air, so its
+/// boundary quads are emitted. Neighbours drawn at a different level of
+/// detail are left out: their surfaces don't line up, and the boundary
+/// quads of both chunks then close any gap between them.
+pub struct Neighbours {
+    borders: ~[Option<~[BlockId]>],
+    light_borders: ~[Option<~[u8]>],
+}
+
+impl Neighbours {
+    pub fn new() -> Neighbours {
+        Neighbours {
+            borders: vec::from_fn(NUM_FACES, |_| None),
+            light_borders: vec::from_fn(NUM_FACES, |_| None),
+        }
+    }
+
+    /// Records the neighbour across `face` of the chunk being meshed, as
+    /// seen at level of detail `lod`.
+    pub fn set(&mut self, face: &Face, neighbour: &Map, light: &LightMap, lod: uint) {
+        let opposite = &faces[face.index ^ 1];
+        let layer = if lod == 0 {
+            border(neighbour, opposite)
+        } else {
+            border(downsample(neighbour, lod), opposite)
+        };
+        self.borders[face.index] = Some(layer);
+        self.light_borders[face.index] =
+            Some(border_layer(opposite, 0u8, |x, y, z| light.packed(x, y, z)));
+    }
+
+    /// Whether any neighbour touches the chunk with a non-air block, which
+    /// may hide some of its faces.
+    pub fn any_blocks(&self) -> bool {
+        self.borders.iter().any(|b| match *b {
+            Some(ref layer) => layer.iter().any(|&id| id != AIR),
+            None => false,
+        })
+    }
+
+    /// Looks up a block one step outside the chunk along a single axis.
+    fn block(&self, x: int, y: int, z: int) -> Option<BlockId> {
+        let (face_index, i) = outside_index(x, y, z);
+        match self.borders[face_index] {
+            Some(ref layer) => Some(layer[i]),
+            None => None,
+        }
+    }
+
+    /// Looks up the packed light levels one step outside the chunk along a
+    /// single axis.
+    fn light(&self, x: int, y: int, z: int) -> Option<u8> {
+        let (face_index, i) = outside_index(x, y, z);
+        match self.light_borders[face_index] {
+            Some(ref layer) => Some(layer[i]),
+            None => None,
+        }
+    }
+}
+
+/// The face a position one step outside the chunk lies across, and its
+/// index in that face's border layer.
+fn outside_index(x: int, y: int, z: int) -> (uint, uint) {
+    let size = CHUNK_SIZE as int;
+    let face_index = if x >= size { 2 } else if x < 0 { 3 }
+                     else if y >= size { 4 } else if y < 0 { 5 }
+                     else if z >= size { 0 } else { 1 };
+    let wrap = |v: int| ((v + size) % size) as uint;
+    (face_index, layer_index(&faces[face_index], wrap(x), wrap(y), wrap(z)))
+}
+
+/// Returns the layer of `map` on the side of `face`.
+pub fn border(map: &Map, face: &Face) -> ~[BlockId] {
+    border_layer(face, AIR, |x, y, z| map.blocks[x][y][z].blocktype)
+}
+
+/// Collects `get` over the layer of a chunk on the side of `face`.
+fn border_layer<T: Clone>(face: &Face, default: T, get: |uint, uint, uint| -> T) -> ~[T] {
+    let last = CHUNK_SIZE - 1;
+    let mut layer = vec::from_elem(CHUNK_SIZE * CHUNK_SIZE, default);
+    for u in range(0, CHUNK_SIZE) {
+        for v in range(0, CHUNK_SIZE) {
+            let (x, y, z) = match face.index {
+                0 => (u, v, last),
+                1 => (u, v, 0),
+                2 => (last, u, v),
+                3 => (0, u, v),
+                4 => (u, last, v),
+                _ => (u, 0, v),
+            };
+            layer[layer_index(face, x, y, z)] = get(x, y, z);
+        }
+    }
+    layer
+}
+
+/// Returns a copy of `map` at level of detail `lod`, where each cube of
+/// 2^lod blocks on a side holds a single block type. A cube that is at least
+/// half non-air takes its most common non-air type, otherwise it is air, so
+/// surfaces stay close to their real height. The greedy mesher then merges
+/// each cube's faces into one quad.
+pub fn downsample(map: &Map, lod: uint) -> ~Map {
+    let size = 1u << lod;
+    let cells = CHUNK_SIZE / size;
+    let mut result = Map::new();
+
+    for cx in range(0, cells) {
+        for cy in range(0, cells) {
+            for cz in range(0, cells) {
+                let mut counts = [0u, ..256];
+                for x in range(cx * size, (cx + 1) * size) {
+                    for y in range(cy * size, (cy + 1) * size) {
+                        for z in range(cz * size, (cz + 1) * size) {
+                            counts[map.blocks[x][y][z].blocktype] += 1;
+                        }
+                    }
+                }
+
+                let mut blocktype = AIR;
+                let mut solid = 0;
+                for id in range(1u, 256) {
+                    solid += counts[id];
+                    if counts[id] > counts[blocktype] || blocktype == AIR && counts[id] > 0 {
+                        blocktype = id as BlockId;
+                    }
+                }
+                if solid * 2 < size * size * size {
+                    continue;
+                }
+
+                for x in range(cx * size, (cx + 1) * size) {
+                    for y in range(cy * size, (cy + 1) * size) {
+                        for z in range(cz * size, (cz + 1) * size) {
+                            result.blocks[x][y][z] = Block { blocktype: blocktype };
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    result
+}
+
+/// Index into a border layer by the two coordinates lying in the face plane.
+fn layer_index(face: &Face, x: uint, y: uint, z: uint) -> uint {
+    match face.index {
+        0 | 1 => x * CHUNK_SIZE + y,
+        2 | 3 => y * CHUNK_SIZE + z,
+        _ => x * CHUNK_SIZE + z,
+    }
+}
+
+/// Builds a greedy mesh of `map` at level of detail `lod`, with vertices
+/// offset by the chunk position. Faces hidden by blocks in `neighbours` are
+/// culled. With `merge_types`, adjacent faces merge whatever their block
+/// type, as long as they are both opaque or both transparent. Faces only
+/// merge if their ambient occlusion and the light in front of them match.
+/// Both are only used at full detail; coarse meshes are drawn unoccluded
+/// in full daylight.
+pub fn build(chunk_x: i64, chunk_y: i64, chunk_z: i64, map: &Map, light: &LightMap,
+             lod: uint, neighbours: &Neighbours, registry: &BlockRegistry,
+             merge_types: bool) -> ~MeshData {
+    let start_time = precise_time_ns();
+
+    let downsampled = if lod > 0 { Some(downsample(map, lod)) } else { None };
+    let map = match downsampled {
+        Some(ref m) => &**m,
+        None => map,
+    };
+
+    let mut vertices : ~[Vec3<f32>] = ~[];
+    let mut normals : ~[Vec3<f32>] = ~[];
+    let mut blocktypes : ~[f32] = ~[];
+    let mut occlusion : ~[f32] = ~[];
+    let mut light_levels : ~[Vec2<f32>] = ~[];
+    let mut elements : ~[u32] = ~[];
+    let mut transparent_elements : ~[u32] = ~[];
+
+    static expected_vertices : uint = 8000;
+    static expected_elements : uint = expected_vertices * 3 / 2;
+    vertices.reserve(expected_vertices);
+    normals.reserve(expected_vertices);
+    blocktypes.reserve(expected_vertices);
+    occlusion.reserve(expected_vertices);
+    light_levels.reserve(expected_vertices);
+    elements.reserve(expected_elements);
+
+    let mut face_ranges = [(0, 0), ..NUM_FACES];
+
+    let chunk_position = Vec3 {
+        x: chunk_x as f32,
+        y: chunk_y as f32,
+        z: chunk_z as f32,
+    };
+
+    for face in faces.iter() {
+        let num_elements_start = elements.len();
+
+        let face_normal_int = Vec3 { x: face.normal.x as int, y: face.normal.y as int, z: face.normal.z as int };
+
+        let mut unmeshed_faces = BlockBitmap::new();
+        /* Light in front of each face in the high byte, occlusion in the low */
+        let mut face_shading = vec::from_elem(CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE, 0u16);
+        for x in std::iter::range(0, CHUNK_SIZE) {
+            for y in std::iter::range(0, CHUNK_SIZE) {
+                for z in std::iter::range(0, CHUNK_SIZE) {
+                    let block = &map.blocks[x][y][z];
+
+                    if (block.blocktype == AIR) {
+                        continue;
+                    }
+
+                    if face_hidden(map, neighbours, registry, block.blocktype,
+                                   x as int + face_normal_int.x,
+                                   y as int + face_normal_int.y,
+                                   z as int + face_normal_int.z) {
+                        continue;
+                    }
+
+                    unmeshed_faces.insert(x, y, z);
+                    let (ao, front_light) = if lod == 0 {
+                        (face_occlusion(map, neighbours, registry, face, x, y, z),
+                         face_light(light, neighbours,
+                                    x as int + face_normal_int.x,
+                                    y as int + face_normal_int.y,
+                                    z as int + face_normal_int.z))
+                    } else {
+                        (UNOCCLUDED, DAYLIGHT)
+                    };
+                    face_shading[BlockBitmap::index(x, y, z)] = (front_light as u16 << 8) | ao as u16;
+                }
+            }
+        }
+
+        for i in std::iter::range(0, CHUNK_SIZE) {
+            for j in std::iter::range(0, CHUNK_SIZE) {
+                for k in std::iter::range(0, CHUNK_SIZE) {
+                    let Vec3 { x: x, y: y, z: z } = face.di.mul_s(i).add_v(&face.dj.mul_s(j)).add_v(&face.dk.mul_s(k));
+                    let block = &map.blocks[x][y][z];
+
+                    if !unmeshed_faces.contains(x, y, z) {
+                        continue;
+                    }
+
+                    let block_position = Vec3 {
+                        x: x as f32,
+                        y: y as f32,
+                        z: z as f32,
+                    };
+
+                    let dim = expand_face(map, &unmeshed_faces, face_shading, face,
+                                          Vec3 { x: x, y: y, z: z }, registry, merge_types);
+                    let dim_f = Vec3 { x: dim.x as f32, y: dim.y as f32, z: dim.z as f32 };
+
+                    for dx in range(0, dim.x) {
+                        for dy in range(0, dim.y) {
+                            for dz in range(0, dim.z) {
+                                unmeshed_faces.remove(x + dx, y + dy, z + dz);
+                            }
+                        }
+                    }
+
+                    let vertex_blocktype = if merge_types { -1.0 } else { block.blocktype as f32 };
+                    /* Every face in the quad is shaded the same as this one */
+                    let shading = face_shading[BlockBitmap::index(x, y, z)];
+                    let ao = (shading & 0xff) as u8;
+                    let front_light = (shading >> 8) as u8;
+                    let light_level = Vec2 {
+                        x: (front_light >> 4) as f32 / MAX_LIGHT as f32,
+                        y: (front_light & 0xf) as f32 / MAX_LIGHT as f32,
+                    };
+                    let vertex_offset = vertices.len();
+                    for (i, v) in face.vertices.iter().enumerate() {
+                        vertices.push(v.mul_v(&dim_f).add_v(&block_position).add_v(&chunk_position));
+                        normals.push(face.normal);
+                        blocktypes.push(vertex_blocktype);
+                        occlusion.push((3 - corner_light(ao, i)) as f32 / 3.0);
+                        light_levels.push(light_level);
+                    }
+
+                    /*
+                     * Split the quad along the diagonal through the darker
+                     * corners, so the occlusion interpolates symmetrically.
+                     */
+                    let quad_elements =
+                        if corner_light(ao, 0) + corner_light(ao, 3) < corner_light(ao, 1) + corner_light(ao, 2) {
+                            &flipped_face_elements
+                        } else {
+                            &face_elements
+                        };
+
+                    let transparent = registry.get(block.blocktype).transparent;
+                    for e in quad_elements.iter() {
+                        if transparent {
+                            transparent_elements.push(vertex_offset as u32 + *e);
+                        } else {
+                            elements.push(vertex_offset as u32 + *e);
+                        }
+                    }
+                }
+            }
+        }
+
+        face_ranges[face.index] = (num_elements_start, elements.len() - num_elements_start);
+    }
+
+    let mut materials = ~[];
+    if merge_types && !vertices.is_empty() {
+        materials.reserve(CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE);
+        for x in range(0, CHUNK_SIZE) {
+            for y in range(0, CHUNK_SIZE) {
+                for z in range(0, CHUNK_SIZE) {
+                    materials.push(map.blocks[x][y][z].blocktype);
+                }
+            }
+        }
+    }
+
+    let end_time = precise_time_ns();
+
+    println!("mesh gen : {}us; lod={}; vertices={}; elements={}; transparent={}",
+             (end_time - start_time)/1000, lod,
+             vertices.len(), elements.len(), transparent_elements.len())
+
+    ~MeshData {
+        vertices: vertices,
+        normals: normals,
+        blocktypes: blocktypes,
+        occlusion: occlusion,
+        light: light_levels,
+        elements: elements,
+        face_ranges: face_ranges,
+        transparent_elements: transparent_elements,
+        materials: materials,
+    }
+}
+
+/// Whether the face of a `blocktype` block looking at (x, y, z) is hidden.
+/// Opaque blocks hide any face. Transparent blocks only hide faces of the
+/// same type, so the inside of a body of water is not meshed but the seabed
+/// beneath it is.
+fn face_hidden(map: &Map, neighbours: &Neighbours, registry: &BlockRegistry,
+               blocktype: BlockId, x: int, y: int, z: int) -> bool {
+    let other = match map.index(x, y, z) {
+        Some(block) => block.blocktype,
+        None => match neighbours.block(x, y, z) {
+            Some(id) => id,
+            None => return false,
+        }
+    };
+
+    registry.is_opaque(other) || (other == blocktype && registry.get(blocktype).transparent)
+}
+
+/// Ambient occlusion of the four corners of the face of the block at
+/// (x, y, z), packed two bits per corner in the order of `face.vertices`.
+/// Each corner's value is the light reaching it: 3 when none of the three
+/// blocks touching it in front of the face is opaque, 0 when the two at
+/// its sides are.
+fn face_occlusion(map: &Map, neighbours: &Neighbours, registry: &BlockRegistry,
+                  face: &Face, x: uint, y: uint, z: uint) -> u8 {
+    let (fx, fy, fz) = (x as int + face.normal.x as int,
+                        y as int + face.normal.y as int,
+                        z as int + face.normal.z as int);
+    let (u, v) = (face.dj, face.dk);
+    let mut result = 0u8;
+
+    for (i, vertex) in face.vertices.iter().enumerate() {
+        let su = corner_sign(vertex, u);
+        let sv = corner_sign(vertex, v);
+        let side_u = occludes(map, neighbours, registry,
+                              fx + su * u.x as int, fy + su * u.y as int, fz + su * u.z as int);
+        let side_v = occludes(map, neighbours, registry,
+                              fx + sv * v.x as int, fy + sv * v.y as int, fz + sv * v.z as int);
+        let corner = occludes(map, neighbours, registry,
+                              fx + su * u.x as int + sv * v.x as int,
+                              fy + su * u.y as int + sv * v.y as int,
+                              fz + su * u.z as int + sv * v.z as int);
+
+        let light = if side_u && side_v {
+            0
+        } else {
+            let mut light = 3u8;
+            for &b in [side_u, side_v, corner].iter() {
+                if b {
+                    light -= 1;
+                }
+            }
+            light
+        };
+        result |= light << (2 * i);
+    }
+
+    result
+}
+
+/// Packed light levels at (x, y, z), in front of a face. Faces with no
+/// neighbour to look into are drawn in daylight until it arrives.
+fn face_light(light: &LightMap, neighbours: &Neighbours, x: int, y: int, z: int) -> u8 {
+    let size = CHUNK_SIZE as int;
+    if x >= 0 && x < size && y >= 0 && y < size && z >= 0 && z < size {
+        light.packed(x as uint, y as uint, z as uint)
+    } else {
+        neighbours.light(x, y, z).unwrap_or(DAYLIGHT)
+    }
+}
+
+/// Light at corner `i` of a face with packed occlusion `ao`.
+fn corner_light(ao: u8, i: uint) -> u8 {
+    (ao >> (2 * i)) & 3
+}
+
+/// Which way `vertex` lies from the centre of its face along `axis`.
+fn corner_sign(vertex: &Vec3<f32>, axis: Vec3<uint>) -> int {
+    let offset = vertex.x * axis.x as f32 + vertex.y * axis.y as f32 + vertex.z * axis.z as f32;
+    if offset > 0.5 { 1 } else { -1 }
+}
+
+/// Whether the block at (x, y, z) darkens the corners of faces next to it.
+/// Blocks diagonally across a chunk edge are not in `neighbours` and are
+/// treated as air.
+fn occludes(map: &Map, neighbours: &Neighbours, registry: &BlockRegistry,
+            x: int, y: int, z: int) -> bool {
+    let outside = |v: int| v < 0 || v >= CHUNK_SIZE as int;
+    let mut axes_outside = 0;
+    for &v in [x, y, z].iter() {
+        if outside(v) {
+            axes_outside += 1;
+        }
+    }
+
+    let blocktype = match map.index(x, y, z) {
+        Some(block) => block.blocktype,
+        None if axes_outside == 1 => match neighbours.block(x, y, z) {
+            Some(id) => id,
+            None => return false,
+        },
+        None => return false,
+    };
+
+    registry.is_opaque(blocktype)
+}
+
+fn expand_face(map : &Map,
+               unmeshed_faces : &BlockBitmap,
+               face_shading : &[u16],
+               face: &Face,
+               p: Vec3<uint>,
+               registry: &BlockRegistry,
+               merge_types: bool) -> Vec3<uint> {
+
+    let len_k = run_length(map, unmeshed_faces, face_shading, p, face.dk, registry, merge_types);
+    let len_j = range(0, len_k).
+        map(|k| run_length(map, unmeshed_faces, face_shading, p.add_v(&face.dk.mul_s(k)), face.dj,
+                           registry, merge_types)).
+        min().unwrap();
+
+    (Vec3 { x: 1, y: 1, z: 1 }).
+        add_v(&face.dk.mul_s(len_k - 1)).
+        add_v(&face.dj.mul_s(len_j - 1))
+}
+
+fn run_length(map : &Map,
+              unmeshed_faces : &BlockBitmap,
+              face_shading : &[u16],
+              mut p: Vec3<uint>,
+              dp: Vec3<uint>,
+              registry: &BlockRegistry,
+              merge_types: bool) -> uint {
+    let block = &map.blocks[p.x][p.y][p.z];
+    let shading = face_shading[BlockBitmap::index(p.x, p.y, p.z)];
+    let mut len = 1;
+
+    loop {
+        p.add_self_v(&dp);
+        if unmeshed_faces.contains(p.x, p.y, p.z) &&
+                face_shading[BlockBitmap::index(p.x, p.y, p.z)] == shading {
+            match map.index(p.x as int, p.y as int, p.z as int) {
+                Some(b) if can_merge(registry, merge_types, b.blocktype, block.blocktype) => {
+                    len += 1;
+                }
+                _ => {
+                    break;
+                }
+            }
+        } else {
+            break;
+        }
+    }
+
+    len
+}
+
+/// Whether faces of blocks `a` and `b` can share a quad. Opaque and
+/// transparent faces never do, since they are drawn in separate passes.
+fn can_merge(registry: &BlockRegistry, merge_types: bool, a: BlockId, b: BlockId) -> bool {
+    a == b || (merge_types && registry.get(a).transparent == registry.get(b).transparent)
+}
+
+struct BlockBitmap {
+    set : BitvSet
+}
+
+impl BlockBitmap {
+    pub fn new() -> BlockBitmap {
+        BlockBitmap {
+            set: BitvSet::new()
+        }
+    }
+
+    pub fn contains(&self, x: uint, y: uint, z: uint) -> bool {
+        self.set.contains(&BlockBitmap::index(x, y, z))
+    }
+
+    pub fn insert(&mut self, x: uint, y: uint, z: uint) {
+        self.set.insert(BlockBitmap::index(x, y, z));
+    }
+
+    pub fn remove(&mut self, x: uint, y: uint, z: uint) {
+        self.set.remove(&BlockBitmap::index(x, y, z));
+    }
+
+    fn index(x: uint, y: uint, z: uint) -> uint {
+        x*CHUNK_SIZE*CHUNK_SIZE + y*CHUNK_SIZE + z
+    }
+}
+
+static face_elements : [u32, ..6] = [
+    0, 1, 2, 3, 2, 1,
+];
+
+/// The same quad split along the other diagonal.
+static flipped_face_elements : [u32, ..6] = [
+    0, 1, 3, 0, 3, 2,
+];
+
+pub static faces : [Face, ..NUM_FACES] = [
+    /* front */
+    Face {
+        index: 0,
+        normal: Vec3 { x: 0.0, y: 0.0, z: 1.0 },
+        di: Vec3 { x: 0, y: 0, z: 1 },
+        dj: Vec3 { x: 1, y: 0, z: 0 },
+        dk: Vec3 { x: 0, y: 1, z: 0 },
+        vertices: [
+            Vec3 { x: 0.0, y: 0.0, z: 1.0 }, /* bottom left */
+            Vec3 { x: 1.0, y: 0.0, z: 1.0 },  /* bottom right */
+            Vec3 { x: 0.0, y: 1.0, z: 1.0 }, /* top left */
+            Vec3 { x: 1.0, y: 1.0, z: 1.0 },  /* top right */
+        ],
+    },
+
+    /* back */
+    Face {
+        index: 1,
+        normal: Vec3 { x: 0.0, y: 0.0, z: -1.0 },
+        di: Vec3 { x: 0, y: 0, z: 1 },
+        dj: Vec3 { x: 1, y: 0, z: 0 },
+        dk: Vec3 { x: 0, y: 1, z: 0 },
+        vertices: [
+            Vec3 { x: 1.0, y: 0.0, z: 0.0 }, /* bottom right */
+            Vec3 { x: 0.0, y: 0.0, z: 0.0 },  /* bottom left */
+            Vec3 { x: 1.0, y: 1.0, z: 0.0 }, /* top right */
+            Vec3 { x: 0.0, y: 1.0, z: 0.0 },  /* top left */
+        ],
+    },
+
+    /* right */
+    Face {
+        index: 2,
+        normal: Vec3 { x: 1.0, y: 0.0, z: 0.0 },
+        di: Vec3 { x: 1, y: 0, z: 0 },
+        dj: Vec3 { x: 0, y: 1, z: 0 },
+        dk: Vec3 { x: 0, y: 0, z: 1 },
+        vertices: [
+            Vec3 { x: 1.0, y: 0.0, z: 1.0 }, /* bottom front */
+            Vec3 { x: 1.0, y: 0.0, z: 0.0 }, /* bottom back */
+            Vec3 { x: 1.0, y: 1.0, z: 1.0 }, /* top front */
+            Vec3 { x: 1.0, y: 1.0, z: 0.0 }, /* top back */
+        ],
+    },
+
+    /* left */
+    Face {
+        index: 3,
+        normal: Vec3 { x: -1.0, y: 0.0, z: 0.0 },
+        di: Vec3 { x: 1, y: 0, z: 0 },
+        dj: Vec3 { x: 0, y: 1, z: 0 },
+        dk: Vec3 { x: 0, y: 0, z: 1 },
+        vertices: [
+            Vec3 { x: 0.0, y: 0.0, z: 0.0 }, /* bottom back */
+            Vec3 { x: 0.0, y: 0.0, z: 1.0 }, /* bottom front */
+            Vec3 { x: 0.0, y: 1.0, z: 0.0 }, /* top back */
+            Vec3 { x: 0.0, y: 1.0, z: 1.0 }, /* top front */
+        ],
+    },
+
+    /* top */
+    Face {
+        index: 4,
+        normal: Vec3 { x: 0.0, y: 1.0, z: 0.0 },
+        di: Vec3 { x: 0, y: 1, z: 0 },
+        dj: Vec3 { x: 1, y: 0, z: 0 },
+        dk: Vec3 { x: 0, y: 0, z: 1 },
+        vertices: [
+            Vec3 { x: 0.0, y: 1.0, z: 1.0 }, /* front left */
+            Vec3 { x: 1.0, y: 1.0, z: 1.0 }, /* front right */
+            Vec3 { x: 0.0, y: 1.0, z: 0.0 }, /* back left */
+            Vec3 { x: 1.0, y: 1.0, z: 0.0 }, /* back right */
+        ],
+    },
+
+    /* bottom */
+    Face {
+        index: 5,
+        normal: Vec3 { x: 0.0, y: -1.0, z: 0.0 },
+        di: Vec3 { x: 0, y: 1, z: 0 },
+        dj: Vec3 { x: 1, y: 0, z: 0 },
+        dk: Vec3 { x: 0, y: 0, z: 1 },
+        vertices: [
+            Vec3 { x: 0.0, y: 0.0, z: 0.0 }, /* back left */
+            Vec3 { x: 1.0, y: 0.0, z: 0.0 }, /* back right */
+            Vec3 { x: 0.0, y: 0.0, z: 1.0 }, /* front left */
+            Vec3 { x: 1.0, y: 0.0, z: 1.0 }, /* front right */
+        ],
+    },
+];
+
+#[cfg(test)]
+static GRASS : u8 = 1;
+#[cfg(test)]
+static STONE : u8 = 2;
+#[cfg(test)]
+static WATER : u8 = 4;
+
+#[cfg(test)]
+fn num_quads(mesh: &MeshData) -> uint {
+    assert_eq!(mesh.vertices.len() % 4, 0);
+    assert_eq!(mesh.elements.len() + mesh.transparent_elements.len(),
+               mesh.vertices.len() / 4 * face_elements.len());
+    mesh.vertices.len() / 4
+}
+
+#[test]
+fn test_mesh_empty() {
+    let map = Map::new();
+    let mesh = build(0, 0, 0, map, LightMap::new(), 0, &Neighbours::new(), &test_registry(), false);
+    assert_eq!(num_quads(mesh), 0);
+    for &(_, count) in mesh.face_ranges.iter() {
+        assert_eq!(count, 0);
+    }
+}
+
+#[test]
+fn test_mesh_single_block() {
+    let mut map = Map::new();
+    map.blocks[1][2][3] = Block { blocktype: STONE };
+    let mesh = build(32, 64, -32, map, LightMap::new(), 0, &Neighbours::new(), &test_registry(), false);
+    assert_eq!(num_quads(mesh), 6);
+
+    let mut offset = 0;
+    for face in faces.iter() {
+        assert_eq!(mesh.face_ranges[face.index], (offset, face_elements.len()));
+        offset += face_elements.len();
+    }
+
+    for v in mesh.vertices.iter() {
+        assert!(v.x == 33.0 || v.x == 34.0);
+        assert!(v.y == 66.0 || v.y == 67.0);
+        assert!(v.z == -29.0 || v.z == -28.0);
+    }
+}
+
+#[test]
+fn test_mesh_merges_same_type() {
+    let mut map = Map::new();
+    for x in range(0, 4) {
+        for z in range(0, 3) {
+            map.blocks[x][0][z] = Block { blocktype: STONE };
+        }
+    }
+    let mesh = build(0, 0, 0, map, LightMap::new(), 0, &Neighbours::new(), &test_registry(), false);
+    assert_eq!(num_quads(mesh), 6);
+}
+
+#[test]
+fn test_mesh_splits_different_types() {
+    let mut map = Map::new();
+    map.blocks[0][0][0] = Block { blocktype: STONE };
+    map.blocks[1][0][0] = Block { blocktype: GRASS };
+    let mesh = build(0, 0, 0, map, LightMap::new(), 0, &Neighbours::new(), &test_registry(), false);
+    /* Shared face is culled; the four faces spanning both blocks split */
+    assert_eq!(num_quads(mesh), 10);
+}
+
+#[test]
+fn test_mesh_culls_against_neighbours() {
+    let registry = test_registry();
+    let mut map = Map::new();
+    map.blocks[CHUNK_SIZE-1][0][0] = Block { blocktype: STONE };
+
+    let mut neighbour = Map::new();
+    neighbour.blocks[0][0][0] = Block { blocktype: STONE };
+
+    let mut neighbours = Neighbours::new();
+    assert!(!neighbours.any_blocks());
+    neighbours.set(&faces[2], neighbour, LightMap::new(), 0);
+    assert!(neighbours.any_blocks());
+
+    let mesh = build(0, 0, 0, map, LightMap::new(), 0, &neighbours, &registry, false);
+    assert_eq!(num_quads(mesh), 5);
+    assert_eq!(mesh.face_ranges[2], (2 * face_elements.len(), 0));
+
+    /* A neighbour that is air in the touching layer hides nothing */
+    let mut neighbours = Neighbours::new();
+    neighbours.set(&faces[2], Map::new(), LightMap::new(), 0);
+    assert_eq!(num_quads(build(0, 0, 0, map, LightMap::new(), 0, &neighbours, &registry, false)), 6);
+}
+
+#[test]
+fn test_mesh_transparent() {
+    let registry = test_registry();
+    let mut map = Map::new();
+    map.blocks[0][0][0] = Block { blocktype: STONE };
+    map.blocks[0][1][0] = Block { blocktype: WATER };
+    map.blocks[0][2][0] = Block { blocktype: WATER };
+    let mesh = build(0, 0, 0, map, LightMap::new(), 0, &Neighbours::new(), &registry, false);
+
+    /* The stone's top face is visible through the water */
+    assert_eq!(mesh.elements.len(), 6 * face_elements.len());
+    /* The two water blocks merge into one box, whose bottom the stone hides */
+    assert_eq!(mesh.transparent_elements.len(), 5 * face_elements.len());
+
+    /* Water meets water across the chunk border without a face */
+    let mut neighbour = Map::new();
+    neighbour.blocks[CHUNK_SIZE-1][1][0] = Block { blocktype: WATER };
+    neighbour.blocks[CHUNK_SIZE-1][2][0] = Block { blocktype: WATER };
+    let mut neighbours = Neighbours::new();
+    neighbours.set(&faces[3], neighbour, LightMap::new(), 0);
+    let mesh = build(0, 0, 0, map, LightMap::new(), 0, &neighbours, &registry, false);
+    assert_eq!(mesh.transparent_elements.len(), 4 * face_elements.len());
+}
+
+#[test]
+fn test_mesh_downsample() {
+    let mut map = Map::new();
+    /* Half of the first 2x2x2 cube is stone or grass, with stone the majority */
+    map.blocks[0][0][0] = Block { blocktype: STONE };
+    map.blocks[1][0][0] = Block { blocktype: STONE };
+    map.blocks[0][0][1] = Block { blocktype: STONE };
+    map.blocks[1][0][1] = Block { blocktype: GRASS };
+    /* A lone block in the next cube is dropped */
+    map.blocks[2][0][0] = Block { blocktype: GRASS };
+
+    let coarse = downsample(map, 1);
+    for x in range(0, 2) {
+        for y in range(0, 2) {
+            for z in range(0, 2) {
+                assert_eq!(coarse.blocks[x][y][z].blocktype, STONE);
+            }
+        }
+    }
+    assert_eq!(coarse.blocks[2][0][0].blocktype, AIR);
+}
+
+#[test]
+fn test_mesh_lod() {
+    let registry = test_registry();
+
+    /* Terrain with a step at every column */
+    let mut map = Map::new();
+    for x in range(0, CHUNK_SIZE) {
+        for z in range(0, CHUNK_SIZE) {
+            for y in range(0, 4 + (x + z) % 8) {
+                map.blocks[x][y][z] = Block { blocktype: STONE };
+            }
+        }
+    }
+
+    let full = build(0, 0, 0, map, LightMap::new(), 0, &Neighbours::new(), &registry, false);
+    let coarse = build(0, 0, 0, map, LightMap::new(), 2, &Neighbours::new(), &registry, false);
+    assert!(num_quads(coarse) * 4 < num_quads(full));
+
+    /* Coarse quads lie on the 4 block grid */
+    for v in coarse.vertices.iter() {
+        assert_eq!(v.x % 4.0, 0.0);
+        assert_eq!(v.y % 4.0, 0.0);
+        assert_eq!(v.z % 4.0, 0.0);
+    }
+}
+
+#[test]
+fn test_mesh_merge_types() {
+    let registry = test_registry();
+    let mut map = Map::new();
+    map.blocks[0][0][0] = Block { blocktype: STONE };
+    map.blocks[1][0][0] = Block { blocktype: GRASS };
+    map.blocks[2][0][0] = Block { blocktype: WATER };
+
+    /* Stone and grass form one box, whose end shows through the water */
+    let mesh = build(0, 0, 0, map, LightMap::new(), 0, &Neighbours::new(), &registry, true);
+    assert_eq!(mesh.elements.len(), 6 * face_elements.len());
+    assert_eq!(mesh.transparent_elements.len(), 5 * face_elements.len());
+    assert!(mesh.blocktypes.iter().all(|&t| t == -1.0));
+
+    assert_eq!(mesh.materials.len(), CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE);
+    assert_eq!(mesh.materials[1 * CHUNK_SIZE * CHUNK_SIZE], GRASS);
+    assert_eq!(mesh.materials[2 * CHUNK_SIZE * CHUNK_SIZE], WATER);
+
+    let mesh = build(0, 0, 0, map, LightMap::new(), 0, &Neighbours::new(), &registry, false);
+    assert_eq!(mesh.elements.len(), 10 * face_elements.len());
+    assert!(mesh.materials.is_empty());
+}
+
+#[test]
+fn test_mesh_ambient_occlusion() {
+    let registry = test_registry();
+
+    /* A row of four blocks along z */
+    let mut map = Map::new();
+    for z in range(0, 4) {
+        map.blocks[0][0][z] = Block { blocktype: STONE };
+    }
+    let mesh = build(0, 0, 0, map, LightMap::new(), 0, &Neighbours::new(), &registry, false);
+    let (_, top_elements) = mesh.face_ranges[4];
+    assert_eq!(top_elements, face_elements.len());
+    assert!(mesh.occlusion.iter().all(|&o| o == 0.0));
+
+    /*
+     * A block resting on the row's edge darkens the corners of the first
+     * two tops, which no longer merge with the others.
+     */
+    map.blocks[1][1][0] = Block { blocktype: STONE };
+    let mesh = build(0, 0, 0, map, LightMap::new(), 0, &Neighbours::new(), &registry, false);
+    let (_, top_elements) = mesh.face_ranges[4];
+    assert_eq!(top_elements, 4 * face_elements.len());
+    assert_eq!(mesh.occlusion.len(), mesh.vertices.len());
+    assert!(mesh.occlusion.iter().any(|&o| o == 1.0 / 3.0));
+    assert!(mesh.occlusion.iter().all(|&o| o >= 0.0 && o <= 1.0));
+
+    /* Corners between two opaque blocks are fully occluded */
+    map.blocks[0][1][1] = Block { blocktype: STONE };
+    let mesh = build(0, 0, 0, map, LightMap::new(), 0, &Neighbours::new(), &registry, false);
+    assert!(mesh.occlusion.iter().any(|&o| o == 1.0));
+
+    /* Coarse meshes are not occluded */
+    let mesh = build(0, 0, 0, map, LightMap::new(), 1, &Neighbours::new(), &registry, false);
+    assert!(mesh.occlusion.iter().all(|&o| o == 0.0));
+}
+
+#[test]
+fn test_mesh_light() {
+    let registry = test_registry();
+    let mut map = Map::new();
+    for z in range(0, 4) {
+        map.blocks[1][0][z] = Block { blocktype: STONE };
+    }
+
+    let mut light = LightMap::new();
+    for x in range(0, CHUNK_SIZE) {
+        for y in range(1, CHUNK_SIZE) {
+            for z in range(0, CHUNK_SIZE) {
+                light.set(SkyLight, x, y, z, MAX_LIGHT);
+            }
+        }
+    }
+    let mesh = build(0, 0, 0, map, light, 0, &Neighbours::new(), &registry, false);
+    let (_, top_elements) = mesh.face_ranges[4];
+    assert_eq!(top_elements, face_elements.len());
+    assert!(mesh.light.iter().any(|l| l.x == 1.0 && l.y == 0.0));
+
+    /* A shadow over the first block's top splits the quad */
+    light.set(SkyLight, 1, 1, 0, 5);
+    light.set(BlockLight, 1, 1, 0, 3);
+    let mesh = build(0, 0, 0, map, light, 0, &Neighbours::new(), &registry, false);
+    let (_, top_elements) = mesh.face_ranges[4];
+    assert_eq!(top_elements, 2 * face_elements.len());
+    assert_eq!(mesh.light.len(), mesh.vertices.len());
+    assert!(mesh.light.iter().any(|l| l.x == 5.0 / 15.0 && l.y == 3.0 / 15.0));
+}
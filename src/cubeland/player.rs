@@ -0,0 +1,224 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern mod cgmath;
+
+use cgmath::vector::Vector;
+use cgmath::vector::Vec3;
+
+/// Bounding box dimensions, in blocks
+static HALF_WIDTH : f32 = 0.3;
+static HEIGHT : f32 = 1.8;
+static EYE_HEIGHT : f32 = 1.6;
+
+/// Speeds in blocks per second, gravity in blocks per second squared
+static WALK_SPEED : f32 = 4.5;
+static JUMP_SPEED : f32 = 8.5;
+static GRAVITY : f32 = 30.0;
+static TERMINAL_VELOCITY : f32 = 60.0;
+
+/// Longest time step simulated at once, so a slow frame doesn't launch the
+/// player through a floor.
+static MAX_TICK : f32 = 0.1;
+
+/// Slack used when comparing the box against block boundaries, so a box
+/// resting exactly against a block is not counted as overlapping it.
+static EPSILON : f32 = 0.001;
+
+pub struct Player {
+    /// Centre of the bottom face of the bounding box
+    position: Vec3<f32>,
+    velocity: Vec3<f32>,
+    on_ground: bool,
+    /// Flying players ignore gravity and pass through blocks
+    flying: bool,
+}
+
+impl Player {
+    pub fn new(eye_position: Vec3<f32>) -> Player {
+        Player {
+            position: eye_position.sub_v(&Vec3::new(0.0f32, EYE_HEIGHT, 0.0f32)),
+            velocity: Vec3::new(0.0f32, 0.0f32, 0.0f32),
+            on_ground: false,
+            flying: false,
+        }
+    }
+
+    pub fn eye_position(&self) -> Vec3<f32> {
+        self.position.add_v(&Vec3::new(0.0f32, EYE_HEIGHT, 0.0f32))
+    }
+
+    pub fn toggle_flying(&mut self) {
+        self.flying = !self.flying;
+        self.velocity = Vec3::new(0.0f32, 0.0f32, 0.0f32);
+        self.on_ground = false;
+    }
+
+    /// Returns true if the bounding box overlaps the block at the given
+    /// world coordinates, so placing a solid block there would trap the
+    /// player.
+    pub fn overlaps(&self, x: i64, y: i64, z: i64) -> bool {
+        let lo = [self.position.x - HALF_WIDTH, self.position.y, self.position.z - HALF_WIDTH];
+        let hi = [self.position.x + HALF_WIDTH, self.position.y + HEIGHT, self.position.z + HALF_WIDTH];
+        let block = [x as f32, y as f32, z as f32];
+        range(0u, 3).all(|i| lo[i] + EPSILON < block[i] + 1.0 && hi[i] - EPSILON > block[i])
+    }
+
+    /// Moves a flying player by `delta` without collision.
+    pub fn fly(&mut self, delta: &Vec3<f32>) {
+        self.position.add_self_v(delta);
+    }
+
+    /// Advances a walking player by `dt` seconds. `direction` is the
+    /// horizontal direction of movement in world space, with unit length
+    /// for full speed. `is_solid` is called with the world coordinates of
+    /// each block the bounding box could move into.
+    pub fn walk(&mut self, dt: f32, direction: &Vec3<f32>, jump: bool,
+                is_solid: |i64, i64, i64| -> bool) {
+        let dt = dt.min(MAX_TICK);
+
+        self.velocity.x = direction.x * WALK_SPEED;
+        self.velocity.z = direction.z * WALK_SPEED;
+
+        if jump && self.on_ground {
+            self.velocity.y = JUMP_SPEED;
+        }
+
+        self.velocity.y = (self.velocity.y - GRAVITY * dt).max(-TERMINAL_VELOCITY);
+
+        let mut pos = [self.position.x, self.position.y, self.position.z];
+        let delta = [self.velocity.x * dt, self.velocity.y * dt, self.velocity.z * dt];
+
+        self.on_ground = false;
+
+        /* One axis at a time, vertical first so landing is resolved before sliding */
+        for &axis in [1u, 0, 2].iter() {
+            if delta[axis] == 0.0 {
+                continue;
+            }
+
+            let lo = [pos[0] - HALF_WIDTH, pos[1], pos[2] - HALF_WIDTH];
+            let hi = [pos[0] + HALF_WIDTH, pos[1] + HEIGHT, pos[2] + HALF_WIDTH];
+            let allowed = sweep(axis, lo, hi, delta[axis], |x, y, z| is_solid(x, y, z));
+            pos[axis] += allowed;
+
+            if allowed != delta[axis] {
+                match axis {
+                    0 => self.velocity.x = 0.0,
+                    1 => self.velocity.y = 0.0,
+                    _ => self.velocity.z = 0.0,
+                }
+                if axis == 1 && delta[axis] < 0.0 {
+                    self.on_ground = true;
+                }
+            }
+        }
+
+        self.position = Vec3::new(pos[0], pos[1], pos[2]);
+    }
+}
+
+/// Returns how far the box from `lo` to `hi` can move by `d` along `axis`
+/// before entering a solid block. Blocks the box already overlaps are
+/// ignored, so a player stuck inside terrain can still walk out.
+fn sweep(axis: uint, lo: [f32, ..3], hi: [f32, ..3], d: f32,
+         is_solid: |i64, i64, i64| -> bool) -> f32 {
+    let (a, b) = match axis {
+        0 => (1, 2),
+        1 => (0, 2),
+        _ => (0, 1),
+    };
+
+    /* Blocks the box covers on the two other axes */
+    let a_start = (lo[a] + EPSILON).floor() as i64;
+    let a_end = (hi[a] - EPSILON).ceil() as i64;
+    let b_start = (lo[b] + EPSILON).floor() as i64;
+    let b_end = (hi[b] - EPSILON).ceil() as i64;
+
+    /* Layers of blocks entered along the axis, nearest first */
+    let (first, last, step) = if d > 0.0 {
+        ((hi[axis] - EPSILON).ceil() as i64, (hi[axis] + d).ceil() as i64 - 1, 1)
+    } else {
+        ((lo[axis] + EPSILON).floor() as i64 - 1, (lo[axis] + d).floor() as i64, -1)
+    };
+
+    let mut i = first;
+    while (last - i) * step >= 0 {
+        let mut solid = false;
+        for j in range(a_start, a_end) {
+            for k in range(b_start, b_end) {
+                let mut c = [0i64, ..3];
+                c[axis] = i;
+                c[a] = j;
+                c[b] = k;
+                solid = solid || is_solid(c[0], c[1], c[2]);
+            }
+        }
+
+        if solid {
+            return if d > 0.0 {
+                (i as f32 - hi[axis]).max(0.0)
+            } else {
+                ((i + 1) as f32 - lo[axis]).min(0.0)
+            };
+        }
+
+        i += step;
+    }
+
+    d
+}
+
+#[test]
+fn test_player_lands_on_floor() {
+    let mut player = Player::new(Vec3::new(0.5f32, 5.0f32 + EYE_HEIGHT, 0.5f32));
+    for _ in range(0, 100) {
+        player.walk(0.05, &Vec3::new(0.0f32, 0.0f32, 0.0f32), false, |_, y, _| y < 2);
+    }
+    assert!(player.on_ground);
+    assert!((player.position.y - 2.0).abs() < EPSILON);
+
+    player.walk(0.05, &Vec3::new(0.0f32, 0.0f32, 0.0f32), true, |_, y, _| y < 2);
+    assert!(!player.on_ground);
+    assert!(player.position.y > 2.0);
+}
+
+#[test]
+fn test_player_blocked_by_wall() {
+    let mut player = Player::new(Vec3::new(0.5f32, EYE_HEIGHT, 0.5f32));
+    let is_solid = |x: i64, y: i64, _: i64| y < 0 || x >= 3;
+    for _ in range(0, 100) {
+        player.walk(0.05, &Vec3::new(1.0f32, 0.0f32, 0.0f32), false, |x, y, z| is_solid(x, y, z));
+    }
+    assert!(player.on_ground);
+    assert!((player.position.x - (3.0 - HALF_WIDTH)).abs() < EPSILON);
+
+    /* Flying passes straight through */
+    player.toggle_flying();
+    player.fly(&Vec3::new(5.0f32, 0.0f32, 0.0f32));
+    assert!(player.position.x > 3.0);
+}
+
+#[test]
+fn test_player_overlaps() {
+    let player = Player::new(Vec3::new(0.5f32, 2.0f32 + EYE_HEIGHT, 0.5f32));
+    assert!(player.overlaps(0, 2, 0));
+    assert!(player.overlaps(0, 3, 0));
+    assert!(!player.overlaps(0, 4, 0));
+
+    /* Standing on a block or beside one is not overlapping it */
+    assert!(!player.overlaps(0, 1, 0));
+    assert!(!player.overlaps(1, 2, 0));
+    assert!(!player.overlaps(0, 2, -1));
+}
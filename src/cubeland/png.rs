@@ -0,0 +1,292 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Just enough PNG to write screenshots and read them back as golden images:
+//! 8-bit RGB or RGBA, without interlacing.
+
+extern mod extra;
+
+use std::path::Path;
+use std::vec;
+
+use extra::flate;
+
+use {read_file, write_file, inflate};
+
+static SIGNATURE : [u8, ..8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+/// An RGBA image, top row first.
+pub struct Image {
+    width: uint,
+    height: uint,
+    pixels: ~[u8],
+}
+
+impl Image {
+    /// Number of pixels where some channel differs by more than `tolerance`.
+    pub fn count_differences(&self, other: &Image, tolerance: u8) -> uint {
+        assert_eq!((self.width, self.height), (other.width, other.height));
+        let mut count = 0;
+        for i in range(0, self.width * self.height) {
+            let differs = range(0u, 4).any(|c| {
+                let a = self.pixels[i * 4 + c] as int;
+                let b = other.pixels[i * 4 + c] as int;
+                (a - b).abs() > tolerance as int
+            });
+            if differs {
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+pub fn save(path: &Path, image: &Image) -> Result<(), ~str> {
+    write_file(path, encode(image))
+}
+
+pub fn load(path: &Path) -> Result<Image, ~str> {
+    if !path.exists() {
+        return Err(format!("{} not found", path.display()));
+    }
+
+    match read_file(path) {
+        Ok(data) => match decode(data) {
+            Ok(image) => Ok(image),
+            Err(msg) => Err(format!("{}: {}", path.display(), msg)),
+        },
+        Err(msg) => Err(msg),
+    }
+}
+
+pub fn encode(image: &Image) -> ~[u8] {
+    let mut buf = SIGNATURE.to_owned();
+
+    let mut header = ~[];
+    push_u32(&mut header, image.width as u32);
+    push_u32(&mut header, image.height as u32);
+    header.push_all([8, 6, 0, 0, 0]); /* depth, RGBA, compression, filter, interlace */
+    push_chunk(&mut buf, "IHDR", header);
+
+    /* Each row is prefixed by its filter type, always none */
+    let stride = image.width * 4;
+    let mut raw = vec::with_capacity((stride + 1) * image.height);
+    for y in range(0, image.height) {
+        raw.push(0u8);
+        raw.push_all(image.pixels.slice(y * stride, (y + 1) * stride));
+    }
+    push_chunk(&mut buf, "IDAT", zlib_compress(raw));
+
+    push_chunk(&mut buf, "IEND", []);
+    buf
+}
+
+pub fn decode(data: &[u8]) -> Result<Image, ~str> {
+    if data.len() < SIGNATURE.len() || data.slice_to(SIGNATURE.len()) != SIGNATURE.slice_from(0) {
+        return Err(~"not a PNG file");
+    }
+
+    let mut pos = SIGNATURE.len();
+    let mut header = None;
+    let mut compressed = ~[];
+
+    loop {
+        if pos + 12 > data.len() {
+            return Err(~"truncated chunk");
+        }
+        let len = read_u32(data, pos) as uint;
+        let kind = data.slice(pos + 4, pos + 8);
+        if pos + 12 + len > data.len() {
+            return Err(~"truncated chunk");
+        }
+        let body = data.slice(pos + 8, pos + 8 + len);
+        if read_u32(data, pos + 8 + len) != crc32(data.slice(pos + 4, pos + 8 + len)) {
+            return Err(~"bad chunk checksum");
+        }
+        pos += 12 + len;
+
+        match kind {
+            b if b == bytes!("IHDR") => {
+                if len != 13 {
+                    return Err(~"bad IHDR chunk");
+                }
+                header = Some((read_u32(body, 0) as uint, read_u32(body, 4) as uint,
+                               body[8], body[9], body[12]));
+            },
+            b if b == bytes!("IDAT") => compressed.push_all(body),
+            b if b == bytes!("IEND") => break,
+            _ => {}
+        }
+    }
+
+    let (width, height, channels) = match header {
+        Some((w, h, 8, 6, 0)) => (w, h, 4),
+        Some((w, h, 8, 2, 0)) => (w, h, 3),
+        Some(_) => return Err(~"only 8-bit RGB and RGBA images are supported"),
+        None => return Err(~"missing IHDR chunk"),
+    };
+
+    let raw = match zlib_decompress(compressed) {
+        Some(raw) => raw,
+        None => return Err(~"bad image data"),
+    };
+
+    let stride = width * channels;
+    if raw.len() != (stride + 1) * height {
+        return Err(format!("image data is {} bytes, expected {}", raw.len(), (stride + 1) * height));
+    }
+
+    let mut pixels = vec::with_capacity(width * height * 4);
+    let mut prev = vec::from_elem(stride, 0u8);
+    for y in range(0, height) {
+        let start = y * (stride + 1);
+        let row = match unfilter(raw[start], raw.slice(start + 1, start + 1 + stride), prev, channels) {
+            Some(row) => row,
+            None => return Err(format!("unknown filter type {}", raw[start])),
+        };
+        for x in range(0, width) {
+            pixels.push_all(row.slice(x * channels, x * channels + 3));
+            pixels.push(if channels == 4 { row[x * channels + 3] } else { 255 });
+        }
+        prev = row;
+    }
+
+    Ok(Image { width: width, height: height, pixels: pixels })
+}
+
+/// Reverses the PNG scanline filter `filter` given the previous row.
+fn unfilter(filter: u8, row: &[u8], prev: &[u8], bpp: uint) -> Option<~[u8]> {
+    let mut out = vec::with_capacity(row.len());
+    for i in range(0, row.len()) {
+        let a = if i >= bpp { out[i - bpp] as int } else { 0 };
+        let b = prev[i] as int;
+        let c = if i >= bpp { prev[i - bpp] as int } else { 0 };
+        let predictor = match filter {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => (a + b) / 2,
+            4 => {
+                let p = a + b - c;
+                let (pa, pb, pc) = ((p - a).abs(), (p - b).abs(), (p - c).abs());
+                if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+            },
+            _ => return None,
+        };
+        out.push((row[i] as int + predictor) as u8);
+    }
+    Some(out)
+}
+
+fn push_chunk(buf: &mut ~[u8], kind: &str, body: &[u8]) {
+    push_u32(buf, body.len() as u32);
+    let start = buf.len();
+    buf.push_all(kind.as_bytes());
+    buf.push_all(body);
+    let crc = crc32(buf.slice_from(start));
+    push_u32(buf, crc);
+}
+
+/// PNG wants a zlib stream; flate only produces raw deflate data.
+fn zlib_compress(data: &[u8]) -> ~[u8] {
+    let mut buf = ~[0x78u8, 0x01];
+    buf.push_all(flate::deflate_bytes(data));
+    push_u32(&mut buf, adler32(data));
+    buf
+}
+
+fn zlib_decompress(data: &[u8]) -> Option<~[u8]> {
+    if data.len() < 6 || data[0] & 0x0f != 8 || data[1] & 0x20 != 0 {
+        return None;
+    }
+    let raw = match inflate(data.slice(2, data.len() - 4)) {
+        Ok(raw) => raw,
+        Err(_) => return None,
+    };
+    if adler32(raw) != read_u32(data, data.len() - 4) {
+        return None;
+    }
+    Some(raw)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &byte in data.iter() {
+        crc ^= byte as u32;
+        for _ in range(0, 8) {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for &byte in data.iter() {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+/// PNG integers are big-endian.
+fn push_u32(buf: &mut ~[u8], v: u32) {
+    buf.push((v >> 24) as u8);
+    buf.push((v >> 16) as u8);
+    buf.push((v >> 8) as u8);
+    buf.push(v as u8);
+}
+
+fn read_u32(buf: &[u8], offset: uint) -> u32 {
+    (buf[offset] as u32 << 24) |
+        (buf[offset + 1] as u32 << 16) |
+        (buf[offset + 2] as u32 << 8) |
+        buf[offset + 3] as u32
+}
+
+#[test]
+fn test_png_checksums() {
+    assert_eq!(crc32(bytes!("IEND")), 0xae426082);
+    assert_eq!(adler32(bytes!("Wikipedia")), 0x11e60398);
+}
+
+#[test]
+fn test_png_round_trip() {
+    let pixels = vec::from_fn(3 * 2 * 4, |i| (i * 11) as u8);
+    let image = Image { width: 3, height: 2, pixels: pixels };
+    let decoded = decode(encode(&image)).unwrap();
+    assert_eq!((decoded.width, decoded.height), (3, 2));
+    assert_eq!(decoded.pixels, image.pixels);
+    assert_eq!(image.count_differences(&decoded, 0), 0);
+
+    let mut other = Image { width: 3, height: 2, pixels: image.pixels.clone() };
+    other.pixels[5] += 3;
+    assert_eq!(image.count_differences(&other, 2), 1);
+    assert_eq!(image.count_differences(&other, 3), 0);
+
+    let mut corrupt = encode(&image);
+    corrupt[20] ^= 1;
+    assert!(decode(corrupt).is_err());
+    assert!(decode(bytes!("GIF89a")).is_err());
+}
+
+#[test]
+fn test_png_unfilter() {
+    /* Sub, Up and Paeth against a known previous row, 1 byte per pixel */
+    assert_eq!(unfilter(1, [1, 2, 3], [0, 0, 0], 1), Some(~[1u8, 3, 6]));
+    assert_eq!(unfilter(2, [1, 2, 3], [10, 20, 30], 1), Some(~[11u8, 22, 33]));
+    assert_eq!(unfilter(4, [1, 1], [5, 9], 1), Some(~[6u8, 10]));
+    assert!(unfilter(5, [0], [0], 1).is_none());
+}
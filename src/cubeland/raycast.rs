@@ -0,0 +1,136 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern mod cgmath;
+
+use cgmath::vector::Vec3;
+
+pub struct RaycastHit {
+    /// World coordinates of the block that was hit
+    block: Vec3<i64>,
+    /// Normal of the face the ray entered through
+    normal: Vec3<i64>,
+}
+
+impl RaycastHit {
+    /// The empty block adjacent to the hit face, or None if the ray started
+    /// inside the hit block and so entered through no face
+    pub fn adjacent(&self) -> Option<Vec3<i64>> {
+        if self.normal == Vec3::new(0i64, 0i64, 0i64) {
+            return None;
+        }
+        Some(Vec3 {
+            x: self.block.x + self.normal.x,
+            y: self.block.y + self.normal.y,
+            z: self.block.z + self.normal.z,
+        })
+    }
+}
+
+/// Walks the voxel grid along a ray (Amanatides & Woo) and returns the first
+/// block for which `is_solid` returns true, or None if nothing is hit within
+/// `max_distance`.
+pub fn raycast(origin: &Vec3<f32>,
+               direction: &Vec3<f32>,
+               max_distance: f32,
+               is_solid: |i64, i64, i64| -> bool) -> Option<RaycastHit> {
+    let origin = [origin.x as f64, origin.y as f64, origin.z as f64];
+    let direction = [direction.x as f64, direction.y as f64, direction.z as f64];
+
+    let length = (direction[0] * direction[0] +
+                  direction[1] * direction[1] +
+                  direction[2] * direction[2]).sqrt();
+    if length == 0.0 {
+        return None;
+    }
+
+    let mut pos = [0i64, ..3];
+    let mut step = [0i64, ..3];
+    let mut t_max = [0.0f64, ..3];
+    let mut t_delta = [0.0f64, ..3];
+
+    for i in range(0, 3) {
+        let d = direction[i] / length;
+        pos[i] = origin[i].floor() as i64;
+        if d > 0.0 {
+            step[i] = 1;
+            t_max[i] = ((pos[i] + 1) as f64 - origin[i]) / d;
+            t_delta[i] = 1.0 / d;
+        } else if d < 0.0 {
+            step[i] = -1;
+            t_max[i] = (pos[i] as f64 - origin[i]) / d;
+            t_delta[i] = -1.0 / d;
+        } else {
+            step[i] = 0;
+            t_max[i] = Bounded::max_value();
+            t_delta[i] = Bounded::max_value();
+        }
+    }
+
+    let mut normal = [0i64, ..3];
+    let mut t = 0.0f64;
+
+    while t <= max_distance as f64 {
+        if is_solid(pos[0], pos[1], pos[2]) {
+            return Some(RaycastHit {
+                block: Vec3 { x: pos[0], y: pos[1], z: pos[2] },
+                normal: Vec3 { x: normal[0], y: normal[1], z: normal[2] },
+            });
+        }
+
+        let axis = if t_max[0] < t_max[1] {
+            if t_max[0] < t_max[2] { 0 } else { 2 }
+        } else {
+            if t_max[1] < t_max[2] { 1 } else { 2 }
+        };
+
+        t = t_max[axis];
+        t_max[axis] += t_delta[axis];
+        pos[axis] += step[axis];
+        normal = [0, 0, 0];
+        normal[axis] = -step[axis];
+    }
+
+    None
+}
+
+#[test]
+fn test_raycast() {
+    let origin = Vec3::new(0.5f32, 0.5f32, 0.5f32);
+
+    let hit = raycast(&origin, &Vec3::new(1.0f32, 0.0f32, 0.0f32), 10.0,
+                      |x, y, z| x == 3 && y == 0 && z == 0).unwrap();
+    assert_eq!(hit.block, Vec3::new(3i64, 0i64, 0i64));
+    assert_eq!(hit.normal, Vec3::new(-1i64, 0i64, 0i64));
+    assert_eq!(hit.adjacent(), Some(Vec3::new(2i64, 0i64, 0i64)));
+
+    let hit = raycast(&origin, &Vec3::new(0.0f32, -1.0f32, 0.0f32), 10.0,
+                      |_, y, _| y < -4).unwrap();
+    assert_eq!(hit.block, Vec3::new(0i64, -5i64, 0i64));
+    assert_eq!(hit.normal, Vec3::new(0i64, 1i64, 0i64));
+
+    assert!(raycast(&origin, &Vec3::new(0.0f32, 0.0f32, 1.0f32), 10.0,
+                    |_, _, z| z > 20).is_none());
+}
+
+#[test]
+fn test_raycast_inside_block() {
+    let origin = Vec3::new(0.5f32, 0.5f32, 0.5f32);
+
+    let hit = raycast(&origin, &Vec3::new(1.0f32, 0.0f32, 0.0f32), 10.0,
+                      |x, y, z| x >= 0 && y == 0 && z == 0).unwrap();
+    assert_eq!(hit.block, Vec3::new(0i64, 0i64, 0i64));
+    assert_eq!(hit.normal, Vec3::new(0i64, 0i64, 0i64));
+    assert!(hit.adjacent().is_none());
+}
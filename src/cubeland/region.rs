@@ -0,0 +1,418 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! On-disk storage for chunks.
+//!
+//! Chunks are grouped into cubic regions of REGION_SIZE^3 chunks, each stored
+//! in its own file:
+//!
+//!   magic        4 bytes, "CBRG"
+//!   version      u32
+//!   region size  u32, chunks per side
+//!   chunk size   u32, blocks per side
+//!   index        region size^3 entries of (offset u32, length u32)
+//!   data         deflated block arrays, one per present chunk
+//!
+//! All integers are little endian. An index entry with length 0 means the
+//! chunk has not been saved. Index entries and a chunk's block array, which is
+//! CHUNK_SIZE^3 bytes of blocktype, are both in x, y, z order.
+//!
+//! Version 1 regions were REGION_SIZE^2 columns of single chunks and are no
+//! longer readable.
+
+extern mod extra;
+
+use std::io;
+use std::io::fs;
+use std::hashmap::HashMap;
+use std::path::Path;
+use std::vec;
+
+use extra::flate;
+
+use {read_file, write_file, trap_io_error, inflate};
+use chunk::Map;
+use chunk::Block;
+use CHUNK_SIZE;
+
+pub static REGION_SIZE : uint = 8;
+pub static FORMAT_VERSION : u32 = 2;
+
+static MAGIC : &'static [u8] = bytes!("CBRG");
+static HEADER_LEN : uint = 16;
+static INDEX_ENTRY_LEN : uint = 8;
+static NUM_REGION_CHUNKS : uint = REGION_SIZE * REGION_SIZE * REGION_SIZE;
+
+/// The decoded contents of one region file. Chunk data is kept compressed.
+pub struct Region {
+    chunks: ~[Option<~[u8]>],
+}
+
+impl Region {
+    pub fn new() -> Region {
+        Region { chunks: vec::from_fn(NUM_REGION_CHUNKS, |_| None) }
+    }
+
+    pub fn get(&self, index: uint) -> Result<Option<~Map>, ~str> {
+        match self.chunks[index] {
+            Some(ref data) => match inflate(*data) {
+                Ok(buf) => decode_map(buf).map(|map| Some(map)),
+                Err(msg) => Err(msg),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn put(&mut self, index: uint, map: &Map) {
+        self.chunks[index] = Some(flate::deflate_bytes(encode_map(map)));
+    }
+
+    pub fn encode(&self) -> ~[u8] {
+        let mut buf = ~[];
+        buf.push_all(MAGIC);
+        push_u32(&mut buf, FORMAT_VERSION);
+        push_u32(&mut buf, REGION_SIZE as u32);
+        push_u32(&mut buf, CHUNK_SIZE as u32);
+
+        let mut offset = HEADER_LEN + NUM_REGION_CHUNKS * INDEX_ENTRY_LEN;
+        for chunk in self.chunks.iter() {
+            match *chunk {
+                Some(ref data) => {
+                    push_u32(&mut buf, offset as u32);
+                    push_u32(&mut buf, data.len() as u32);
+                    offset += data.len();
+                },
+                None => {
+                    push_u32(&mut buf, 0);
+                    push_u32(&mut buf, 0);
+                }
+            }
+        }
+
+        for chunk in self.chunks.iter() {
+            match *chunk {
+                Some(ref data) => buf.push_all(*data),
+                None => {}
+            }
+        }
+
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Region, ~str> {
+        if buf.len() < HEADER_LEN || buf.slice(0, 4) != MAGIC {
+            return Err(~"not a region file");
+        }
+
+        let version = read_u32(buf, 4);
+        if version != FORMAT_VERSION {
+            return Err(format!("unsupported region format version {}", version));
+        }
+
+        if read_u32(buf, 8) as uint != REGION_SIZE || read_u32(buf, 12) as uint != CHUNK_SIZE {
+            return Err(~"region was written with different dimensions");
+        }
+
+        if buf.len() < HEADER_LEN + NUM_REGION_CHUNKS * INDEX_ENTRY_LEN {
+            return Err(~"truncated region index");
+        }
+
+        let mut region = Region::new();
+        for i in range(0, NUM_REGION_CHUNKS) {
+            let entry = HEADER_LEN + i * INDEX_ENTRY_LEN;
+            let offset = read_u32(buf, entry) as uint;
+            let length = read_u32(buf, entry + 4) as uint;
+            if length == 0 {
+                continue;
+            }
+            if offset + length > buf.len() {
+                return Err(format!("chunk {} extends past end of region", i));
+            }
+            region.chunks[i] = Some(buf.slice(offset, offset + length).to_owned());
+        }
+
+        Ok(region)
+    }
+}
+
+/// A directory of region files.
+#[deriving(Clone)]
+pub struct RegionStore {
+    dir: Path,
+}
+
+impl RegionStore {
+    pub fn new(dir: Path) -> RegionStore {
+        if !dir.exists() {
+            fs::mkdir_recursive(&dir, io::UserRWX);
+        }
+        RegionStore { dir: dir }
+    }
+
+    /// Reads a chunk, returning None if it has never been saved.
+    pub fn load(&self, cx: i64, cy: i64, cz: i64) -> Result<Option<~Map>, ~str> {
+        let (rx, ry, rz, index) = region_coords(cx, cy, cz);
+        match self.read_region(rx, ry, rz) {
+            Ok(Some(region)) => region.get(index),
+            Ok(None) => Ok(None),
+            Err(msg) => Err(msg),
+        }
+    }
+
+    pub fn save(&self, cx: i64, cy: i64, cz: i64, map: &Map) -> Result<(), ~str> {
+        let (rx, ry, rz, index) = region_coords(cx, cy, cz);
+        let mut region = match self.read_region(rx, ry, rz) {
+            Ok(Some(region)) => region,
+            Ok(None) => Region::new(),
+            Err(msg) => return Err(msg),
+        };
+        region.put(index, map);
+        self.write_region(rx, ry, rz, &region)
+    }
+
+    /// Writes many chunks, given by origin, reading and rewriting each
+    /// region file once. Returns the chunks that could not be saved, with
+    /// the reason.
+    pub fn save_all(&self, chunks: &[((i64, i64, i64), &Map)]) -> ~[((i64, i64, i64), ~str)] {
+        let mut regions : HashMap<(i64, i64, i64), ~[uint]> = HashMap::new();
+        for (i, &((cx, cy, cz), _)) in chunks.iter().enumerate() {
+            let (rx, ry, rz, _) = region_coords(cx, cy, cz);
+            regions.find_or_insert((rx, ry, rz), ~[]).push(i);
+        }
+
+        let mut failed = ~[];
+        for (&(rx, ry, rz), members) in regions.iter() {
+            let result = match self.read_region(rx, ry, rz) {
+                Ok(region) => {
+                    let mut region = region.unwrap_or(Region::new());
+                    for &i in members.iter() {
+                        let ((cx, cy, cz), map) = chunks[i];
+                        let (_, _, _, index) = region_coords(cx, cy, cz);
+                        region.put(index, map);
+                    }
+                    self.write_region(rx, ry, rz, &region)
+                },
+                Err(msg) => Err(msg),
+            };
+
+            match result {
+                Ok(()) => {},
+                Err(msg) => {
+                    for &i in members.iter() {
+                        let (coords, _) = chunks[i];
+                        failed.push((coords, msg.clone()));
+                    }
+                },
+            }
+        }
+        failed
+    }
+
+    /// Replaces a region file, through a temporary file so a failed write
+    /// leaves the old one intact.
+    fn write_region(&self, rx: i64, ry: i64, rz: i64, region: &Region) -> Result<(), ~str> {
+        let path = self.region_path(rx, ry, rz);
+        let tmp_path = path.with_extension("tmp");
+        match write_file(&tmp_path, region.encode()) {
+            Ok(()) => {},
+            Err(msg) => return Err(msg),
+        }
+        match trap_io_error(|| fs::rename(&tmp_path, &path)) {
+            Ok(()) => Ok(()),
+            Err(msg) => Err(format!("failed to rename {}: {}", tmp_path.display(), msg)),
+        }
+    }
+
+    fn read_region(&self, rx: i64, ry: i64, rz: i64) -> Result<Option<Region>, ~str> {
+        let path = self.region_path(rx, ry, rz);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        match read_file(&path) {
+            Ok(data) => match Region::decode(data) {
+                Ok(region) => Ok(Some(region)),
+                Err(msg) => Err(format!("{}: {}", path.display(), msg)),
+            },
+            Err(msg) => Err(msg),
+        }
+    }
+
+    fn region_path(&self, rx: i64, ry: i64, rz: i64) -> Path {
+        self.dir.join(format!("r.{}.{}.{}.region", rx, ry, rz))
+    }
+}
+
+/// Maps a chunk origin to its region coordinates and index within the region.
+fn region_coords(cx: i64, cy: i64, cz: i64) -> (i64, i64, i64, uint) {
+    let n = REGION_SIZE as i64;
+    let ix = cx / CHUNK_SIZE as i64;
+    let iy = cy / CHUNK_SIZE as i64;
+    let iz = cz / CHUNK_SIZE as i64;
+    let rx = div_floor(ix, n);
+    let ry = div_floor(iy, n);
+    let rz = div_floor(iz, n);
+    let index = ((ix - rx * n) * n + (iy - ry * n)) * n + (iz - rz * n);
+    (rx, ry, rz, index as uint)
+}
+
+fn div_floor(a: i64, b: i64) -> i64 {
+    if a >= 0 { a / b } else { (a - b + 1) / b }
+}
+
+fn encode_map(map: &Map) -> ~[u8] {
+    let mut buf = vec::with_capacity(CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE);
+    for x in range(0, CHUNK_SIZE) {
+        for y in range(0, CHUNK_SIZE) {
+            for z in range(0, CHUNK_SIZE) {
+                buf.push(map.blocks[x][y][z].blocktype);
+            }
+        }
+    }
+    buf
+}
+
+fn decode_map(buf: &[u8]) -> Result<~Map, ~str> {
+    if buf.len() != CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE {
+        return Err(format!("chunk has {} blocks, expected {}",
+                           buf.len(), CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE));
+    }
+
+    let mut map = Map::new();
+    let mut i = 0;
+    for x in range(0, CHUNK_SIZE) {
+        for y in range(0, CHUNK_SIZE) {
+            for z in range(0, CHUNK_SIZE) {
+                /* Ids missing from the registry are kept so they survive a re-save */
+                map.blocks[x][y][z] = Block { blocktype: buf[i] };
+                i += 1;
+            }
+        }
+    }
+    Ok(map)
+}
+
+fn push_u32(buf: &mut ~[u8], v: u32) {
+    buf.push(v as u8);
+    buf.push((v >> 8) as u8);
+    buf.push((v >> 16) as u8);
+    buf.push((v >> 24) as u8);
+}
+
+fn read_u32(buf: &[u8], offset: uint) -> u32 {
+    buf[offset] as u32 |
+        (buf[offset + 1] as u32 << 8) |
+        (buf[offset + 2] as u32 << 16) |
+        (buf[offset + 3] as u32 << 24)
+}
+
+#[cfg(test)]
+fn test_map() -> ~Map {
+    let mut map = Map::new();
+    map.blocks[0][0][0] = Block { blocktype: 2 };
+    map.blocks[1][2][3] = Block { blocktype: 1 };
+    map.blocks[CHUNK_SIZE-1][CHUNK_SIZE-1][CHUNK_SIZE-1] = Block { blocktype: 200 };
+    map
+}
+
+#[cfg(test)]
+fn assert_maps_equal(a: &Map, b: &Map) {
+    for x in range(0, CHUNK_SIZE) {
+        for y in range(0, CHUNK_SIZE) {
+            for z in range(0, CHUNK_SIZE) {
+                assert!(a.blocks[x][y][z].blocktype == b.blocks[x][y][z].blocktype);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_region_coords() {
+    let size = CHUNK_SIZE as i64;
+    let n = REGION_SIZE as i64;
+    assert_eq!(region_coords(0, 0, 0), (0, 0, 0, 0));
+    assert_eq!(region_coords(size, 0, 0), (0, 0, 0, REGION_SIZE * REGION_SIZE));
+    assert_eq!(region_coords(0, size, 0), (0, 0, 0, REGION_SIZE));
+    assert_eq!(region_coords(0, 0, size), (0, 0, 0, 1));
+    assert_eq!(region_coords(-size, -size, -size), (-1, -1, -1, NUM_REGION_CHUNKS - 1));
+    assert_eq!(region_coords(n * size, 0, -n * size), (1, 0, -1, 0));
+}
+
+#[test]
+fn test_region_round_trip() {
+    let map = test_map();
+    let mut region = Region::new();
+    region.put(5, map);
+
+    let decoded = Region::decode(region.encode()).unwrap();
+    assert!(decoded.get(0).unwrap().is_none());
+    assert_maps_equal(map, decoded.get(5).unwrap().unwrap());
+}
+
+#[test]
+fn test_region_bad_version() {
+    let mut buf = Region::new().encode();
+    buf[4] = 99;
+    assert!(Region::decode(buf).is_err());
+}
+
+#[test]
+fn test_region_corrupt_chunk() {
+    let mut region = Region::new();
+    region.put(0, test_map());
+    /* A deflate block of the reserved type */
+    region.chunks[5] = Some(~[0xff, 0xff, 0xff, 0xff]);
+
+    let decoded = Region::decode(region.encode()).unwrap();
+    assert!(decoded.get(0).unwrap().is_some());
+    assert!(decoded.get(5).is_err());
+}
+
+#[test]
+fn test_region_store() {
+    let dir = extra::tempfile::TempDir::new("cubeland-region").unwrap();
+    let store = RegionStore::new(dir.path().clone());
+    let size = CHUNK_SIZE as i64;
+    let map = test_map();
+
+    assert!(store.load(-size, 2 * size, 3 * size).unwrap().is_none());
+    store.save(-size, 2 * size, 3 * size, map).unwrap();
+    store.save(0, 0, 0, Map::new()).unwrap();
+
+    assert_maps_equal(map, store.load(-size, 2 * size, 3 * size).unwrap().unwrap());
+    assert!(store.load(0, size, 0).unwrap().is_none());
+    assert!(store.load(0, -size, 0).unwrap().is_none());
+
+    /* Two chunks in one region and one in another */
+    let empty = Map::new();
+    let failed = store.save_all([((0, size, 0), &*map), ((size, 0, 0), &*empty),
+                                 ((-size, -size, -size), &*map)]);
+    assert!(failed.is_empty());
+    assert_maps_equal(map, store.load(0, size, 0).unwrap().unwrap());
+    assert_maps_equal(empty, store.load(size, 0, 0).unwrap().unwrap());
+    assert_maps_equal(map, store.load(-size, -size, -size).unwrap().unwrap());
+    /* Chunks saved earlier in the same region are kept */
+    assert_maps_equal(map, store.load(-size, 2 * size, 3 * size).unwrap().unwrap());
+}
+
+#[test]
+fn test_region_store_write_error() {
+    let dir = extra::tempfile::TempDir::new("cubeland-region").unwrap();
+    let store = RegionStore::new(dir.path().clone());
+
+    /* A directory in the way of the temporary file makes the write fail */
+    fs::mkdir(&dir.path().join("r.0.0.0.tmp"), io::UserRWX);
+    assert!(store.save(0, 0, 0, Map::new()).is_err());
+    assert!(store.load(0, 0, 0).unwrap().is_none());
+}
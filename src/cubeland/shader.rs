@@ -0,0 +1,235 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Loading GLSL source from disk.
+//!
+//! A line of the form `#include "file.glsl"` is replaced by the contents of
+//! that file, found relative to the including file. Each file is included
+//! at most once per shader, so shared snippets need no include guards.
+//! Compiler logs refer to lines of the combined source; Source::map_log
+//! translates them back to files and lines.
+
+extern mod extra;
+
+use std::io;
+use std::io::fs;
+use std::str;
+
+use ratelimiter::RateLimiter;
+use read_file;
+#[cfg(test)]
+use write_file;
+
+/// Interval between checks for changed shader files, in nanoseconds
+static WATCH_INTERVAL : u64 = 1000*1000*1000;
+
+pub struct Source {
+    /// The combined source
+    text: ~str,
+    /// Every file read, the shader itself first
+    files: ~[Path],
+    /// Index into files and line number, from 1, of each line of text
+    lines: ~[(uint, uint)],
+}
+
+impl Source {
+    /// Reads the shader at `path` and everything it includes.
+    pub fn load(path: &Path) -> Result<Source, ~str> {
+        let mut source = Source { text: ~"", files: ~[], lines: ~[] };
+        match source.append(path) {
+            Ok(()) => Ok(source),
+            Err(msg) => Err(msg),
+        }
+    }
+
+    fn append(&mut self, path: &Path) -> Result<(), ~str> {
+        if self.files.iter().any(|p| p == path) {
+            return Ok(());
+        }
+
+        if !path.exists() {
+            return Err(format!("{} not found", path.display()));
+        }
+
+        let data = match read_file(path) {
+            Ok(data) => data,
+            Err(msg) => return Err(msg),
+        };
+
+        let text = match str::from_utf8_opt(data) {
+            Some(text) => text,
+            None => return Err(format!("{} is not UTF-8", path.display())),
+        };
+
+        let index = self.files.len();
+        self.files.push(path.clone());
+
+        for (i, line) in text.lines().enumerate() {
+            match include_path(line) {
+                Some(name) => {
+                    let included = path.dir_path().join(name);
+                    match self.append(&included) {
+                        Ok(()) => {},
+                        Err(msg) => return Err(format!("{}:{}: {}", path.display(), i + 1, msg)),
+                    }
+                },
+                None => {
+                    self.text.push_str(line);
+                    self.text.push_char('\n');
+                    self.lines.push((index, i + 1));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the source locations in a compiler log, like "0:12(5)" from
+    /// Mesa or "0(12)" from NVIDIA, as file and line.
+    pub fn map_log(&self, log: &str) -> ~str {
+        let mapped : ~[~str] = log.lines().map(|line| self.map_log_line(line)).collect();
+        mapped.connect("\n")
+    }
+
+    fn map_log_line(&self, line: &str) -> ~str {
+        let bytes = line.as_bytes();
+        let digits = |start: uint| {
+            let mut end = start;
+            while end < bytes.len() && bytes[end] >= '0' as u8 && bytes[end] <= '9' as u8 {
+                end += 1;
+            }
+            end
+        };
+
+        /* A source string number, then ':' or '(', then a line number */
+        let mut start = 0;
+        while start < bytes.len() {
+            let string_end = digits(start);
+            if string_end > start && string_end < bytes.len() &&
+                    (bytes[string_end] == ':' as u8 || bytes[string_end] == '(' as u8) {
+                let line_end = digits(string_end + 1);
+                let n : Option<uint> = from_str(line.slice(string_end + 1, line_end));
+                match n {
+                    Some(n) if n >= 1 && n <= self.lines.len() => {
+                        let (file, file_line) = self.lines[n - 1];
+                        let mut end = line_end;
+                        if bytes[string_end] == '(' as u8 && end < bytes.len() && bytes[end] == ')' as u8 {
+                            end += 1;
+                        }
+                        return format!("{}{}:{}{}", line.slice_to(start),
+                                       self.files[file].display(), file_line,
+                                       line.slice_from(end));
+                    },
+                    _ => {}
+                }
+            }
+            start = if string_end > start { string_end } else { start + 1 };
+        }
+
+        line.to_owned()
+    }
+}
+
+/// Notices when GLSL files in a directory are added, removed or modified.
+pub struct Watcher {
+    dir: Path,
+    times: ~[(Path, u64)],
+    limiter: RateLimiter,
+}
+
+impl Watcher {
+    pub fn new(dir: Path) -> Watcher {
+        let times = modification_times(&dir);
+        Watcher {
+            dir: dir,
+            times: times,
+            limiter: RateLimiter::new(WATCH_INTERVAL),
+        }
+    }
+
+    /// Returns true if the shaders changed since the last call. Only looks
+    /// at the files once a second, so it is cheap to call every frame.
+    pub fn changed(&mut self) -> bool {
+        if !self.limiter.limit() {
+            return false;
+        }
+
+        let times = modification_times(&self.dir);
+        if times == self.times {
+            return false;
+        }
+        self.times = times;
+        true
+    }
+}
+
+/// The file named by an #include directive, if `line` is one.
+fn include_path<'a>(line: &'a str) -> Option<&'a str> {
+    let line = line.trim();
+    if !line.starts_with("#include") {
+        return None;
+    }
+
+    let name = line.slice_from("#include".len()).trim();
+    if name.len() >= 2 && name.starts_with("\"") && name.ends_with("\"") {
+        Some(name.slice(1, name.len() - 1))
+    } else {
+        None
+    }
+}
+
+fn modification_times(dir: &Path) -> ~[(Path, u64)] {
+    /* Editors may remove a file between listing and checking it */
+    io::io_error::cond.trap(|_| {}).inside(|| {
+        fs::readdir(dir).move_iter()
+            .filter(|p| p.extension_str() == Some("glsl"))
+            .map(|p| {
+                let modified = p.stat().modified;
+                (p, modified)
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+fn write_text(path: &Path, text: &str) {
+    write_file(path, text.as_bytes()).unwrap();
+}
+
+#[test]
+fn test_shader_include() {
+    let dir = extra::tempfile::TempDir::new("cubeland-shader").unwrap();
+    let main = dir.path().join("main.glsl");
+    let common = dir.path().join("common.glsl");
+    write_text(&main, "#version 120\n#include \"common.glsl\"\n#include \"common.glsl\"\nvoid main() {}\n");
+    write_text(&common, "float one() { return 1.0; }\n");
+
+    let source = Source::load(&main).unwrap();
+    assert_eq!(source.text, ~"#version 120\nfloat one() { return 1.0; }\nvoid main() {}\n");
+    assert_eq!(source.lines, ~[(0, 1), (1, 1), (0, 4)]);
+
+    /* Mesa and NVIDIA log formats */
+    assert_eq!(source.map_log("0:2(15): error: syntax error"),
+               format!("{}:1(15): error: syntax error", common.display()));
+    assert_eq!(source.map_log("0(3) : error C0000: syntax error"),
+               format!("{}:4 : error C0000: syntax error", main.display()));
+    assert_eq!(source.map_log("warning: no location"), ~"warning: no location");
+
+    write_text(&common, "#include \"missing.glsl\"\n");
+    assert!(Source::load(&main).is_err());
+    assert!(Source::load(&dir.path().join("nothing.glsl")).is_err());
+
+    write_file(&common, [0x23, 0xff, 0xfe, 0x0a]).unwrap();
+    assert!(Source::load(&main).is_err());
+}
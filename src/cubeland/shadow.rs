@@ -0,0 +1,318 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cascaded shadow maps for the sun.
+//!
+//! The view frustum is split by distance into NUM_CASCADES slices reaching
+//! the edge of the visible chunks. Each slice gets a depth map rendered from
+//! the sun with an orthographic projection around the slice's bounding
+//! sphere, so nearby shadows get the most texels. The sphere keeps each map
+//! the same size as the camera turns, and its centre is snapped to whole
+//! texels so shadow edges don't shimmer as the camera moves.
+
+extern mod gl;
+extern mod cgmath;
+
+use std;
+use std::ptr;
+
+use gl::types::*;
+
+use cgmath::matrix::Matrix;
+use cgmath::matrix::Mat3;
+use cgmath::matrix::Mat4;
+use cgmath::matrix::ToMat4;
+use cgmath::vector::{Vector, EuclideanVector};
+use cgmath::vector::Vec3;
+use cgmath::vector::Vec4;
+use cgmath::ptr::Ptr;
+
+use camera;
+use camera::{Camera, Curvature};
+use chunk::ChunkLoader;
+use {load_program, disable_vertex_arrays, check_gl};
+
+pub static NUM_CASCADES : uint = 3;
+
+/// Where each cascade ends, as a fraction of the shadow range.
+static CASCADE_SPLITS : [f32, ..NUM_CASCADES] = [0.1, 0.3, 1.0];
+
+/// How far beyond a slice towards the sun blocks still cast shadows into it.
+static CASTER_DISTANCE : f32 = 256.0;
+
+/// The main shader samples cascade i from texture unit FIRST_TEXTURE_UNIT + i.
+pub static FIRST_TEXTURE_UNIT : uint = 2;
+
+pub struct ShadowMaps {
+    /// Width and height of each depth map
+    size: u32,
+    framebuffer: GLuint,
+    textures: ~[GLuint],
+    program: GLuint,
+    vertex_shader: GLuint,
+    fragment_shader: GLuint,
+    uniform_transform: GLint,
+}
+
+/// One slice of the view frustum, for a single frame.
+pub struct Cascade {
+    /// World to clip coordinates of the sun's view
+    clip_transform: Mat4<f32>,
+    /// Distance from the camera where the slice ends
+    far: f32,
+}
+
+impl Cascade {
+    /// World to shadow map coordinates, with depth also from 0 to 1.
+    pub fn texture_transform(&self) -> Mat4<f32> {
+        let bias = Mat4::<f32>::from_cols(
+            Vec4::new(0.5f32, 0.0f32, 0.0f32, 0.0f32),
+            Vec4::new(0.0f32, 0.5f32, 0.0f32, 0.0f32),
+            Vec4::new(0.0f32, 0.0f32, 0.5f32, 0.0f32),
+            Vec4::new(0.5f32, 0.5f32, 0.5f32, 1.0f32));
+        bias.mul_m(&self.clip_transform)
+    }
+}
+
+impl ShadowMaps {
+    /// Creates `size` by `size` depth maps and loads the depth-only shaders.
+    pub fn new(size: u32) -> Result<ShadowMaps, ~str> {
+        let (program, vertex_shader, fragment_shader) =
+            match load_program("shadow.vs.glsl", "shadow.fs.glsl") {
+                Ok(x) => x,
+                Err(msg) => return Err(msg),
+            };
+
+        let uniform_transform = unsafe {
+            "transform".with_c_str(|ptr| gl::GetUniformLocation(program, ptr))
+        };
+
+        let mut framebuffer = 0;
+        let mut textures = std::vec::from_elem(NUM_CASCADES, 0 as GLuint);
+
+        unsafe {
+            gl::GenFramebuffers(1, &mut framebuffer);
+            gl::GenTextures(NUM_CASCADES as GLsizei, &mut textures[0]);
+        }
+
+        for &texture in textures.iter() {
+            gl::BindTexture(gl::TEXTURE_2D, texture);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
+            unsafe {
+                gl::TexImage2D(gl::TEXTURE_2D, 0, gl::DEPTH_COMPONENT24 as GLint,
+                               size as GLsizei, size as GLsizei, 0,
+                               gl::DEPTH_COMPONENT, gl::UNSIGNED_INT, ptr::null());
+            }
+        }
+        gl::BindTexture(gl::TEXTURE_2D, 0);
+
+        let result = ShadowMaps {
+            size: size,
+            framebuffer: framebuffer,
+            textures: textures,
+            program: program,
+            vertex_shader: vertex_shader,
+            fragment_shader: fragment_shader,
+            uniform_transform: uniform_transform,
+        };
+
+        let mut previous_framebuffer : GLint = 0;
+        unsafe {
+            gl::GetIntegerv(gl::FRAMEBUFFER_BINDING, &mut previous_framebuffer);
+        }
+
+        gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+        gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT,
+                                 gl::TEXTURE_2D, result.textures[0], 0);
+        gl::DrawBuffer(gl::NONE);
+        gl::ReadBuffer(gl::NONE);
+        let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+        gl::BindFramebuffer(gl::FRAMEBUFFER, previous_framebuffer as GLuint);
+
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            return Err(format!("incomplete shadow framebuffer, status {:x}", status));
+        }
+
+        check_gl("creating shadow maps");
+
+        Ok(result)
+    }
+
+    /// Fits a cascade around each slice of the camera's view, out to
+    /// `range`, for a sun shining in `direction`.
+    pub fn cascades(&self, camera: &Camera, aspect: f32, direction: &Vec3<f32>,
+                    range: f32) -> ~[Cascade] {
+        let rotation = sun_rotation(direction);
+        let mut near = 0.0f32;
+
+        CASCADE_SPLITS.iter().map(|&split| {
+            let far = range * split;
+            let (center, radius) = camera.frustum_slice_bounds(aspect, near, far);
+            near = far;
+
+            /* Move the map in whole texels so static shadows stay put */
+            let texel = radius * 2.0 / self.size as f32;
+            let light_center = rotation.mul_v(&center);
+            let x = (light_center.x / texel).floor() * texel;
+            let y = (light_center.y / texel).floor() * texel;
+            let depth = -light_center.z;
+
+            let projection = cgmath::projection::ortho(x - radius, x + radius,
+                                                       y - radius, y + radius,
+                                                       depth - radius - CASTER_DISTANCE,
+                                                       depth + radius);
+
+            Cascade {
+                clip_transform: projection.mul_m(&rotation.to_mat4()),
+                far: far,
+            }
+        }).collect()
+    }
+
+    /// Renders the opaque blocks of the loaded chunks among `coords` into
+    /// each cascade's depth map. The caller restores the viewport.
+    pub fn draw(&self, cascades: &[Cascade], chunk_loader: &ChunkLoader,
+                coords: &[(i64, i64, i64)]) {
+        let mut previous_framebuffer : GLint = 0;
+        let mut previous_program : GLint = 0;
+        unsafe {
+            gl::GetIntegerv(gl::FRAMEBUFFER_BINDING, &mut previous_framebuffer);
+            gl::GetIntegerv(gl::CURRENT_PROGRAM, &mut previous_program);
+        }
+
+        gl::BindFramebuffer(gl::FRAMEBUFFER, self.framebuffer);
+        gl::Viewport(0, 0, self.size as GLint, self.size as GLint);
+        gl::UseProgram(self.program);
+
+        disable_vertex_arrays();
+
+        /* Both sides cast shadows; the offset keeps surfaces from shadowing themselves */
+        let cull_face = gl::IsEnabled(gl::CULL_FACE) == gl::TRUE;
+        gl::Disable(gl::CULL_FACE);
+        gl::Enable(gl::POLYGON_OFFSET_FILL);
+        gl::PolygonOffset(2.0, 4.0);
+
+        for (cascade, &texture) in cascades.iter().zip(self.textures.iter()) {
+            gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::DEPTH_ATTACHMENT,
+                                     gl::TEXTURE_2D, texture, 0);
+            gl::Clear(gl::DEPTH_BUFFER_BIT);
+            unsafe {
+                gl::UniformMatrix4fv(self.uniform_transform, 1, gl::FALSE,
+                                     cascade.clip_transform.ptr());
+            }
+
+            for &(cx, cy, cz) in coords.iter() {
+                let mesh = match chunk_loader.cache.find(&(cx, cy, cz)) {
+                    Some(chunk) => &chunk.mesh,
+                    None => continue,
+                };
+
+                let count = mesh.opaque_count();
+                if count == 0 {
+                    continue;
+                }
+
+                let chunk_pos = Vec4::new(cx as f32, cy as f32, cz as f32, 0.0f32);
+                /* Shadows are cast by the flat world */
+                if camera::view_frustum_cull(&cascade.clip_transform, &chunk_pos,
+                                             &Curvature::flat()) {
+                    continue;
+                }
+
+                mesh.bind_positions(self.program);
+                unsafe {
+                    gl::DrawElements(gl::TRIANGLES, count as GLsizei,
+                                     gl::UNSIGNED_INT, ptr::null());
+                }
+            }
+        }
+
+        gl::Disable(gl::POLYGON_OFFSET_FILL);
+        if cull_face {
+            gl::Enable(gl::CULL_FACE);
+        }
+
+        gl::BindFramebuffer(gl::FRAMEBUFFER, previous_framebuffer as GLuint);
+        gl::UseProgram(previous_program as GLuint);
+    }
+
+    /// Binds the depth maps for the main shader.
+    pub fn bind_textures(&self) {
+        for (i, &texture) in self.textures.iter().enumerate() {
+            gl::ActiveTexture(gl::TEXTURE0 + (FIRST_TEXTURE_UNIT + i) as GLenum);
+            gl::BindTexture(gl::TEXTURE_2D, texture);
+        }
+        gl::ActiveTexture(gl::TEXTURE0);
+    }
+}
+
+impl Drop for ShadowMaps {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteFramebuffers(1, &self.framebuffer);
+            gl::DeleteTextures(NUM_CASCADES as GLsizei, &self.textures[0]);
+        }
+        gl::DeleteProgram(self.program);
+        gl::DeleteShader(self.vertex_shader);
+        gl::DeleteShader(self.fragment_shader);
+    }
+}
+
+/// Rotates world directions into the view of a sun shining in `direction`,
+/// which becomes -z.
+fn sun_rotation(direction: &Vec3<f32>) -> Mat3<f32> {
+    let w = direction.normalize().mul_s(-1.0);
+
+    /* Any up vector will do, as long as it isn't parallel to the sun */
+    let up = if w.y.abs() > 0.99 {
+        Vec3::new(1.0f32, 0.0f32, 0.0f32)
+    } else {
+        Vec3::new(0.0f32, 1.0f32, 0.0f32)
+    };
+    let u = up.cross(&w).normalize();
+    let v = w.cross(&u);
+
+    /* Rows u, v and w */
+    Mat3::from_cols(Vec3::new(u.x, v.x, w.x),
+                    Vec3::new(u.y, v.y, w.y),
+                    Vec3::new(u.z, v.z, w.z))
+}
+
+#[test]
+fn test_shadow_sun_rotation() {
+    let direction = Vec3::new(0.408248f32, -0.816497f32, 0.408248f32);
+    let rotation = sun_rotation(&direction);
+
+    /* The sun looks down -z */
+    let d = rotation.mul_v(&direction);
+    assert!(d.x.abs() < 0.001 && d.y.abs() < 0.001 && (d.z + 1.0).abs() < 0.001);
+
+    /* Straight down still works */
+    let rotation = sun_rotation(&Vec3::new(0.0f32, -1.0f32, 0.0f32));
+    let d = rotation.mul_v(&Vec3::new(0.0f32, -1.0f32, 0.0f32));
+    assert!((d.z + 1.0).abs() < 0.001);
+}
+
+#[test]
+fn test_shadow_ortho() {
+    /* Near and far are distances in front of the sun, as used by cascades() */
+    let projection = cgmath::projection::ortho(-10.0f32, 10.0, 0.0, 20.0, 5.0, 105.0);
+    let near = projection.mul_v(&Vec4::new(-10.0f32, 0.0f32, -5.0f32, 1.0f32));
+    let far = projection.mul_v(&Vec4::new(10.0f32, 20.0f32, -105.0f32, 1.0f32));
+    assert!((near.x + 1.0).abs() < 0.001 && (near.y + 1.0).abs() < 0.001 && (near.z + 1.0).abs() < 0.001);
+    assert!((far.x - 1.0).abs() < 0.001 && (far.y - 1.0).abs() < 0.001 && (far.z - 1.0).abs() < 0.001);
+}
@@ -0,0 +1,146 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The sky, drawn behind the terrain as a screen-filling quad. The fragment
+//! shader turns each pixel into a view direction and shades it with a
+//! gradient from the horizon to the zenith, the sun and moon discs, and
+//! stars at night. The horizon is the fog colour, so fogged chunks fade
+//! into the sky.
+
+extern mod gl;
+extern mod cgmath;
+
+use std;
+use std::cast;
+use std::ptr;
+
+use gl::types::*;
+
+use cgmath::vector::Vec2;
+use cgmath::ptr::Ptr;
+
+use camera::Camera;
+use clock::Lighting;
+use {load_program, disable_vertex_arrays};
+
+/// Two triangles covering the screen, in normalized device coordinates.
+static QUAD : [Vec2<f32>, ..6] = [
+    Vec2 { x: -1.0, y: -1.0 },
+    Vec2 { x: 1.0, y: -1.0 },
+    Vec2 { x: 1.0, y: 1.0 },
+    Vec2 { x: -1.0, y: -1.0 },
+    Vec2 { x: 1.0, y: 1.0 },
+    Vec2 { x: -1.0, y: 1.0 },
+];
+
+pub struct Sky {
+    program: GLuint,
+    vertex_shader: GLuint,
+    fragment_shader: GLuint,
+    vertex_buffer: GLuint,
+    uniform_view_to_world: GLint,
+    uniform_view_scale: GLint,
+    uniform_light_direction: GLint,
+    uniform_horizon_color: GLint,
+    uniform_zenith_color: GLint,
+}
+
+impl Sky {
+    pub fn new() -> Result<Sky, ~str> {
+        let (program, vertex_shader, fragment_shader) =
+            match load_program("sky.vs.glsl", "sky.fs.glsl") {
+                Ok(x) => x,
+                Err(msg) => return Err(msg),
+            };
+
+        let mut vertex_buffer = 0;
+        unsafe {
+            gl::GenBuffers(1, &mut vertex_buffer);
+            gl::BindBuffer(gl::ARRAY_BUFFER, vertex_buffer);
+            gl::BufferData(gl::ARRAY_BUFFER,
+                           (QUAD.len() * std::mem::size_of::<Vec2<f32>>()) as GLsizeiptr,
+                           cast::transmute(&QUAD[0]),
+                           gl::STATIC_DRAW);
+        }
+
+        let uniform_view_to_world = unsafe { "view_to_world".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+        let uniform_view_scale = unsafe { "view_scale".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+        let uniform_light_direction = unsafe { "light_direction".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+        let uniform_horizon_color = unsafe { "horizon_color".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+        let uniform_zenith_color = unsafe { "zenith_color".with_c_str(|ptr| gl::GetUniformLocation(program, ptr)) };
+
+        Ok(Sky {
+            program: program,
+            vertex_shader: vertex_shader,
+            fragment_shader: fragment_shader,
+            vertex_buffer: vertex_buffer,
+            uniform_view_to_world: uniform_view_to_world,
+            uniform_view_scale: uniform_view_scale,
+            uniform_light_direction: uniform_light_direction,
+            uniform_horizon_color: uniform_horizon_color,
+            uniform_zenith_color: uniform_zenith_color,
+        })
+    }
+
+    /// Fills the screen with the sky as seen by `camera`. Leaves depth
+    /// untouched, so draw it first.
+    pub fn draw(&self, camera: &Camera, aspect: f32, lighting: &Lighting) {
+        let mut previous_program : GLint = 0;
+        unsafe {
+            gl::GetIntegerv(gl::CURRENT_PROGRAM, &mut previous_program);
+        }
+
+        gl::UseProgram(self.program);
+        disable_vertex_arrays();
+
+        let (half_width, half_height) = camera.half_extents(aspect);
+        let view_to_world = camera.inverse_rotation();
+        unsafe {
+            gl::UniformMatrix3fv(self.uniform_view_to_world, 1, gl::FALSE, view_to_world.ptr());
+            gl::Uniform3fv(self.uniform_light_direction, 1, lighting.sun_direction.ptr());
+            gl::Uniform3fv(self.uniform_horizon_color, 1, lighting.sky_color.ptr());
+            gl::Uniform3fv(self.uniform_zenith_color, 1, lighting.zenith_color.ptr());
+        }
+        gl::Uniform2f(self.uniform_view_scale, half_width, half_height);
+
+        unsafe {
+            let vert_attr = "position".with_c_str(|ptr| gl::GetAttribLocation(self.program, ptr));
+            assert!(vert_attr as u32 != gl::INVALID_VALUE);
+            gl::BindBuffer(gl::ARRAY_BUFFER, self.vertex_buffer);
+            gl::EnableVertexAttribArray(vert_attr as GLuint);
+            gl::VertexAttribPointer(vert_attr as GLuint, 2, gl::FLOAT,
+                                    gl::FALSE as GLboolean, 0, ptr::null());
+        }
+
+        gl::Disable(gl::DEPTH_TEST);
+        gl::DepthMask(gl::FALSE);
+        gl::DrawArrays(gl::TRIANGLES, 0, QUAD.len() as GLsizei);
+        gl::DepthMask(gl::TRUE);
+        gl::Enable(gl::DEPTH_TEST);
+
+        disable_vertex_arrays();
+        gl::UseProgram(previous_program as GLuint);
+    }
+}
+
+impl Drop for Sky {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.vertex_buffer);
+        }
+        gl::DeleteProgram(self.program);
+        gl::DeleteShader(self.vertex_shader);
+        gl::DeleteShader(self.fragment_shader);
+    }
+}
@@ -0,0 +1,481 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern mod extra;
+extern mod noise;
+
+use std;
+use std::path::Path;
+use std::str;
+
+use extra::time::precise_time_ns;
+
+use noise::Perlin;
+
+use biome;
+use biome::BiomeMap;
+use blocks::{BlockId, BlockRegistry};
+use chunk::{Map, Block};
+use {CHUNK_SIZE, read_file};
+#[cfg(test)]
+use blocks::test_registry;
+
+/// Fills chunks with blocks. Implementations are shared between the worker
+/// tasks, so generation must be deterministic and must not mutate state.
+pub trait TerrainGenerator {
+    /// Fills `map`, the chunk whose origin is at the given world coordinates.
+    /// `map` is all air on entry.
+    fn generate(&self, chunk_x: i64, chunk_y: i64, chunk_z: i64, map: &mut Map);
+}
+
+/// Sets each block in one column of the chunk to `column(y)`, where y is the
+/// block's world height. Blocks for which it returns None are left as air.
+fn fill_column(chunk_y: i64, block_x: uint, block_z: uint, map: &mut Map,
+               column: |i64| -> Option<BlockId>) {
+    for block_y in range(0, CHUNK_SIZE) {
+        match column(chunk_y + block_y as i64) {
+            Some(blocktype) => map.blocks[block_x][block_y][block_z] = Block { blocktype: blocktype },
+            None => {}
+        }
+    }
+}
+
+/// How far, in blocks, 3D noise can push the surface up or down from the
+/// column's heightmap value. This is what produces overhangs and arches.
+static OVERHANG_AMPLITUDE : f64 = 6.0;
+
+/// Caves are carved where the cave noise is within this distance of zero,
+/// which gives long connected tunnels rather than round blobs.
+static CAVE_THRESHOLD : f64 = 0.06;
+
+/// Caves stay at least this far below the heightmap surface so they do not
+/// punch holes in every hillside or drain the sea floor.
+static CAVE_MIN_DEPTH : i64 = 4;
+
+/// The deepest soil layer is 12 blocks, so this many blocks above the chunk
+/// are scanned to find each block's depth below the surface.
+static SOIL_SCAN_HEIGHT : i64 = 12;
+
+/// Ids of the blocks the built-in generators place, looked up by name so
+/// generators work with any block registry that defines them.
+struct TerrainBlocks {
+    grass: BlockId,
+    dirt: BlockId,
+    stone: BlockId,
+    water: BlockId,
+}
+
+impl TerrainBlocks {
+    fn new(registry: &BlockRegistry) -> Result<TerrainBlocks, ~str> {
+        let mut ids = ~[];
+        for name in ["grass", "dirt", "stone", "water"].iter() {
+            match registry.require(*name) {
+                Ok(id) => ids.push(id),
+                Err(msg) => return Err(msg),
+            }
+        }
+
+        Ok(TerrainBlocks { grass: ids[0], dirt: ids[1], stone: ids[2], water: ids[3] })
+    }
+}
+
+/// Perlin noise landscape shaped by the biome at each column, with a 3D
+/// density function for overhangs and caves.
+pub struct DefaultGenerator {
+    blocks: TerrainBlocks,
+    /// (surface, subsurface) ids for each entry of `biome::biomes`
+    soil: ~[(BlockId, BlockId)],
+    biomes: BiomeMap,
+    perlin1: Perlin,
+    perlin2: Perlin,
+    perlin3: Perlin,
+    perlin4: Perlin,
+    overhang_perlin: Perlin,
+    cave_perlin: Perlin,
+}
+
+impl DefaultGenerator {
+    pub fn new(seed: u32, registry: &BlockRegistry) -> Result<DefaultGenerator, ~str> {
+        let blocks = match TerrainBlocks::new(registry) {
+            Ok(blocks) => blocks,
+            Err(msg) => return Err(msg),
+        };
+
+        let mut soil = ~[];
+        for biome in biome::biomes.iter() {
+            match (registry.require(biome.surface), registry.require(biome.subsurface)) {
+                (Ok(surface), Ok(subsurface)) => soil.push((surface, subsurface)),
+                (Err(msg), _) | (_, Err(msg)) => return Err(msg),
+            }
+        }
+
+        Ok(DefaultGenerator {
+            blocks: blocks,
+            soil: soil,
+            biomes: BiomeMap::new(seed),
+            perlin1: Perlin::from_seed([seed as uint]),
+            perlin2: Perlin::from_seed([seed as uint * 7]),
+            perlin3: Perlin::from_seed([seed as uint * 13]),
+            perlin4: Perlin::from_seed([seed as uint * 17]),
+            overhang_perlin: Perlin::from_seed([seed as uint * 19]),
+            cave_perlin: Perlin::from_seed([seed as uint * 23]),
+        })
+    }
+
+    /// Whether the block at (x, y, z) is solid before caves are carved. The
+    /// heightmap is the zero crossing of the density, offset by 3D noise.
+    fn solid(&self, x: i64, y: i64, z: i64, height: i64) -> bool {
+        let gradient = (height - y) as f64;
+        if gradient > OVERHANG_AMPLITUDE {
+            return true;
+        } else if gradient < -OVERHANG_AMPLITUDE {
+            return false;
+        }
+
+        let noise = self.overhang_perlin.gen([
+            x as f64 * 0.04,
+            y as f64 * 0.06,
+            z as f64 * 0.04
+        ]);
+        gradient + noise * OVERHANG_AMPLITUDE > 0.0
+    }
+
+    fn cave(&self, x: i64, y: i64, z: i64, height: i64) -> bool {
+        if y > height - CAVE_MIN_DEPTH {
+            return false;
+        }
+
+        let noise = self.cave_perlin.gen([
+            x as f64 * 0.03,
+            y as f64 * 0.05,
+            z as f64 * 0.03
+        ]);
+        noise.abs() < CAVE_THRESHOLD
+    }
+}
+
+impl TerrainGenerator for DefaultGenerator {
+    fn generate(&self, chunk_x: i64, chunk_y: i64, chunk_z: i64, map: &mut Map) {
+        let start_time = precise_time_ns();
+
+        for block_x in std::iter::range(0, CHUNK_SIZE) {
+            for block_z in std::iter::range(0, CHUNK_SIZE) {
+                let noise1 = self.perlin1.gen([
+                    (chunk_x + block_x as i64) as f64 * 0.07,
+                    (chunk_z + block_z as i64) as f64 * 0.04
+                ]);
+                let noise2 = self.perlin2.gen([
+                    (chunk_x + block_x as i64) as f64 * 0.05,
+                    (chunk_z + block_z as i64) as f64 * 0.05
+                ]);
+                let noise3 = self.perlin3.gen([
+                    (chunk_x + block_x as i64) as f64 * 0.005,
+                    (chunk_z + block_z as i64) as f64 * 0.005
+                ]);
+                let noise4 = self.perlin4.gen([
+                    (chunk_x + block_x as i64) as f64 * 0.001,
+                    (chunk_z + block_z as i64) as f64 * 0.001
+                ]);
+
+                let biome = self.biomes.column(chunk_x + block_x as i64,
+                                               chunk_z + block_z as i64);
+
+                let height = (
+                        biome.base_height +
+                        noise4 * 10.0 +
+                        biome.variance *
+                            std::num::pow(noise3 + 1.0, 2.5) *
+                            noise1
+                    ) as i64;
+
+                let dirt_height = (4.0 + noise2 * 8.0) as i64;
+                let water_height = 10;
+                let max_soil_height = biome.max_soil_height as i64;
+                let (surface, subsurface) = self.soil[biome.dominant_index];
+
+                let x = chunk_x + block_x as i64;
+                let z = chunk_z + block_z as i64;
+
+                /*
+                 * Walk down the column counting solid blocks since the last
+                 * air, so soil follows overhangs as well as the heightmap.
+                 */
+                let mut depth = 0;
+                let mut y = chunk_y + CHUNK_SIZE as i64 + SOIL_SCAN_HEIGHT - 1;
+                while y >= chunk_y {
+                    let solid = self.solid(x, y, z, height);
+                    if !solid {
+                        depth = 0;
+                    }
+
+                    if y < chunk_y + CHUNK_SIZE as i64 {
+                        let blocktype = if solid {
+                            if self.cave(x, y, z, height) {
+                                None
+                            } else if (height <= max_soil_height) && (depth < dirt_height) {
+                                if depth >= 2 {
+                                    Some(subsurface)
+                                } else {
+                                    Some(surface)
+                                }
+                            } else {
+                                Some(self.blocks.stone)
+                            }
+                        } else if y < water_height {
+                            Some(self.blocks.water)
+                        } else {
+                            None
+                        };
+
+                        match blocktype {
+                            Some(blocktype) => {
+                                let block_y = (y - chunk_y) as uint;
+                                map.blocks[block_x][block_y][block_z] = Block { blocktype: blocktype };
+                            },
+                            None => {}
+                        }
+                    }
+
+                    if solid {
+                        depth += 1;
+                    }
+                    y -= 1;
+                }
+            }
+        }
+
+        let end_time = precise_time_ns();
+
+        println!("terrain gen : {}us",
+                 (end_time - start_time)/1000);
+    }
+}
+
+/// Stone up to `height`, with a layer of dirt and grass on top.
+pub struct FlatGenerator {
+    blocks: TerrainBlocks,
+    height: i64,
+}
+
+impl FlatGenerator {
+    pub fn new(height: i64, registry: &BlockRegistry) -> Result<FlatGenerator, ~str> {
+        match TerrainBlocks::new(registry) {
+            Ok(blocks) => Ok(FlatGenerator { blocks: blocks, height: height }),
+            Err(msg) => Err(msg),
+        }
+    }
+}
+
+impl TerrainGenerator for FlatGenerator {
+    fn generate(&self, _chunk_x: i64, chunk_y: i64, _chunk_z: i64, map: &mut Map) {
+        for block_x in range(0, CHUNK_SIZE) {
+            for block_z in range(0, CHUNK_SIZE) {
+                fill_column(chunk_y, block_x, block_z, map, |y| surface_block(&self.blocks, y, self.height));
+            }
+        }
+    }
+}
+
+/// Terrain heights read from a greyscale image, tiled across the world.
+/// Black is `base_height` and white is `base_height + scale`.
+pub struct HeightmapGenerator {
+    blocks: TerrainBlocks,
+    width: uint,
+    depth: uint,
+    heights: ~[i64],
+}
+
+impl HeightmapGenerator {
+    /// Loads a binary PGM (P5) image. Convert other formats with e.g.
+    /// `convert heightmap.png heightmap.pgm`.
+    pub fn load(path: &Path, base_height: i64, scale: f64,
+                registry: &BlockRegistry) -> Result<HeightmapGenerator, ~str> {
+        if !path.exists() {
+            return Err(format!("{} not found", path.display()));
+        }
+
+        let data = match read_file(path) {
+            Ok(data) => data,
+            Err(msg) => return Err(msg),
+        };
+
+        HeightmapGenerator::from_pgm(data, base_height, scale, registry)
+    }
+
+    pub fn from_pgm(data: &[u8], base_height: i64, scale: f64,
+                    registry: &BlockRegistry) -> Result<HeightmapGenerator, ~str> {
+        let blocks = match TerrainBlocks::new(registry) {
+            Ok(blocks) => blocks,
+            Err(msg) => return Err(msg),
+        };
+
+        let (width, depth, maxval, pixels) = match parse_pgm(data) {
+            Some(x) => x,
+            None => return Err(~"not a binary PGM image"),
+        };
+
+        let heights = pixels.iter().map(|&p| {
+            base_height + (p as f64 / maxval as f64 * scale) as i64
+        }).collect();
+
+        Ok(HeightmapGenerator { blocks: blocks, width: width, depth: depth, heights: heights })
+    }
+
+    fn height(&self, x: i64, z: i64) -> i64 {
+        let ix = ((x % self.width as i64) + self.width as i64) % self.width as i64;
+        let iz = ((z % self.depth as i64) + self.depth as i64) % self.depth as i64;
+        self.heights[iz as uint * self.width + ix as uint]
+    }
+}
+
+impl TerrainGenerator for HeightmapGenerator {
+    fn generate(&self, chunk_x: i64, chunk_y: i64, chunk_z: i64, map: &mut Map) {
+        for block_x in range(0, CHUNK_SIZE) {
+            for block_z in range(0, CHUNK_SIZE) {
+                let height = self.height(chunk_x + block_x as i64, chunk_z + block_z as i64);
+                fill_column(chunk_y, block_x, block_z, map, |y| surface_block(&self.blocks, y, height));
+            }
+        }
+    }
+}
+
+fn surface_block(blocks: &TerrainBlocks, y: i64, height: i64) -> Option<BlockId> {
+    if y >= height {
+        None
+    } else if y == height - 1 {
+        Some(blocks.grass)
+    } else if y >= height - 4 {
+        Some(blocks.dirt)
+    } else {
+        Some(blocks.stone)
+    }
+}
+
+/// Returns (width, height, maxval, pixels) of an 8-bit binary PGM image.
+fn parse_pgm(data: &[u8]) -> Option<(uint, uint, uint, ~[u8])> {
+    let mut pos = 0;
+    let mut fields = ~[];
+
+    /* Magic, width, height and maxval, separated by whitespace and comments */
+    while fields.len() < 4 {
+        while pos < data.len() && (data[pos] as char).is_whitespace() {
+            pos += 1;
+        }
+        if pos < data.len() && data[pos] == '#' as u8 {
+            while pos < data.len() && data[pos] != '\n' as u8 {
+                pos += 1;
+            }
+            continue;
+        }
+        let start = pos;
+        while pos < data.len() && !(data[pos] as char).is_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return None;
+        }
+        match str::from_utf8_opt(data.slice(start, pos)) {
+            Some(field) => fields.push(field.to_owned()),
+            None => return None,
+        }
+    }
+    pos += 1;
+
+    if fields[0] != ~"P5" {
+        return None;
+    }
+
+    let width : uint = match from_str(fields[1]) { Some(x) => x, None => return None };
+    let height : uint = match from_str(fields[2]) { Some(x) => x, None => return None };
+    let maxval : uint = match from_str(fields[3]) { Some(x) => x, None => return None };
+
+    let size = match width.checked_mul(&height) { Some(x) => x, None => return None };
+
+    if width == 0 || height == 0 || maxval == 0 || maxval > 255 ||
+       pos > data.len() || size > data.len() - pos {
+        return None;
+    }
+
+    Some((width, height, maxval, data.slice(pos, pos + size).to_owned()))
+}
+
+#[test]
+fn test_flat_generator() {
+    let registry = test_registry();
+    let gen = FlatGenerator::new(40, &registry).unwrap();
+    let mut map = Map::new();
+    gen.generate(0, 32, 0, map);
+    assert!(map.blocks[0][0][0].blocktype == registry.find("stone").unwrap());
+    assert!(map.blocks[5][7][9].blocktype == registry.find("grass").unwrap());
+    assert!(map.blocks[5][6][9].blocktype == registry.find("dirt").unwrap());
+    assert!(!registry.is_opaque(map.blocks[5][8][9].blocktype));
+}
+
+#[test]
+fn test_heightmap_generator() {
+    let mut pgm = bytes!("P5\n# test\n2 1\n255\n").to_owned();
+    pgm.push(0);
+    pgm.push(255);
+    let registry = test_registry();
+    let gen = HeightmapGenerator::from_pgm(pgm, 10, 20.0, &registry).unwrap();
+    assert_eq!(gen.height(0, 0), 10);
+    assert_eq!(gen.height(1, 0), 30);
+    assert_eq!(gen.height(-1, 0), 30);
+    assert_eq!(gen.height(2, 5), 10);
+
+    assert!(HeightmapGenerator::from_pgm(bytes!("P2\n1 1\n255\n0"), 0, 1.0, &registry).is_err());
+    assert!(HeightmapGenerator::from_pgm(bytes!("P5\n4 4\n255\n"), 0, 1.0, &registry).is_err());
+    assert!(HeightmapGenerator::from_pgm(bytes!("P5\n1 1\n255"), 0, 1.0, &registry).is_err());
+
+    /* Header fields that aren't UTF-8, and sizes that overflow */
+    assert!(HeightmapGenerator::from_pgm([0x50, 0x35, 0xff, 0x0a, 0x31, 0x20, 0x31, 0x0a, 0x32, 0x35, 0x35, 0x0a, 0],
+                                         0, 1.0, &registry).is_err());
+    assert!(HeightmapGenerator::from_pgm(bytes!("P5\n4294967296 4294967296\n255\n0"),
+                                         0, 1.0, &registry).is_err());
+}
+
+#[test]
+fn test_default_generator_deterministic() {
+    let registry = test_registry();
+    let a = DefaultGenerator::new(42, &registry).unwrap();
+    let b = DefaultGenerator::new(42, &registry).unwrap();
+    let mut map_a = Map::new();
+    let mut map_b = Map::new();
+    a.generate(64, 0, -32, map_a);
+    b.generate(64, 0, -32, map_b);
+
+    for x in range(0, CHUNK_SIZE) {
+        for y in range(0, CHUNK_SIZE) {
+            for z in range(0, CHUNK_SIZE) {
+                assert!(map_a.blocks[x][y][z].blocktype == map_b.blocks[x][y][z].blocktype);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_default_generator_density() {
+    let gen = DefaultGenerator::new(42, &test_registry()).unwrap();
+    assert!(gen.solid(0, 0, 0, OVERHANG_AMPLITUDE as i64 + 1));
+    assert!(!gen.solid(0, OVERHANG_AMPLITUDE as i64 + 1, 0, 0));
+    assert!(!gen.cave(0, 10, 0, 10 + CAVE_MIN_DEPTH - 1));
+}
+
+#[test]
+fn test_generator_missing_block() {
+    let registry = BlockRegistry::from_json(
+        "[{\"id\": 0, \"name\": \"air\", \"color\": [0, 0, 0, 0]}]").unwrap();
+    assert!(FlatGenerator::new(40, &registry).is_err());
+    assert!(DefaultGenerator::new(42, &registry).is_err());
+}
@@ -24,6 +24,8 @@ use gl::types::*;
 
 use noise::Perlin;
 
+use CHUNK_SIZE;
+
 pub fn make_noise_texture() -> GLuint {
     let start_time = extra::time::precise_time_ns();
 
@@ -70,3 +72,37 @@ pub fn make_noise_texture() -> GLuint {
 
     tex
 }
+
+/// Creates a 3D texture holding a chunk's block ids, laid out like
+/// `Map::blocks`. Since z varies fastest, the texture's s, t and r
+/// coordinates are the chunk's z, y and x.
+pub fn make_block_texture(blocks: &[u8]) -> GLuint {
+    assert_eq!(blocks.len(), CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE);
+
+    let mut tex : GLuint = 0;
+
+    unsafe {
+        gl::GenTextures(1, &mut tex);
+    }
+
+    gl::BindTexture(gl::TEXTURE_3D, tex);
+    gl::TexParameteri(gl::TEXTURE_3D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as GLint);
+    gl::TexParameteri(gl::TEXTURE_3D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as GLint);
+    gl::TexParameteri(gl::TEXTURE_3D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
+    gl::TexParameteri(gl::TEXTURE_3D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
+    gl::TexParameteri(gl::TEXTURE_3D, gl::TEXTURE_WRAP_R, gl::CLAMP_TO_EDGE as GLint);
+    gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+
+    unsafe {
+        gl::TexImage3D(
+            gl::TEXTURE_3D, 0,
+            1 as GLint,
+            CHUNK_SIZE as GLsizei, CHUNK_SIZE as GLsizei, CHUNK_SIZE as GLsizei, 0,
+            gl::RED, gl::UNSIGNED_BYTE,
+            cast::transmute(&blocks[0]));
+    }
+
+    gl::BindTexture(gl::TEXTURE_3D, 0);
+
+    tex
+}
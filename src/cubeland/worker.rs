@@ -0,0 +1,180 @@
+// Copyright 2014 Rich Lane.
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern mod extra;
+
+use std::comm::SharedChan;
+use std::hashmap::HashSet;
+
+use extra::arc::{Arc, RWArc};
+
+use blocks::BlockRegistry;
+use chunk::Map;
+use light;
+use light::LightMap;
+use mesher;
+use mesher::{MeshData, Neighbours};
+use region::RegionStore;
+use terrain::TerrainGenerator;
+
+pub type ChunkCoords = (i64, i64, i64);
+
+pub enum WorkerRequest {
+    /// Load or generate a chunk, light it and build its mesh at the given
+    /// level of detail
+    LoadChunk(ChunkCoords, uint),
+    /// Rebuild the mesh of a loaded chunk at a level of detail; the last
+    /// uint is its mesh version
+    MeshChunk(ChunkCoords, ~Map, ~LightMap, uint, ~Neighbours, uint),
+}
+
+pub enum WorkerResult {
+    /// Terrain, its own light, level of detail and CPU mesh for a chunk,
+    /// ready for GL upload
+    ChunkReady(ChunkCoords, ~Map, ~LightMap, uint, ~MeshData),
+    /// Result of a MeshChunk request
+    ChunkMeshed(ChunkCoords, ~MeshData, uint),
+    /// The chunk left the visible set before the worker got to it
+    ChunkCancelled(ChunkCoords),
+}
+
+/// A pool of tasks that load or generate chunks and build their meshes.
+///
+/// Requests are handed out round-robin. Before starting each stage a worker
+/// checks the shared wanted set, so chunks the camera has moved away from are
+/// dropped without doing the work.
+pub struct WorkerPool {
+    request_chans: ~[Chan<WorkerRequest>],
+    result_port: Port<WorkerResult>,
+    wanted: RWArc<HashSet<ChunkCoords>>,
+    next_worker: uint,
+}
+
+impl WorkerPool {
+    pub fn new(num_workers: uint,
+               generator: ~TerrainGenerator:Send+Freeze,
+               registry: Arc<BlockRegistry>,
+               store: RegionStore,
+               merge_types: bool) -> WorkerPool {
+        let generator = Arc::new(generator);
+        let (result_port, result_chan) = SharedChan::new();
+        let wanted = RWArc::new(HashSet::new());
+        let mut request_chans = ~[];
+
+        for _ in range(0, num_workers) {
+            let (request_port, request_chan) = Chan::new();
+            let result_chan = result_chan.clone();
+            let wanted = wanted.clone();
+            let store = store.clone();
+            let generator = generator.clone();
+            let registry = registry.clone();
+            do spawn {
+                worker_main(generator, registry, store, request_port, result_chan, wanted,
+                            merge_types);
+            }
+            request_chans.push(request_chan);
+        }
+
+        WorkerPool {
+            request_chans: request_chans,
+            result_port: result_port,
+            wanted: wanted,
+            next_worker: 0,
+        }
+    }
+
+    pub fn request(&mut self, coords: ChunkCoords, lod: uint) {
+        self.send(LoadChunk(coords, lod));
+    }
+
+    pub fn remesh(&mut self, coords: ChunkCoords, map: ~Map, light: ~LightMap, lod: uint,
+                  neighbours: ~Neighbours, version: uint) {
+        self.send(MeshChunk(coords, map, light, lod, neighbours, version));
+    }
+
+    fn send(&mut self, request: WorkerRequest) {
+        self.request_chans[self.next_worker].send(request);
+        self.next_worker = (self.next_worker + 1) % self.request_chans.len();
+    }
+
+    /// Replaces the set of chunks workers should still produce.
+    pub fn set_wanted(&self, coords: &[ChunkCoords]) {
+        self.wanted.write(|wanted| {
+            wanted.clear();
+            for &c in coords.iter() {
+                wanted.insert(c);
+            }
+        });
+    }
+
+    pub fn try_recv(&self) -> Option<WorkerResult> {
+        self.result_port.try_recv()
+    }
+}
+
+fn worker_main(generator: Arc<~TerrainGenerator:Send+Freeze>,
+               registry: Arc<BlockRegistry>,
+               store: RegionStore,
+               requests: Port<WorkerRequest>,
+               results: SharedChan<WorkerResult>,
+               wanted: RWArc<HashSet<ChunkCoords>>,
+               merge_types: bool) {
+    let is_wanted = |coords: &ChunkCoords| wanted.read(|wanted| wanted.contains(coords));
+
+    loop {
+        let (coords, lod) = match requests.recv_opt() {
+            Some(LoadChunk(coords, lod)) => (coords, lod),
+            Some(MeshChunk(coords, map, light, lod, neighbours, version)) => {
+                let (cx, cy, cz) = coords;
+                let mesh = mesher::build(cx, cy, cz, map, light, lod, neighbours, registry.get(),
+                                         merge_types);
+                results.send(ChunkMeshed(coords, mesh, version));
+                continue;
+            },
+            None => break,
+        };
+        let (cx, cy, cz) = coords;
+
+        if !is_wanted(&coords) {
+            results.send(ChunkCancelled(coords));
+            continue;
+        }
+
+        let map = match store.load(cx, cy, cz) {
+            Ok(Some(map)) => map,
+            Ok(None) => generate(generator.get(), coords),
+            Err(msg) => {
+                println!("failed to load chunk ({}, {}, {}): {}", cx, cy, cz, msg);
+                generate(generator.get(), coords)
+            }
+        };
+
+        if !is_wanted(&coords) {
+            results.send(ChunkCancelled(coords));
+            continue;
+        }
+
+        let light = light::light_chunk(map, registry.get());
+        let mesh = mesher::build(cx, cy, cz, map, light, lod, &Neighbours::new(), registry.get(),
+                                 merge_types);
+        results.send(ChunkReady(coords, map, light, lod, mesh));
+    }
+}
+
+fn generate(generator: &~TerrainGenerator:Send+Freeze, coords: ChunkCoords) -> ~Map {
+    let (cx, cy, cz) = coords;
+    let mut map = Map::new();
+    generator.generate(cx, cy, cz, map);
+    map
+}